@@ -0,0 +1,925 @@
+use crate::constants::*;
+use crate::helpers::get_text_from_user;
+use crate::horizons_table::HORIZONS_COLORS;
+use csv::{ReaderBuilder, StringRecord};
+use flate2::read::GzDecoder;
+use macroquad::math::DVec2;
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/*
+Reads a wide or long CSV written by the simulation (optionally gzipped) and reports what the
+notebook used to: energy and momentum drift, the orbital period of every body about the primary
+and the closest approaches between bodies. The plots go in {file stem}_analysis next to the CSV.
+Run with "analyze <file> [png|svg]" as the arguments, giving either file of a long CSV pair.
+ */
+pub fn run_analysis(arguments: &[String]) {
+    let path = match arguments.first() {
+        Some(path) => path.clone(),
+        None => get_text_from_user("Which CSV should be analyzed?"),
+    };
+    let format = match arguments
+        .get(1)
+        .map(|format| format.to_lowercase())
+        .as_deref()
+    {
+        Some("svg") => PlotFormat::Svg,
+        _ => PlotFormat::Png,
+    };
+    let data = match read_simulation(Path::new(&path)) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Could not read {}: {}", path, e);
+            return;
+        }
+    };
+    println!(
+        "{} samples of {} bodies over {:.3} years",
+        data.times.len(),
+        data.bodies.len(),
+        data.times.last().copied().unwrap_or(0.) / SECONDS_IN_YEAR
+    );
+
+    let energy = energy_drift(&data);
+    if let Some(ref energy) = energy {
+        let (kind, unit) = if energy.relative {
+            ("relative", "")
+        } else {
+            ("absolute", " J")
+        };
+        println!(
+            "Energy: initial {:.6e} J, largest {} drift {:.3e}{}, final {:.3e}{}, trend {:.3e} J/year",
+            energy.initial,
+            kind,
+            energy.largest_drift,
+            unit,
+            energy.final_drift,
+            unit,
+            energy.trend_per_year
+        );
+    }
+    if let Some(momentum) = momentum_drift(&data) {
+        println!(
+            "Momentum: initial ({:.6e}, {:.6e}) kg m/s, largest change {:.3e} kg m/s, final change {:.3e} kg m/s",
+            momentum.initial.x, momentum.initial.y, momentum.largest_change, momentum.final_change
+        );
+    }
+
+    if let Some(primary) = data.primary() {
+        println!("Orbital periods about {}:", data.bodies[primary].name);
+        for (body, period) in orbital_periods(&data, primary) {
+            match period {
+                Some((period, orbits)) => println!(
+                    "{:>12}: {:.5} years over {:.1} orbits",
+                    data.bodies[body].name,
+                    period / SECONDS_IN_YEAR,
+                    orbits
+                ),
+                None => println!("{:>12}: less than one orbit", data.bodies[body].name),
+            }
+        }
+    }
+
+    if data.bodies.len() > ANALYSIS_MAX_PAIR_BODIES {
+        println!(
+            "Close approaches are only searched among the first {} bodies",
+            ANALYSIS_MAX_PAIR_BODIES
+        );
+    }
+    println!("Closest approaches:");
+    for approach in close_approaches(&data) {
+        println!(
+            "{:>12} - {:<12} {:.4e} m at {:.4} years",
+            data.bodies[approach.bodies.0].name,
+            data.bodies[approach.bodies.1].name,
+            approach.distance,
+            approach.time / SECONDS_IN_YEAR
+        );
+    }
+
+    let directory = analysis_directory(Path::new(&path));
+    match write_plots(&data, &directory, format) {
+        Ok(()) => println!("Wrote plots to {}", directory.display()),
+        Err(e) => println!("Could not write the plots: {}", e),
+    }
+}
+
+pub struct BodyTrack {
+    pub name: String,
+    pub mass: f64,
+    pub positions: Vec<Option<DVec2>>, // None once the body has merged into another
+}
+
+pub struct SimulationData {
+    pub times: Vec<f64>,
+    pub kinetic_energy: Vec<f64>,
+    pub potential_energy: Vec<f64>,
+    pub total_energy: Vec<f64>,
+    pub momentum: Vec<DVec2>,
+    pub bodies: Vec<BodyTrack>,
+}
+
+impl SimulationData {
+    // The most massive body, which the periods are measured about
+    pub fn primary(&self) -> Option<usize> {
+        self.bodies
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.mass.total_cmp(&b.mass))
+            .map(|(i, _)| i)
+    }
+}
+
+fn open_csv(path: &Path) -> io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|extension| extension == "gz") {
+        Ok(Box::new(GzDecoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+fn parse_value(text: &str) -> f64 {
+    text.trim().parse().unwrap_or(f64::NAN)
+}
+
+// Picks the reader from the file name, and names the formats that can't be analyzed
+pub fn read_simulation(path: &Path) -> io::Result<SimulationData> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    if name.ends_with(".parquet") {
+        return Err(io::Error::other(
+            "Parquet output can't be analyzed, write a wide or long CSV instead",
+        ));
+    }
+    for suffix in ["_bodies.csv", "_diagnostics.csv"] {
+        if let Some(prefix) = name.strip_suffix(suffix) {
+            return read_long_csv(&path.with_file_name(prefix));
+        }
+    }
+    read_wide_csv(path)
+}
+
+/*
+The wide layout from add_topline_data: body names, then masses, then column labels, each body
+taking the same number of columns after LEFT_PAD. Only the X and Y columns are needed.
+ */
+pub fn read_wide_csv(path: &Path) -> io::Result<SimulationData> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(open_csv(path)?);
+    let mut records = reader.records();
+    let mut header = || -> io::Result<StringRecord> {
+        records
+            .next()
+            .ok_or_else(|| io::Error::other("the file ends inside the header"))?
+            .map_err(io::Error::other)
+    };
+    let (names, masses, labels) = (header()?, header()?, header()?);
+
+    let body_labels: Vec<&str> = labels.iter().skip(LEFT_PAD).collect();
+    if body_labels.is_empty() {
+        return Err(io::Error::other("the file has no body columns"));
+    }
+    let width = body_labels
+        .iter()
+        .skip(1)
+        .position(|label| *label == body_labels[0])
+        .map_or(body_labels.len(), |position| position + 1);
+    let (Some(x_column), Some(y_column)) = (
+        body_labels[..width].iter().position(|label| *label == "X"),
+        body_labels[..width].iter().position(|label| *label == "Y"),
+    ) else {
+        return Err(io::Error::other(
+            "the file has no X and Y body columns, write it with the position columns to analyze it",
+        ));
+    };
+    let body_count = body_labels.len() / width;
+
+    let mut bodies: Vec<BodyTrack> = (0..body_count)
+        .map(|i| BodyTrack {
+            name: names.get(LEFT_PAD + i * width).unwrap_or("").to_string(),
+            mass: parse_value(masses.get(LEFT_PAD + i * width).unwrap_or("")),
+            positions: Vec::new(),
+        })
+        .collect();
+    let mut data = SimulationData {
+        times: Vec::new(),
+        kinetic_energy: Vec::new(),
+        potential_energy: Vec::new(),
+        total_energy: Vec::new(),
+        momentum: Vec::new(),
+        bodies: Vec::new(),
+    };
+
+    for record in records {
+        let record = record.map_err(io::Error::other)?;
+        let value = |column: usize| parse_value(record.get(column).unwrap_or(""));
+        data.times.push(value(0));
+        data.kinetic_energy.push(value(1));
+        data.potential_energy.push(value(2));
+        data.total_energy.push(value(3));
+        data.momentum.push(DVec2::new(value(4), value(5)));
+        for (i, body) in bodies.iter_mut().enumerate() {
+            let position = DVec2::new(
+                value(LEFT_PAD + i * width + x_column),
+                value(LEFT_PAD + i * width + y_column),
+            );
+            body.positions
+                .push((position.is_finite() && position != COLLIDED_POSITION).then_some(position));
+        }
+    }
+    data.bodies = bodies;
+    Ok(data)
+}
+
+// The file that holds one half of the long format, gzipped or not
+fn long_csv_file(prefix: &Path, suffix: &str) -> io::Result<PathBuf> {
+    let name = prefix
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    [
+        format!("{}{}", name, suffix),
+        format!("{}{}.gz", name, suffix),
+    ]
+    .into_iter()
+    .map(|name| prefix.with_file_name(name))
+    .find(|path| path.exists())
+    .ok_or_else(|| io::Error::other(format!("there is no {}{} next to it", name, suffix)))
+}
+
+fn column(headers: &StringRecord, name: &str) -> io::Result<usize> {
+    headers
+        .iter()
+        .position(|header| header == name)
+        .ok_or_else(|| io::Error::other(format!("the file has no {} column", name)))
+}
+
+/*
+The long layout from tidy_output, a {prefix}_bodies.csv and {prefix}_diagnostics.csv pair. The
+system totals come from the diagnostics and the positions from the x_m and y_m body columns.
+ */
+pub fn read_long_csv(prefix: &Path) -> io::Result<SimulationData> {
+    let reader = |suffix: &str| -> io::Result<csv::Reader<Box<dyn Read>>> {
+        Ok(ReaderBuilder::new().from_reader(open_csv(&long_csv_file(prefix, suffix)?)?))
+    };
+    let mut data = SimulationData {
+        times: Vec::new(),
+        kinetic_energy: Vec::new(),
+        potential_energy: Vec::new(),
+        total_energy: Vec::new(),
+        momentum: Vec::new(),
+        bodies: Vec::new(),
+    };
+
+    let mut diagnostics = reader("_diagnostics.csv")?;
+    let headers = diagnostics.headers().map_err(io::Error::other)?.clone();
+    let columns: Vec<usize> = [
+        "time_s",
+        "kinetic_energy_j",
+        "potential_energy_j",
+        "total_energy_j",
+        "momentum_x_kg_m_per_s",
+        "momentum_y_kg_m_per_s",
+    ]
+    .iter()
+    .map(|name| column(&headers, name))
+    .collect::<io::Result<_>>()?;
+    for record in diagnostics.records() {
+        let record = record.map_err(io::Error::other)?;
+        let value = |index: usize| parse_value(record.get(columns[index]).unwrap_or(""));
+        data.times.push(value(0));
+        data.kinetic_energy.push(value(1));
+        data.potential_energy.push(value(2));
+        data.total_energy.push(value(3));
+        data.momentum.push(DVec2::new(value(4), value(5)));
+    }
+
+    let mut bodies = reader("_bodies.csv")?;
+    let headers = bodies.headers().map_err(io::Error::other)?.clone();
+    let (Ok(x), Ok(y)) = (column(&headers, "x_m"), column(&headers, "y_m")) else {
+        return Err(io::Error::other(
+            "the file has no x_m and y_m body columns, write it with the position columns to analyze it",
+        ));
+    };
+    let (time, body_id, name, mass) = (
+        column(&headers, "time_s")?,
+        column(&headers, "body_id")?,
+        column(&headers, "name")?,
+        column(&headers, "mass_kg")?,
+    );
+    // Each sample is a run of rows with the same time, one row per body
+    let mut row = 0;
+    let mut last_time: Option<String> = None;
+    for record in bodies.records() {
+        let record = record.map_err(io::Error::other)?;
+        let text = |column: usize| record.get(column).unwrap_or("");
+        if last_time.as_deref().is_some_and(|last| last != text(time)) {
+            row += 1;
+        }
+        last_time = Some(text(time).to_string());
+        let Ok(id) = text(body_id).trim().parse::<usize>() else {
+            continue;
+        };
+        while data.bodies.len() <= id {
+            data.bodies.push(BodyTrack {
+                name: String::new(),
+                mass: f64::NAN,
+                positions: Vec::new(),
+            });
+        }
+        let body = &mut data.bodies[id];
+        if body.positions.is_empty() {
+            body.name = text(name).to_string();
+            body.mass = parse_value(text(mass));
+        }
+        body.positions.resize(row, None);
+        let position = DVec2::new(parse_value(text(x)), parse_value(text(y)));
+        body.positions.push(
+            (position.is_finite() && position != COLLIDED_POSITION && parse_value(text(mass)) > 0.)
+                .then_some(position),
+        );
+    }
+    for body in data.bodies.iter_mut() {
+        body.positions.resize(data.times.len(), None);
+    }
+    Ok(data)
+}
+
+// Least squares slope and intercept of y against x
+fn linear_fit(points: &[(f64, f64)]) -> (f64, f64) {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let slope = if variance > 0. {
+        covariance / variance
+    } else {
+        0.
+    };
+    (slope, mean_y - slope * mean_x)
+}
+
+pub struct EnergyDrift {
+    pub initial: f64,
+    pub relative: bool, // False when the initial energy is zero, and the drift is in joules
+    pub largest_drift: f64,
+    pub final_drift: f64,
+    pub trend_per_year: f64,
+}
+
+// Drift relative to the initial energy, or absolute when there's nothing to divide by
+fn drift_from(initial: f64, energy: f64) -> f64 {
+    if initial == 0. {
+        (energy - initial).abs()
+    } else {
+        ((energy - initial) / initial).abs()
+    }
+}
+
+fn finite_points(times: &[f64], values: &[f64]) -> Vec<(f64, f64)> {
+    times
+        .iter()
+        .zip(values)
+        .filter(|(time, value)| time.is_finite() && value.is_finite())
+        .map(|(time, value)| (*time, *value))
+        .collect()
+}
+
+pub fn energy_drift(data: &SimulationData) -> Option<EnergyDrift> {
+    let points = finite_points(&data.times, &data.total_energy);
+    let (_, initial) = *points.first()?;
+    let drift: Vec<f64> = points
+        .iter()
+        .map(|(_, energy)| drift_from(initial, *energy))
+        .collect();
+    Some(EnergyDrift {
+        initial,
+        relative: initial != 0.,
+        largest_drift: drift.iter().copied().fold(0., f64::max),
+        final_drift: *drift.last()?,
+        trend_per_year: linear_fit(&points).0 * SECONDS_IN_YEAR,
+    })
+}
+
+pub struct MomentumDrift {
+    pub initial: DVec2,
+    pub largest_change: f64,
+    pub final_change: f64,
+}
+
+// Momentum is usually close to zero, so the change is absolute rather than relative
+pub fn momentum_drift(data: &SimulationData) -> Option<MomentumDrift> {
+    let momenta: Vec<DVec2> = data
+        .momentum
+        .iter()
+        .copied()
+        .filter(|momentum| momentum.is_finite())
+        .collect();
+    let initial = *momenta.first()?;
+    let changes: Vec<f64> = momenta
+        .iter()
+        .map(|momentum| (*momentum - initial).length())
+        .collect();
+    Some(MomentumDrift {
+        initial,
+        largest_change: changes.iter().copied().fold(0., f64::max),
+        final_change: *changes.last()?,
+    })
+}
+
+/*
+The mean period of every other body about the primary and how many orbits it is taken over, from
+the unwrapped angle of the body around it. Bodies that haven't completed an orbit get None.
+ */
+pub fn orbital_periods(data: &SimulationData, primary: usize) -> Vec<(usize, Option<(f64, f64)>)> {
+    (0..data.bodies.len())
+        .filter(|body| *body != primary)
+        .map(|body| {
+            let mut angle = 0.;
+            let mut previous: Option<(f64, f64)> = None; // Time and angle of the last sample
+            let mut start = None;
+            for (row, time) in data.times.iter().enumerate() {
+                let (Some(position), Some(center)) = (
+                    data.bodies[body].positions[row],
+                    data.bodies[primary].positions[row],
+                ) else {
+                    continue;
+                };
+                let relative = position - center;
+                let current = relative.y.atan2(relative.x);
+                if let Some((_, last)) = previous {
+                    let mut step = current - last;
+                    if step > std::f64::consts::PI {
+                        step -= std::f64::consts::TAU;
+                    } else if step < -std::f64::consts::PI {
+                        step += std::f64::consts::TAU;
+                    }
+                    angle += step;
+                } else {
+                    start = Some(*time);
+                }
+                previous = Some((*time, current));
+            }
+            let orbits = f64::abs(angle) / std::f64::consts::TAU;
+            let period = match (start, previous) {
+                (Some(start), Some((end, _))) if orbits >= 1. => {
+                    Some(((end - start) / orbits, orbits))
+                }
+                _ => None,
+            };
+            (body, period)
+        })
+        .collect()
+}
+
+pub struct CloseApproach {
+    pub bodies: (usize, usize),
+    pub distance: f64,
+    pub time: f64,
+}
+
+// The smallest separation of each pair of bodies, closest first
+pub fn close_approaches(data: &SimulationData) -> Vec<CloseApproach> {
+    let count = data.bodies.len().min(ANALYSIS_MAX_PAIR_BODIES);
+    let mut approaches: Vec<CloseApproach> = (0..count)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            (i + 1..count).filter_map(move |j| {
+                let mut closest: Option<CloseApproach> = None;
+                for (row, time) in data.times.iter().enumerate() {
+                    if let (Some(a), Some(b)) =
+                        (data.bodies[i].positions[row], data.bodies[j].positions[row])
+                    {
+                        let distance = (a - b).length();
+                        if closest
+                            .as_ref()
+                            .is_none_or(|closest| distance < closest.distance)
+                        {
+                            closest = Some(CloseApproach {
+                                bodies: (i, j),
+                                distance,
+                                time: *time,
+                            });
+                        }
+                    }
+                }
+                closest
+            })
+        })
+        .collect();
+    approaches.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    approaches.truncate(ANALYSIS_CLOSE_APPROACHES);
+    approaches
+}
+
+fn analysis_directory(path: &Path) -> PathBuf {
+    let mut stem = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    for extension in [".gz", ".csv"] {
+        if let Some(stripped) = stem.strip_suffix(extension) {
+            stem = stripped.to_string();
+        }
+    }
+    path.with_file_name(format!("{}_analysis", stem))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlotFormat {
+    Png,
+    Svg,
+}
+
+pub struct Series {
+    pub name: String,
+    pub points: Vec<(f64, f64)>,
+    pub color: RGBColor,
+}
+
+pub struct Plot {
+    pub title: String,
+    pub x_label: String,
+    pub y_label: String,
+    pub series: Vec<Series>,
+    pub equal_aspect: bool, // For positions, so orbits aren't stretched
+}
+
+fn write_plots(data: &SimulationData, directory: &Path, format: PlotFormat) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+    let years: Vec<f64> = data
+        .times
+        .iter()
+        .map(|time| time / SECONDS_IN_YEAR)
+        .collect();
+    let series = |name: &str, values: &[f64], color: RGBColor| Series {
+        name: name.to_string(),
+        points: finite_points(&years, values),
+        color,
+    };
+
+    let mut energy_series = vec![
+        series("Kinetic energy", &data.kinetic_energy, RED),
+        series("Potential energy", &data.potential_energy, BLUE),
+        series("Total energy", &data.total_energy, BLACK),
+    ];
+    let total = &energy_series[2].points;
+    if total.len() > 1 {
+        let (slope, intercept) = linear_fit(total);
+        let ends = [total[0].0, total[total.len() - 1].0];
+        energy_series.push(Series {
+            name: format!("Fit, {:.2e} J/year", slope),
+            points: ends.iter().map(|x| (*x, slope * x + intercept)).collect(),
+            color: GREEN,
+        });
+    }
+    let initial_energy = energy_drift(data).map_or(f64::NAN, |drift| drift.initial);
+    let drift_kind = if initial_energy == 0. {
+        "Absolute"
+    } else {
+        "Relative"
+    };
+    let plots = [
+        (
+            "energy",
+            Plot {
+                title: "Energy".to_string(),
+                x_label: "Time (years)".to_string(),
+                y_label: "Energy (J)".to_string(),
+                series: energy_series,
+                equal_aspect: false,
+            },
+        ),
+        (
+            "energy_drift",
+            Plot {
+                title: format!("{} energy drift", drift_kind),
+                x_label: "Time (years)".to_string(),
+                y_label: if initial_energy == 0. {
+                    "|E - E0| (J)".to_string()
+                } else {
+                    "|E - E0| / |E0|".to_string()
+                },
+                series: {
+                    let drift: Vec<f64> = data
+                        .total_energy
+                        .iter()
+                        .map(|energy| drift_from(initial_energy, *energy))
+                        .collect();
+                    vec![series(&format!("{} drift", drift_kind), &drift, BLACK)]
+                },
+                equal_aspect: false,
+            },
+        ),
+        (
+            "momentum",
+            Plot {
+                title: "Momentum".to_string(),
+                x_label: "Time (years)".to_string(),
+                y_label: "Momentum (kg m/s)".to_string(),
+                series: vec![
+                    series(
+                        "X momentum",
+                        &data.momentum.iter().map(|p| p.x).collect::<Vec<f64>>(),
+                        RED,
+                    ),
+                    series(
+                        "Y momentum",
+                        &data.momentum.iter().map(|p| p.y).collect::<Vec<f64>>(),
+                        BLUE,
+                    ),
+                ],
+                equal_aspect: false,
+            },
+        ),
+        (
+            "orbits",
+            Plot {
+                title: "Paths".to_string(),
+                x_label: "X position (m)".to_string(),
+                y_label: "Y position (m)".to_string(),
+                series: data
+                    .bodies
+                    .iter()
+                    .enumerate()
+                    .map(|(i, body)| Series {
+                        name: body.name.clone(),
+                        points: body
+                            .positions
+                            .iter()
+                            .flatten()
+                            .map(|position| (position.x, position.y))
+                            .collect(),
+                        color: body_color(&body.name, i),
+                    })
+                    .collect(),
+                equal_aspect: true,
+            },
+        ),
+    ];
+    for (name, plot) in plots {
+        let extension = match format {
+            PlotFormat::Png => "png",
+            PlotFormat::Svg => "svg",
+        };
+        save_plot(
+            &plot,
+            &directory.join(format!("{}.{}", name, extension)),
+            format,
+        )?;
+    }
+    Ok(())
+}
+
+// The renderer's colors for Solar System bodies, a palette for everything else
+fn body_color(name: &str, index: usize) -> RGBColor {
+    match HORIZONS_COLORS.get(name.to_lowercase().as_str()) {
+        Some(color) => RGBColor(
+            (color.r * 255.) as u8,
+            (color.g * 255.) as u8,
+            (color.b * 255.) as u8,
+        ),
+        None => {
+            let (r, g, b) = Palette99::pick(index).to_rgba().rgb();
+            RGBColor(r, g, b)
+        }
+    }
+}
+
+pub fn save_plot(plot: &Plot, path: &Path, format: PlotFormat) -> io::Result<()> {
+    let result = match format {
+        PlotFormat::Png => draw_plot(
+            BitMapBackend::new(path, ANALYSIS_PLOT_SIZE).into_drawing_area(),
+            plot,
+        ),
+        PlotFormat::Svg => draw_plot(
+            SVGBackend::new(path, ANALYSIS_PLOT_SIZE).into_drawing_area(),
+            plot,
+        ),
+    };
+    result.map_err(io::Error::other)
+}
+
+fn range(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    let (low, high) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(low, high), value| {
+        (low.min(value), high.max(value))
+    });
+    if !low.is_finite() {
+        (0., 1.)
+    } else if low == high {
+        let pad = if low == 0. { 1. } else { low.abs() * 0.01 };
+        (low - pad, high + pad)
+    } else {
+        (low, high)
+    }
+}
+
+fn draw_plot<DB: DrawingBackend>(root: DrawingArea<DB, Shift>, plot: &Plot) -> Result<(), String> {
+    let error = |e: DrawingAreaErrorKind<DB::ErrorType>| e.to_string();
+    root.fill(&WHITE).map_err(error)?;
+    let points = || plot.series.iter().flat_map(|series| series.points.iter());
+    let (mut x_low, mut x_high) = range(points().map(|(x, _)| *x));
+    let (mut y_low, mut y_high) = range(points().map(|(_, y)| *y));
+    if plot.equal_aspect {
+        // Widen whichever range is short of the plot's aspect ratio
+        let aspect = ANALYSIS_PLOT_SIZE.0 as f64 / ANALYSIS_PLOT_SIZE.1 as f64;
+        let (x_center, y_center) = ((x_low + x_high) / 2., (y_low + y_high) / 2.);
+        let half_height = ((x_high - x_low) / aspect).max(y_high - y_low) / 2.;
+        (x_low, x_high) = (
+            x_center - half_height * aspect,
+            x_center + half_height * aspect,
+        );
+        (y_low, y_high) = (y_center - half_height, y_center + half_height);
+    }
+
+    let mut chart = ChartBuilder::on(&root)
+        .caption(&plot.title, ("sans-serif", 28))
+        .margin(20)
+        .x_label_area_size(50)
+        .y_label_area_size(110)
+        .build_cartesian_2d(x_low..x_high, y_low..y_high)
+        .map_err(error)?;
+    chart
+        .configure_mesh()
+        .x_desc(plot.x_label.as_str())
+        .y_desc(plot.y_label.as_str())
+        .x_label_formatter(&|x| format!("{:.3e}", x))
+        .y_label_formatter(&|y| format!("{:.3e}", y))
+        .draw()
+        .map_err(error)?;
+
+    for series in &plot.series {
+        let stride = series
+            .points
+            .len()
+            .div_ceil(ANALYSIS_MAX_PLOT_POINTS)
+            .max(1);
+        let color = series.color;
+        chart
+            .draw_series(LineSeries::new(
+                series.points.iter().step_by(stride).copied(),
+                color.stroke_width(2),
+            ))
+            .map_err(error)?
+            .label(series.name.as_str())
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], color));
+    }
+    chart
+        .configure_series_labels()
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
+        .draw()
+        .map_err(error)?;
+    root.present().map_err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body_columns::{BodyColumns, BodyQuantity};
+    use crate::helpers::Particle;
+    use crate::output_sink::test_support::{test_prefix, test_system};
+    use crate::output_sink::{Destination, OutputSink, OutputStream, WideCsvSink};
+    use crate::tidy_output::TidyWriter;
+
+    #[test]
+    fn test_analysis_of_a_circular_orbit() {
+        let body = |name: &str, mass: f64, position: DVec2, velocity: DVec2| Particle {
+            mass,
+            radius: 1.,
+            position,
+            velocity,
+            color: macroquad::color::WHITE,
+            name: name.to_string(),
+        };
+        let speed = (G * STAR_MASS / AU).sqrt();
+        let period = std::f64::consts::TAU * AU / speed;
+        let prefix = test_prefix("analysis");
+        let columns = BodyColumns::new(&[BodyQuantity::Velocity, BodyQuantity::Position]);
+        let system = |time: f64| {
+            let angle = std::f64::consts::TAU * time / period;
+            let direction = DVec2::new(angle.cos(), angle.sin());
+            vec![
+                body("sun", STAR_MASS, DVec2::ZERO, DVec2::ZERO),
+                body(
+                    "earth",
+                    EARTH_MASS,
+                    direction * AU,
+                    direction.perp() * speed,
+                ),
+            ]
+        };
+        let mut sink: Box<dyn OutputSink> = Box::new(
+            WideCsvSink::new(
+                OutputStream::open(&format!("{}.csv", prefix), Destination::File).unwrap(),
+                &system(0.),
+                &columns,
+                None,
+            )
+            .unwrap(),
+        );
+        for i in 0..=250 {
+            let time = i as f64 * period / 100.;
+            sink.add_data(&system(time), time).unwrap();
+        }
+        sink.finish().unwrap();
+
+        let path = PathBuf::from(format!("{}.csv", prefix));
+        let data = read_wide_csv(&path).unwrap();
+        assert_eq!(data.times.len(), 251);
+        assert_eq!(data.bodies[1].name, "earth");
+        assert_eq!(data.primary(), Some(0));
+
+        let periods = orbital_periods(&data, 0);
+        let (measured, orbits) = periods[0].1.unwrap();
+        assert!((measured / period - 1.).abs() < 1e-9);
+        assert!((orbits - 2.5).abs() < 1e-9);
+
+        let approaches = close_approaches(&data);
+        assert_eq!(approaches[0].bodies, (0, 1));
+        assert!((approaches[0].distance / AU - 1.).abs() < 1e-12);
+
+        // The energy drift only comes from rounding in the CSV
+        assert!(energy_drift(&data).unwrap().largest_drift < 1e-12);
+
+        let directory = analysis_directory(&path);
+        write_plots(&data, &directory, PlotFormat::Svg).unwrap();
+        let orbits_plot = fs::read_to_string(directory.join("orbits.svg")).unwrap();
+        assert!(orbits_plot.contains("earth"));
+        fs::remove_dir_all(directory).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_analysis_of_the_long_format() {
+        let prefix = test_prefix("analysis_long");
+        let mut system = test_system();
+        let mut sink: Box<dyn OutputSink> = Box::new(
+            TidyWriter::new(
+                OutputStream::open(&format!("{}_bodies.csv", prefix), Destination::File).unwrap(),
+                OutputStream::open(&format!("{}_diagnostics.csv", prefix), Destination::File)
+                    .unwrap(),
+                &BodyColumns::new(&[BodyQuantity::Position]),
+                None,
+            )
+            .unwrap(),
+        );
+        sink.add_data(&system, 0.).unwrap();
+        system[1].mass = 0.;
+        system[1].position = COLLIDED_POSITION;
+        sink.add_data(&system, 1.).unwrap();
+        sink.finish().unwrap();
+
+        let data = read_simulation(Path::new(&format!("{}_diagnostics.csv", prefix))).unwrap();
+        assert_eq!(data.times, [0., 1.]);
+        assert_eq!(data.bodies.len(), 2);
+        assert_eq!(data.bodies[1].name, "earth");
+        assert_eq!(data.bodies[1].mass, EARTH_MASS);
+        assert_eq!(data.bodies[1].positions, [Some(DVec2::new(AU, 0.)), None]);
+        assert_eq!(data.primary(), Some(0));
+        fs::remove_file(format!("{}_bodies.csv", prefix)).unwrap();
+        fs::remove_file(format!("{}_diagnostics.csv", prefix)).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_files() {
+        let prefix = test_prefix("analysis_unreadable");
+        let path = PathBuf::from(format!("{}.csv", prefix));
+        fs::write(&path, "a,b\nc,d\ne,f\n").unwrap();
+        assert!(read_simulation(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        let error = read_simulation(Path::new("run_bodies.parquet"))
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("Parquet"));
+    }
+
+    #[test]
+    fn test_drift_from_zero_energy() {
+        let data = SimulationData {
+            times: vec![0., 1.],
+            kinetic_energy: vec![1., 2.],
+            potential_energy: vec![-1., -1.5],
+            total_energy: vec![0., 0.5],
+            momentum: vec![DVec2::ZERO; 2],
+            bodies: Vec::new(),
+        };
+        let drift = energy_drift(&data).unwrap();
+        assert!(!drift.relative);
+        assert_eq!(drift.largest_drift, 0.5);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::constants::{COLLISION_MIN_MASS, G};
+use crate::helpers::{Particle, get_text_from_user};
+use rayon::prelude::*;
+
+/*
+The quantities written for every body in the CSV and Parquet formats. Each one becomes one or
+two columns per body, computed from the state at the sample, so nothing has to be recovered by
+finite differences afterwards. The primary is the most massive body, and the orbital quantities
+are relative to it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyQuantity {
+    Position,
+    Velocity,
+    Acceleration,
+    KineticEnergy,
+    SpecificOrbitalEnergy, // v^2 / 2 - G (M + m) / r about the primary, NaN for the primary itself
+    DistanceToPrimary,
+}
+
+impl BodyQuantity {
+    pub const ALL: [BodyQuantity; 6] = [
+        BodyQuantity::Position,
+        BodyQuantity::Velocity,
+        BodyQuantity::Acceleration,
+        BodyQuantity::KineticEnergy,
+        BodyQuantity::SpecificOrbitalEnergy,
+        BodyQuantity::DistanceToPrimary,
+    ];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            BodyQuantity::Position => "Position",
+            BodyQuantity::Velocity => "Velocity",
+            BodyQuantity::Acceleration => "Acceleration",
+            BodyQuantity::KineticEnergy => "Kinetic energy",
+            BodyQuantity::SpecificOrbitalEnergy => "Specific orbital energy about the primary",
+            BodyQuantity::DistanceToPrimary => "Distance to the primary",
+        }
+    }
+
+    // The sub headers of the wide CSV, under each body's name
+    pub fn wide_names(self) -> &'static [&'static str] {
+        match self {
+            BodyQuantity::Position => &["X", "Y"],
+            BodyQuantity::Velocity => &["VX", "VY"],
+            BodyQuantity::Acceleration => &["AX", "AY"],
+            BodyQuantity::KineticEnergy => &["Kinetic Energy"],
+            BodyQuantity::SpecificOrbitalEnergy => &["Specific Orbital Energy"],
+            BodyQuantity::DistanceToPrimary => &["Distance To Primary"],
+        }
+    }
+
+    // The column names of the long formats, with their SI units
+    pub fn long_names(self) -> &'static [&'static str] {
+        match self {
+            BodyQuantity::Position => &["x_m", "y_m"],
+            BodyQuantity::Velocity => &["vx_m_per_s", "vy_m_per_s"],
+            BodyQuantity::Acceleration => &["ax_m_per_s2", "ay_m_per_s2"],
+            BodyQuantity::KineticEnergy => &["kinetic_energy_j"],
+            BodyQuantity::SpecificOrbitalEnergy => &["specific_orbital_energy_j_per_kg"],
+            BodyQuantity::DistanceToPrimary => &["distance_to_primary_m"],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BodyColumns {
+    pub quantities: Vec<BodyQuantity>,
+}
+
+impl BodyColumns {
+    pub fn new(quantities: &[BodyQuantity]) -> BodyColumns {
+        BodyColumns {
+            quantities: quantities.to_vec(),
+        }
+    }
+
+    // Columns per body
+    pub fn width(&self) -> usize {
+        self.quantities
+            .iter()
+            .map(|quantity| quantity.wide_names().len())
+            .sum()
+    }
+
+    pub fn wide_names(&self) -> Vec<&'static str> {
+        self.quantities
+            .iter()
+            .flat_map(|quantity| quantity.wide_names().iter().copied())
+            .collect()
+    }
+
+    pub fn long_names(&self) -> Vec<&'static str> {
+        self.quantities
+            .iter()
+            .flat_map(|quantity| quantity.long_names().iter().copied())
+            .collect()
+    }
+
+    // One row of width() values for every body, in the same order as the names
+    pub fn values(&self, system: &[Particle]) -> Vec<Vec<f64>> {
+        let accelerations = if self.quantities.contains(&BodyQuantity::Acceleration) {
+            (0..system.len())
+                .into_par_iter()
+                .map(|i| {
+                    if system[i].mass > COLLISION_MIN_MASS {
+                        system[i].calculate_g_force(system, i) / system[i].mass
+                    } else {
+                        system[i].calculate_g_acceleration(system)
+                    }
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let primary = system
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.mass.total_cmp(&b.mass))
+            .map(|(i, _)| i);
+
+        (0..system.len())
+            .map(|i| {
+                let body = &system[i];
+                let mut row = Vec::with_capacity(self.width());
+                for quantity in &self.quantities {
+                    match quantity {
+                        BodyQuantity::Position => row.extend([body.position.x, body.position.y]),
+                        BodyQuantity::Velocity => row.extend([body.velocity.x, body.velocity.y]),
+                        BodyQuantity::Acceleration => {
+                            row.extend([accelerations[i].x, accelerations[i].y])
+                        }
+                        BodyQuantity::KineticEnergy => row.push(body.calculate_kinetic_energy()),
+                        BodyQuantity::SpecificOrbitalEnergy => row.push(match primary {
+                            Some(primary) if primary != i => {
+                                let primary = &system[primary];
+                                let distance = (body.position - primary.position).length();
+                                let speed = (body.velocity - primary.velocity).length();
+                                0.5 * speed * speed - G * (primary.mass + body.mass) / distance
+                            }
+                            _ => f64::NAN,
+                        }),
+                        BodyQuantity::DistanceToPrimary => row.push(match primary {
+                            Some(primary) => (body.position - system[primary].position).length(),
+                            None => f64::NAN,
+                        }),
+                    }
+                }
+                row
+            })
+            .collect()
+    }
+}
+
+// Comma separated choices, with an empty answer keeping the format's default columns
+pub fn choose_body_columns(default: &[BodyQuantity]) -> BodyColumns {
+    let mut question =
+        "Which quantities should be written for each body? Separate them with commas".to_string();
+    for (i, quantity) in BodyQuantity::ALL.iter().enumerate() {
+        question.push_str(&format!("\n[{}] {}", i, quantity.description()));
+    }
+    let default_descriptions: Vec<&str> = default
+        .iter()
+        .map(|quantity| quantity.description())
+        .collect();
+    question.push_str(&format!(
+        "\nLeave empty for {}",
+        default_descriptions.join(", ")
+    ));
+    loop {
+        let answer = get_text_from_user(&question);
+        if answer.is_empty() {
+            return BodyColumns::new(default);
+        }
+        match parse_body_columns(&answer) {
+            Some(columns) => return columns,
+            None => println!("Invalid choice of quantities"),
+        }
+    }
+}
+
+fn parse_body_columns(answer: &str) -> Option<BodyColumns> {
+    let mut quantities = Vec::new();
+    for choice in answer.split(',') {
+        let quantity = *BodyQuantity::ALL.get(choice.trim().parse::<usize>().ok()?)?;
+        if !quantities.contains(&quantity) {
+            quantities.push(quantity);
+        }
+    }
+    Some(BodyColumns { quantities })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+    use macroquad::color::WHITE;
+    use macroquad::math::DVec2;
+
+    #[test]
+    fn test_body_columns() {
+        let body = |mass: f64, position: DVec2, velocity: DVec2| Particle {
+            mass,
+            radius: 1.,
+            position,
+            velocity,
+            color: WHITE,
+            name: String::new(),
+        };
+        let speed = (G * STAR_MASS / AU).sqrt();
+        let system = vec![
+            body(STAR_MASS, DVec2::ZERO, DVec2::ZERO),
+            body(0., DVec2::new(AU, 0.), DVec2::new(0., speed)),
+        ];
+        let columns = parse_body_columns("5, 4, 2, 4").unwrap();
+        assert_eq!(columns.width(), 4);
+        assert_eq!(
+            columns.long_names(),
+            [
+                "distance_to_primary_m",
+                "specific_orbital_energy_j_per_kg",
+                "ax_m_per_s2",
+                "ay_m_per_s2"
+            ]
+        );
+
+        let values = columns.values(&system);
+        assert_eq!(values[0][0], 0.);
+        assert!(values[0][1].is_nan());
+        assert_eq!(values[1][0], AU);
+        // A circular orbit has half the potential energy per unit mass
+        assert!((values[1][1] / (-G * STAR_MASS / (2. * AU)) - 1.).abs() < 1e-12);
+        assert!((values[1][2] / (-G * STAR_MASS / (AU * AU)) - 1.).abs() < 1e-9);
+        assert!(parse_body_columns("1, 9").is_none());
+    }
+}
@@ -0,0 +1,469 @@
+use crate::constants::*;
+use crate::helpers::Particle;
+use macroquad::color::Color;
+use macroquad::math::{DVec2, DVec3};
+use macroquad::rand::gen_range;
+use rayon::prelude::*;
+use std::f64::consts::{PI, TAU};
+
+/*
+All of the star cluster generators first sample a model in its own dimensionless units
+(G = M = 1, with the model's natural scale radius), sample it in three dimensions and
+project it onto the simulation plane. The positions are then rescaled so the projected
+half-mass radius matches the requested one, the masses are shared out of the requested
+total mass, and the velocities are rescaled so the system has the requested virial ratio.
+ */
+
+pub fn initialize_plummer_sphere(
+    system: &mut Vec<Particle>,
+    bodies_to_add: &usize,
+    total_mass: &f64,
+    half_mass_radius: &f64,
+    color: &Color,
+) -> (usize, usize) {
+    let samples: Vec<(DVec3, DVec3)> = (0..*bodies_to_add).map(|_| sample_plummer()).collect();
+    push_spherical_model(
+        system,
+        samples,
+        total_mass,
+        half_mass_radius,
+        &CLUSTER_VIRIAL_RATIO,
+        color,
+        "Star",
+    )
+}
+
+pub fn initialize_cold_collapse(
+    system: &mut Vec<Particle>,
+    bodies_to_add: &usize,
+    total_mass: &f64,
+    half_mass_radius: &f64,
+    color: &Color,
+) -> (usize, usize) {
+    let samples: Vec<(DVec3, DVec3)> = (0..*bodies_to_add)
+        .map(|_| (random_direction() * unit_random().cbrt(), DVec3::ZERO))
+        .collect();
+    push_spherical_model(
+        system,
+        samples,
+        total_mass,
+        half_mass_radius,
+        &COLD_COLLAPSE_VIRIAL_RATIO,
+        color,
+        "Star",
+    )
+}
+
+pub fn initialize_king_model(
+    system: &mut Vec<Particle>,
+    bodies_to_add: &usize,
+    total_mass: &f64,
+    half_mass_radius: &f64,
+    central_potential: &f64,
+    color: &Color,
+) -> (usize, usize) {
+    let king_profile = KingProfile::solve(*central_potential);
+    let samples: Vec<(DVec3, DVec3)> = (0..*bodies_to_add).map(|_| king_profile.sample()).collect();
+    push_spherical_model(
+        system,
+        samples,
+        total_mass,
+        half_mass_radius,
+        &CLUSTER_VIRIAL_RATIO,
+        color,
+        "Star",
+    )
+}
+
+// One part of a disk galaxy: how many stars, their total mass, the scale length of the disk or
+// scale radius of the bulge, and their color
+pub struct GalaxyComponent {
+    pub bodies: usize,
+    pub mass: f64,
+    pub scale: f64,
+    pub color: Color,
+}
+
+// Exponential disk on circular orbits around a Plummer bulge. The disk isn't virialized since
+// it is held up by rotation rather than by random motions.
+pub fn initialize_exponential_disk(
+    system: &mut Vec<Particle>,
+    disk: &GalaxyComponent,
+    bulge: &GalaxyComponent,
+) -> (usize, usize) {
+    let first_body = system.len();
+    let bulge_body_mass = bulge.mass / bulge.bodies.max(1) as f64;
+    let bulge_velocity_scale = (G * bulge.mass / bulge.scale).sqrt();
+    for i in 0..bulge.bodies {
+        let (position, velocity) = sample_plummer();
+        system.push(Particle {
+            mass: bulge_body_mass,
+            position: position.truncate() * bulge.scale,
+            velocity: velocity.truncate() * bulge_velocity_scale,
+            radius: STAR_RADIUS,
+            color: bulge.color,
+            name: format!("Bulge Star {}", i + 1),
+        });
+    }
+
+    // The radius of an exponential disk follows a gamma distribution of shape 2
+    let disk_body_mass = disk.mass / disk.bodies.max(1) as f64;
+    let mut disk_radii: Vec<f64> = Vec::with_capacity(disk.bodies);
+    while disk_radii.len() < disk.bodies {
+        let radius = -(unit_random() * unit_random()).ln();
+        if radius < DISK_TRUNCATION_SCALE_LENGTHS {
+            disk_radii.push(radius * disk.scale);
+        }
+    }
+    disk_radii.sort_by(|a, b| a.total_cmp(b));
+
+    for (i, radius) in disk_radii.iter().enumerate() {
+        let enclosed_disk_mass = disk_body_mass * i as f64;
+        let bulge_radius_ratio = *radius / bulge.scale;
+        let enclosed_bulge_mass =
+            bulge.mass * bulge_radius_ratio.powi(3) / (1. + bulge_radius_ratio.powi(2)).powf(1.5);
+        let circular_speed = (G * (enclosed_disk_mass + enclosed_bulge_mass) / *radius).sqrt();
+
+        let angle = gen_range(0., TAU);
+        let radial_direction = DVec2::from_angle(angle);
+        let dispersion = DISK_VELOCITY_DISPERSION * circular_speed;
+        let velocity = radial_direction.perp() * circular_speed
+            + DVec2::new(gen_range(-1., 1.), gen_range(-1., 1.)) * dispersion;
+        system.push(Particle {
+            mass: disk_body_mass,
+            position: radial_direction * *radius,
+            velocity,
+            radius: STAR_RADIUS,
+            color: disk.color,
+            name: format!("Disk Star {}", i + 1),
+        });
+    }
+    remove_center_of_mass_motion(&mut system[first_body..]);
+
+    count_bodies(&system[first_body..])
+}
+
+// Free fall time scale of a system of the given mass and size, used to pick dt and run lengths
+pub fn dynamical_time(total_mass: f64, radius: f64) -> f64 {
+    (radius.powi(3) / (G * total_mass)).sqrt()
+}
+
+// Logarithmic speed range for velocity_to_color, taken from the spread of speeds in the system.
+// Systems starting at rest fall back to a range around the given characteristic speed.
+pub fn speed_color_range(system: &[Particle], characteristic_speed: f64) -> (f64, f64) {
+    let mut speeds: Vec<f64> = system.iter().map(|body| body.velocity.length()).collect();
+    speeds.sort_by(|a, b| a.total_cmp(b));
+
+    let low = quantile(&speeds, SPEED_COLOR_QUANTILES.0);
+    let high = quantile(&speeds, SPEED_COLOR_QUANTILES.1);
+    if low > 0. && high > low {
+        (low.log10(), high.log10())
+    } else {
+        (
+            (0.1 * characteristic_speed).log10(),
+            (2. * characteristic_speed).log10(),
+        )
+    }
+}
+
+// Expects a sorted slice
+pub fn quantile(sorted_values: &[f64], fraction: f64) -> f64 {
+    if sorted_values.is_empty() {
+        return 0.;
+    }
+    let index = (fraction * (sorted_values.len() - 1) as f64).round() as usize;
+    sorted_values[index.min(sorted_values.len() - 1)]
+}
+
+fn push_spherical_model(
+    system: &mut Vec<Particle>,
+    samples: Vec<(DVec3, DVec3)>,
+    total_mass: &f64,
+    half_mass_radius: &f64,
+    virial_ratio: &f64,
+    color: &Color,
+    category_name: &str,
+) -> (usize, usize) {
+    let first_body = system.len();
+    let body_mass = *total_mass / samples.len().max(1) as f64;
+
+    let mut projected_radii: Vec<f64> = samples.iter().map(|s| s.0.truncate().length()).collect();
+    projected_radii.sort_by(|a, b| a.total_cmp(b));
+    let model_half_mass_radius = quantile(&projected_radii, 0.5).max(f64::MIN_POSITIVE);
+    let length_scale = *half_mass_radius / model_half_mass_radius;
+
+    for (i, (position, velocity)) in samples.iter().enumerate() {
+        system.push(Particle {
+            mass: body_mass,
+            position: position.truncate() * length_scale,
+            velocity: velocity.truncate(),
+            radius: STAR_RADIUS,
+            color: *color,
+            name: format!("{} {}", category_name, i + 1),
+        });
+    }
+    let bodies = &mut system[first_body..];
+    remove_center_of_mass_motion(bodies);
+    set_virial_ratio(bodies, *virial_ratio);
+
+    count_bodies(bodies)
+}
+
+fn count_bodies(bodies: &[Particle]) -> (usize, usize) {
+    let important_bodies = bodies
+        .iter()
+        .filter(|body| body.mass >= IMPORTANT_BODY_MASS_MIN)
+        .count();
+    (bodies.len(), important_bodies)
+}
+
+fn remove_center_of_mass_motion(bodies: &mut [Particle]) {
+    let total_mass: f64 = bodies.iter().map(|body| body.mass).sum();
+    if total_mass <= 0. {
+        return;
+    }
+    let center_of_mass: DVec2 = bodies
+        .iter()
+        .map(|body| body.position * body.mass)
+        .sum::<DVec2>()
+        / total_mass;
+    let center_of_mass_velocity: DVec2 = bodies
+        .iter()
+        .map(|body| body.velocity * body.mass)
+        .sum::<DVec2>()
+        / total_mass;
+    for body in bodies.iter_mut() {
+        body.position -= center_of_mass;
+        body.velocity -= center_of_mass_velocity;
+    }
+}
+
+// Rescales the velocities so that 2K / |W| equals twice the virial ratio (0.5 is equilibrium)
+fn set_virial_ratio(bodies: &mut [Particle], virial_ratio: f64) {
+    let kinetic_energy: f64 = bodies.iter().map(|b| b.calculate_kinetic_energy()).sum();
+    let potential_energy: f64 = (0..bodies.len())
+        .into_par_iter()
+        .map(|i| {
+            bodies[i + 1..]
+                .iter()
+                .filter(|other| other.mass > COLLISION_MIN_MASS)
+                .map(|other| {
+                    -G * bodies[i].mass * other.mass
+                        / (other.position - bodies[i].position).length()
+                })
+                .sum::<f64>()
+        })
+        .sum();
+
+    let scale = if kinetic_energy > 0. {
+        (virial_ratio * potential_energy.abs() / kinetic_energy).sqrt()
+    } else {
+        0.
+    };
+    for body in bodies.iter_mut() {
+        body.velocity *= scale;
+    }
+}
+
+fn unit_random() -> f64 {
+    gen_range(f64::EPSILON, 1.)
+}
+
+fn random_direction() -> DVec3 {
+    let z: f64 = gen_range(-1., 1.);
+    let azimuth: f64 = gen_range(0., TAU);
+    let planar = (1. - z * z).sqrt();
+    DVec3::new(planar * azimuth.cos(), planar * azimuth.sin(), z)
+}
+
+// Aarseth, Hénon & Wielen (1974) sampling of the Plummer model in units G = M = a = 1
+fn sample_plummer() -> (DVec3, DVec3) {
+    let radius = loop {
+        let radius = 1. / (unit_random().powf(-2. / 3.) - 1.).sqrt();
+        if radius < PLUMMER_TRUNCATION_RADIUS {
+            break radius;
+        }
+    };
+    let speed_fraction = loop {
+        let q: f64 = gen_range(0., 1.);
+        let g: f64 = gen_range(0., 0.1);
+        if g < q * q * (1. - q * q).powf(3.5) {
+            break q;
+        }
+    };
+    let escape_speed = 2f64.sqrt() * (1. + radius * radius).powf(-0.25);
+    (
+        random_direction() * radius,
+        random_direction() * speed_fraction * escape_speed,
+    )
+}
+
+/*
+King (1966) model in units where the velocity dispersion parameter and the King radius are 1.
+The dimensionless potential W(r) is tabulated by integrating Poisson's equation outwards from
+the central potential W0 until it reaches zero at the tidal radius.
+ */
+struct KingProfile {
+    radii: Vec<f64>,
+    potentials: Vec<f64>,
+    cumulative_masses: Vec<f64>,
+}
+
+impl KingProfile {
+    fn solve(central_potential: f64) -> KingProfile {
+        let central_density = king_density(central_potential);
+        let second_derivative = |radius: f64, potential: f64, slope: f64| {
+            -9. * king_density(potential) / central_density - 2. * slope / radius
+        };
+
+        // Series expansion around the center, where W'' = -3
+        let mut radius: f64 = 1e-4;
+        let mut potential = central_potential - 1.5 * radius * radius;
+        let mut slope = -3. * radius;
+
+        let mut profile = KingProfile {
+            radii: vec![0., radius],
+            potentials: vec![central_potential, potential],
+            cumulative_masses: vec![0., 4. / 3. * PI * radius.powi(3)],
+        };
+
+        while potential > 0. {
+            let h = 1e-3 * radius.max(1.);
+            let k1 = (slope, second_derivative(radius, potential, slope));
+            let k2 = (
+                slope + 0.5 * h * k1.1,
+                second_derivative(
+                    radius + 0.5 * h,
+                    potential + 0.5 * h * k1.0,
+                    slope + 0.5 * h * k1.1,
+                ),
+            );
+            let k3 = (
+                slope + 0.5 * h * k2.1,
+                second_derivative(
+                    radius + 0.5 * h,
+                    potential + 0.5 * h * k2.0,
+                    slope + 0.5 * h * k2.1,
+                ),
+            );
+            let k4 = (
+                slope + h * k3.1,
+                second_derivative(radius + h, potential + h * k3.0, slope + h * k3.1),
+            );
+            let previous_density = king_density(potential) / central_density;
+            potential += h / 6. * (k1.0 + 2. * k2.0 + 2. * k3.0 + k4.0);
+            slope += h / 6. * (k1.1 + 2. * k2.1 + 2. * k3.1 + k4.1);
+            let previous_radius = radius;
+            radius += h;
+
+            let density = king_density(potential.max(0.)) / central_density;
+            let shell_mass = 2.
+                * PI
+                * h
+                * (previous_density * previous_radius.powi(2) + density * radius.powi(2));
+            let enclosed_mass = profile.cumulative_masses.last().unwrap() + shell_mass;
+
+            profile.radii.push(radius);
+            profile.potentials.push(potential.max(0.));
+            profile.cumulative_masses.push(enclosed_mass);
+        }
+        profile
+    }
+
+    fn sample(&self) -> (DVec3, DVec3) {
+        let total_mass = *self.cumulative_masses.last().unwrap();
+        let target_mass = gen_range(0., total_mass);
+        let index = self
+            .cumulative_masses
+            .partition_point(|&mass| mass < target_mass)
+            .clamp(1, self.radii.len() - 1);
+        let fraction = (target_mass - self.cumulative_masses[index - 1])
+            / (self.cumulative_masses[index] - self.cumulative_masses[index - 1]);
+        let radius = self.radii[index - 1] + fraction * (self.radii[index] - self.radii[index - 1]);
+        let potential = self.potentials[index - 1]
+            + fraction * (self.potentials[index] - self.potentials[index - 1]);
+
+        // Lowered Maxwellian: p(v) ~ v^2 (exp(W - v^2/2) - 1) for v below the escape speed
+        let escape_speed = (2. * potential).sqrt();
+        let speed_density =
+            |speed: f64| speed * speed * ((potential - 0.5 * speed * speed).exp() - 1.);
+        let density_bound = 1.1
+            * (1..=32)
+                .map(|i| speed_density(escape_speed * i as f64 / 32.))
+                .fold(0., f64::max);
+        let speed = if density_bound > 0. {
+            loop {
+                let speed = gen_range(0., escape_speed);
+                if gen_range(0., density_bound) < speed_density(speed) {
+                    break speed;
+                }
+            }
+        } else {
+            0.
+        };
+
+        (random_direction() * radius, random_direction() * speed)
+    }
+}
+
+fn king_density(potential: f64) -> f64 {
+    if potential <= 0. {
+        return 0.;
+    }
+    (potential.exp() * erf(potential.sqrt())
+        - (4. * potential / PI).sqrt() * (1. + 2. * potential / 3.))
+        .max(0.)
+}
+
+// Abramowitz & Stegun 7.1.26, accurate to about 1.5e-7
+fn erf(x: f64) -> f64 {
+    let t = 1. / (1. + 0.3275911 * x.abs());
+    let polynomial = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let result = 1. - polynomial * (-x * x).exp();
+    if x >= 0. { result } else { -result }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macroquad::color::WHITE;
+
+    #[test]
+    fn test_king_concentration() {
+        // log10(tidal radius / King radius) from King (1966)
+        for (central_potential, concentration) in [(3., 0.67), (6., 1.26), (9., 2.12)] {
+            let profile = KingProfile::solve(central_potential);
+            let result = profile.radii.last().unwrap().log10();
+            assert!(
+                (result - concentration).abs() < 0.02,
+                "W0 {}: {}",
+                central_potential,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_plummer_sphere_scaling() {
+        let mut system = Vec::new();
+        let added = initialize_plummer_sphere(
+            &mut system,
+            &400,
+            &CLUSTER_MASS,
+            &CLUSTER_HALF_MASS_RADIUS,
+            &WHITE,
+        );
+        assert_eq!(added, (400, 400));
+
+        let total_mass: f64 = system.iter().map(|body| body.mass).sum();
+        assert!((total_mass / CLUSTER_MASS - 1.).abs() < 1e-9);
+
+        let kinetic_energy: f64 = system.iter().map(|b| b.calculate_kinetic_energy()).sum();
+        let potential_energy: f64 = (0..system.len())
+            .map(|i| system[i].find_potential_gravitational_energy(&system, i))
+            .sum();
+        assert!((kinetic_energy / potential_energy.abs() - CLUSTER_VIRIAL_RATIO).abs() < 1e-6);
+    }
+}
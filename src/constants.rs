@@ -5,13 +5,30 @@ use macroquad::math::DVec2;
 pub const STAR_MASS: f64 = 1.9891e30;
 pub const EARTH_MASS: f64 = 5.9722e24;
 
+pub const JUPITER_MASS: f64 = 1.8982e27;
+
 pub const EARTH_RADIUS: f64 = 6.3781e6;
+pub const JUPITER_RADIUS: f64 = 6.9911e7;
 pub const STAR_RADIUS: f64 = 6.957e8;
 pub const COMET_RADIUS: f64 = 7.4e3;
 
+// Defaults for Horizons bodies without physical data
+pub const DEFAULT_SMALL_BODY_RADIUS: f64 = 1e3;
+pub const DEFAULT_SMALL_BODY_DENSITY: f64 = 2e3; // kilograms/meters^3
+pub const DEFAULT_SPACECRAFT_MASS: f64 = 1e3;
+pub const DEFAULT_SPACECRAFT_RADIUS: f64 = 10.;
+
+// Cached Horizons responses older than this are fetched again unless Horizons can't be reached
+pub const HORIZONS_CACHE_MAX_AGE_DAYS: i64 = 30;
+pub const HORIZONS_TIMEOUT_SECONDS: u64 = 5;
+pub const HORIZONS_RETRIES: u32 = 3;
+pub const HORIZONS_INITIAL_BACKOFF_MILLISECONDS: u64 = 500; // Doubles after every retry
+pub const HORIZONS_WORKERS: usize = 4; // Concurrent Horizons downloads
+
 // Orbital Radii
 pub const EARTH_ORBITAL_RADIUS: f64 = 1.496e11;
 pub const AU: f64 = 149597870700.;
+pub const JUPITER_ORBITAL_RADIUS: f64 = 5.2044 * AU;
 
 // Orbital Velocities in meters / second
 // pub const EARTH_ORBITAL_VELOCITY: f64 = 2.978e4;
@@ -19,6 +36,7 @@ pub const AU: f64 = 149597870700.;
 // Fundamental Constants
 pub const G: f64 = 6.674e-11; //meters^3 kilograms^−1 seconds^−2
 pub const SECONDS_IN_YEAR: f64 = 31556926.; // seconds/year
+pub const SECONDS_IN_DAY: f64 = 86400.; // seconds/day
 
 // Simulation Parameters
 // pub const VIEWER_SECONDS_PER_FRAME: f64 = 1./ FRAMES_PER_VIEWER_SECOND as f64;
@@ -26,6 +44,26 @@ pub const SECONDS_IN_YEAR: f64 = 31556926.; // seconds/year
 
 pub const COLLISION_MIN_MASS: f64 = 1.0;
 
+// Star Cluster Parameters
+pub const PARSEC: f64 = 3.0857e16;
+pub const KILOPARSEC: f64 = 1e3 * PARSEC;
+pub const CLUSTER_STAR_NUMBER_MAX: usize = 5000;
+pub const CLUSTER_MASS: f64 = 1e3 * STAR_MASS;
+pub const CLUSTER_HALF_MASS_RADIUS: f64 = PARSEC;
+pub const CLUSTER_VIRIAL_RATIO: f64 = 0.5;
+pub const COLD_COLLAPSE_VIRIAL_RATIO: f64 = 0.0;
+pub const KING_CENTRAL_POTENTIAL: f64 = 6.0;
+pub const PLUMMER_TRUNCATION_RADIUS: f64 = 10.0; // In Plummer radii
+pub const DISK_GALAXY_MASS: f64 = 5e10 * STAR_MASS;
+pub const BULGE_MASS: f64 = 1e10 * STAR_MASS;
+pub const DISK_SCALE_LENGTH: f64 = 3.0 * KILOPARSEC;
+pub const BULGE_SCALE_RADIUS: f64 = 0.5 * KILOPARSEC;
+pub const BULGE_STAR_FRACTION: f64 = 0.2;
+pub const DISK_TRUNCATION_SCALE_LENGTHS: f64 = 8.0;
+pub const DISK_VELOCITY_DISPERSION: f64 = 0.05; // Fraction of the circular speed
+pub const DYNAMICAL_TIMES_PER_FRAME_CLUSTER: f64 = 0.01;
+pub const DYNAMICAL_TIMES_OF_WRITING_CLUSTER: f64 = 40.0;
+
 // Simulation Initialization Parameters
 //pub const COMET_MASS_VARIANCE_MAX: f64 = 0.8;
 //pub const COMET_ORBITAL_RADIUS_VARIANCE_MAX: f64 = 1.2;
@@ -34,28 +72,54 @@ pub const COLLISION_MIN_MASS: f64 = 1.0;
 pub const YEARS_PER_FRAME_SOLAR_SYS: f64 = 0.0005;
 pub const DEFAULT_ANGULAR_OFFSET: f64 = 0.;
 pub const FIGURE_8_SECONDS_PER_FRAME: f64 = 8e6;
+pub const PERIODIC_ORBIT_TIME_UNITS_PER_FRAME: f64 = 0.003;
 pub const SPIRO_SECONDS_PER_FRAME: f64 = 2e4;
 pub const SOLAR_SYS_SECONDS_PER_FRAME: f64 = YEARS_PER_FRAME_SOLAR_SYS * SECONDS_IN_YEAR;
+pub const RESTRICTED_SECONDS_PER_FRAME: f64 = 0.01 * SECONDS_IN_YEAR;
 
 pub const TICKS_PER_FRAME_FIG8: usize = 120; //divide by zero error if 1 IDK why
 
 pub const TICKS_PER_FRAME_SPIRO: usize = 20; //divide by zero error if 1 IDK why
 
 pub const TICKS_PER_FRAME_SOLAR_SYSTEM: usize = 300; //divide by zero error if 1 IDK why
+
+pub const TICKS_PER_FRAME_CLUSTER: usize = 10;
+pub const TICKS_PER_FRAME_RESTRICTED: usize = 20;
+pub const TICKS_PER_FRAME_IMPORTED: usize = 100;
+pub const IMPORTED_TICKS_PER_ORBIT: f64 = 500.; // Ticks in the shortest orbit of an imported file
 pub const EARTH_NUMBER_MAX: usize = 600;
+pub const MASSIVE_ASTEROID_NUMBER_MAX: usize = 2000;
+pub const TEST_PARTICLE_NUMBER_MAX: usize = 100000;
+pub const ASTEROID_MASS: f64 = 10e12;
+pub const ASTEROID_RADIUS: f64 = 25e5;
+pub const KIRKWOOD_INNER_RADIUS_AU: f64 = 2.0;
+pub const KIRKWOOD_OUTER_RADIUS_AU: f64 = 3.6;
 pub const EPSILON: f64 = COMET_RADIUS;
 pub const COLLIDED_POSITION: DVec2 =
     DVec2::new(EARTH_ORBITAL_RADIUS * 1e8, EARTH_ORBITAL_RADIUS * 1e8);
 
 // Data Parameters
 pub const ROW_LIMIT: usize = 24000;
+pub const PARQUET_BATCH_ROWS: usize = 65536; // Rows buffered before each write
+pub const PARQUET_ROW_GROUP_ROWS: usize = 1048576;
+pub const HDF5_SNAPSHOT_INTERVAL: usize = 100; // Output samples per HDF5 snapshot
+pub const SOCKET_DEFAULT_ADDRESS: &str = "127.0.0.1:7878";
+pub const ANALYSIS_CLOSE_APPROACHES: usize = 10; // Closest pairs reported by analyze
+pub const ANALYSIS_MAX_PAIR_BODIES: usize = 200; // Bodies searched for close approaches
+pub const ANALYSIS_MAX_PLOT_POINTS: usize = 4000; // Points per plotted series
+pub const ANALYSIS_PLOT_SIZE: (u32, u32) = (1200, 800);
 pub const PHYSICAL_DATA_INTERVAL: usize = 1;
 pub const YEARS_OF_WRITING_SPIRO: f32 = 8.0;
 pub const YEARS_OF_WRITING_SOLAR_SYSTEM: f32 = 24.0;
 pub const YEARS_OF_WRITING_FIG8: f32 = 1000.0;
+pub const YEARS_OF_WRITING_RESTRICTED: f32 = 2000.0;
+pub const RETURN_MAP_TOLERANCE: f64 = 1e-2; // Unitless phase space distance
+pub const LYAPUNOV_INITIAL_SEPARATION: f64 = 1e-8; // Unitless phase space distance
+pub const LYAPUNOV_RENORMALIZATION_TICKS: usize = 100;
+pub const DRIFT_THRESHOLD: f64 = 1e-4; // Relative energy or angular momentum error
+pub const DRIFT_MAX_HALVINGS: u32 = 8;
 
 pub const LEFT_PAD: usize = 6;
-pub const COLUMNS_PER_OBJECT: usize = 2;
 
 // Graphics Parameters
 pub const SCREEN_SIZE_PIXELS: u32 = 1000;
@@ -65,9 +129,15 @@ pub const ZOOM_VELOCITY: f64 = 0.95;
 pub const SCREEN_SIZE_SPIRO_METERS: f64 = 2.5 * AU;
 pub const SCREEN_SIZE_FIG8_METERS: f64 = 2.5 * AU;
 pub const SCREEN_SIZE_SOLAR_SYS_METERS: f64 = 65.0 * AU;
+pub const SCREEN_SIZE_RESTRICTED_METERS: f64 = 14.0 * AU;
 pub const OLD_FRAME_LIMIT_SPIRO: usize = 2usize.pow(9);
 pub const OLD_FRAME_LIMIT_FIG8: usize = 2usize.pow(11);
+pub const OLD_FRAME_LIMIT_PERIODIC_ORBIT_MAX: usize = 2usize.pow(14);
 pub const OLD_FRAME_LIMIT_SOLAR_SYS: usize = 2usize.pow(11);
+pub const OLD_FRAME_LIMIT_CLUSTER: usize = 2usize.pow(6);
+pub const SCREEN_FIT_QUANTILE: f64 = 0.9;
+pub const SCREEN_FIT_MARGIN: f64 = 1.5;
+pub const SPEED_COLOR_QUANTILES: (f64, f64) = (0.05, 0.95);
 
 pub const SMALL_RADIUS: f64 = EARTH_RADIUS / 10.;
 pub const MAX_TRAIL_LINE_LEN: f32 = EARTH_ORBITAL_RADIUS as f32;
@@ -82,3 +152,6 @@ pub const IMPORTANT_BODY_MASS_MIN: f64 = 0.2 * EARTH_MASS;
 pub const TRAIL_RADIUS: f32 = 1.;
 pub const MAX_RADIUS_PIXELS: f32 = 4.0;
 pub const MIN_RADIUS_PIXELS: f32 = 1.0;
+pub const TEST_PARTICLE_RADIUS_PIXELS: f32 = 1.0;
+pub const ENCOUNTER_RING_PIXELS: f32 = 6.0; // Gap between a body and its encounter ring
+pub const ENCOUNTER_LINE_WIDTH: f32 = 1.5;
@@ -0,0 +1,157 @@
+use crate::constants::DRIFT_MAX_HALVINGS;
+use crate::helpers::{Particle, find_system_angular_momentum, find_system_totals, print_message};
+
+/*
+Watches how far the total energy and angular momentum have moved from where they started, as
+|E - E0| / |E0| and |L - L0| / sum |L_i|. The angular momentum is scaled by the sum of every
+body's own angular momentum because systems like the figure 8 start with none at all. Crossing
+the threshold prints a warning, and when asked to, halves dt and measures from there instead.
+Collisions lose energy on purpose, so they move the baseline too.
+ */
+pub struct DriftMonitor {
+    initial_energy: f64,
+    energy_scale: f64,
+    initial_angular_momentum: f64,
+    angular_momentum_scale: f64,
+    threshold: f64,
+    halve_dt: bool,
+    pub halvings: u32,
+    pub energy_error: f64,
+    pub angular_momentum_error: f64,
+    pub over_threshold: bool,
+}
+
+impl DriftMonitor {
+    pub fn new(system: &[Particle], threshold: f64, halve_dt: bool) -> DriftMonitor {
+        let mut monitor = DriftMonitor {
+            initial_energy: 0.,
+            energy_scale: 0.,
+            initial_angular_momentum: 0.,
+            angular_momentum_scale: 0.,
+            threshold,
+            halve_dt,
+            halvings: 0,
+            energy_error: 0.,
+            angular_momentum_error: 0.,
+            over_threshold: false,
+        };
+        monitor.rebase(system);
+        monitor
+    }
+
+    pub fn rebase(&mut self, system: &[Particle]) {
+        let totals = find_system_totals(system);
+        self.initial_energy = totals.total_energy();
+        // Falls back to the size of the terms when they happen to cancel
+        self.energy_scale = if self.initial_energy != 0. {
+            self.initial_energy.abs()
+        } else {
+            totals.kinetic_energy + totals.potential_energy.abs()
+        };
+        self.initial_angular_momentum = find_system_angular_momentum(system);
+        self.angular_momentum_scale = system
+            .iter()
+            .map(|body| body.calculate_angular_momentum().abs())
+            .sum();
+        self.energy_error = 0.;
+        self.angular_momentum_error = 0.;
+        self.over_threshold = false;
+    }
+
+    // Updates the errors, and halves dt_origin if the drift is too large and halving is on
+    pub fn check(&mut self, system: &[Particle], dt_origin: &mut f64) {
+        let energy = find_system_totals(system).total_energy();
+        self.energy_error = relative_error(energy, self.initial_energy, self.energy_scale);
+        self.angular_momentum_error = relative_error(
+            find_system_angular_momentum(system),
+            self.initial_angular_momentum,
+            self.angular_momentum_scale,
+        );
+
+        let exceeded = self.energy_error.max(self.angular_momentum_error) > self.threshold;
+        if !exceeded {
+            self.over_threshold = false;
+            return;
+        }
+        if self.over_threshold {
+            return;
+        }
+        self.over_threshold = true;
+        print_message(&format!(
+            "Warning: energy error {:.2e} and angular momentum error {:.2e}, over the threshold of {:.2e}",
+            self.energy_error, self.angular_momentum_error, self.threshold
+        ));
+        if self.halve_dt && self.halvings < DRIFT_MAX_HALVINGS {
+            *dt_origin /= 2.;
+            self.halvings += 1;
+            print_message(&format!(
+                "Halved dt to {:.3e} seconds, measuring drift from here",
+                dt_origin
+            ));
+            self.rebase(system);
+        }
+    }
+}
+
+fn relative_error(value: f64, initial: f64, scale: f64) -> f64 {
+    if scale > 0. {
+        (value - initial).abs() / scale
+    } else {
+        0.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+    use crate::helpers::leapfrog_step;
+    use macroquad::color::WHITE;
+    use macroquad::math::DVec2;
+
+    fn two_bodies() -> Vec<Particle> {
+        let body = |mass: f64, position: DVec2, velocity: DVec2| Particle {
+            mass,
+            radius: 1.,
+            position,
+            velocity,
+            color: WHITE,
+            name: String::new(),
+        };
+        let speed = (G * STAR_MASS / AU).sqrt();
+        vec![
+            body(STAR_MASS, DVec2::ZERO, DVec2::ZERO),
+            body(EARTH_MASS, DVec2::new(AU, 0.), DVec2::new(0., speed)),
+        ]
+    }
+
+    #[test]
+    fn test_small_steps_stay_under_threshold() {
+        let mut system = two_bodies();
+        let mut monitor = DriftMonitor::new(&system, DRIFT_THRESHOLD, false);
+        let mut dt = SECONDS_IN_DAY;
+        for _ in 0..365 {
+            leapfrog_step(&mut system, dt);
+        }
+        monitor.check(&system, &mut dt);
+        assert!(monitor.energy_error < DRIFT_THRESHOLD);
+        assert!(monitor.angular_momentum_error < 1e-9);
+        assert!(!monitor.over_threshold);
+    }
+
+    #[test]
+    fn test_drift_halves_dt() {
+        let mut system = two_bodies();
+        let mut monitor = DriftMonitor::new(&system, DRIFT_THRESHOLD, true);
+        // Knocking the planet off its orbit changes the energy, as a bad step would
+        system[1].velocity *= 1.01;
+        let mut dt = SECONDS_IN_DAY;
+        monitor.check(&system, &mut dt);
+        assert_eq!(dt, SECONDS_IN_DAY / 2.);
+        assert_eq!(monitor.halvings, 1);
+        // Measured from the new state, so nothing is over the threshold any more
+        monitor.check(&system, &mut dt);
+        assert_eq!(dt, SECONDS_IN_DAY / 2.);
+        assert!(monitor.energy_error < 1e-12);
+    }
+}
@@ -0,0 +1,354 @@
+use crate::constants::{AU, COLLISION_MIN_MASS};
+use crate::helpers::{Particle, get_int_from_user, get_number_from_user, print_message};
+use csv::Writer;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncounterCriterion {
+    HillRadii(f64), // Multiples of the pair's mutual Hill radius about the primary
+    Distance(f64),  // Meters
+}
+
+impl EncounterCriterion {
+    fn threshold(self, system: &[Particle], primary: usize, i: usize, j: usize) -> Option<f64> {
+        match self {
+            EncounterCriterion::Distance(distance) => Some(distance),
+            EncounterCriterion::HillRadii(multiple) => {
+                if i == primary || j == primary {
+                    return None;
+                }
+                let center = &system[primary];
+                let mean_distance = ((system[i].position - center.position).length()
+                    + (system[j].position - center.position).length())
+                    / 2.;
+                let mass_ratio = (system[i].mass + system[j].mass) / (3. * center.mass);
+                Some(multiple * mass_ratio.cbrt() * mean_distance)
+            }
+        }
+    }
+}
+
+// One pass of two bodies within the encounter distance of each other
+#[derive(Debug, Clone)]
+pub struct Encounter {
+    pub bodies: (usize, usize),
+    pub threshold: f64, // Meters, when the encounter started
+    pub start_time: f64,
+    pub end_time: Option<f64>, // None if the run ended during the encounter
+    pub closest_time: f64,
+    pub min_distance: f64,
+    pub relative_speed: f64, // At closest approach
+}
+
+/*
+Finds pairs of bodies closer than the criterion allows, checked every tick. The mutual Hill radius
+is ((m_i + m_j) / 3M)^(1/3) (a_i + a_j) / 2 with M the primary, the most massive body, and a the
+current distance to it, so the primary itself has no Hill encounters. Between ticks the bodies
+are taken to move in straight lines, which places the closest approach inside the tick rather
+than on it.
+ */
+pub struct EncounterDetector {
+    criterion: EncounterCriterion,
+    active: HashMap<(usize, usize), Encounter>,
+    pub finished: usize,
+}
+
+impl EncounterDetector {
+    pub fn new(criterion: EncounterCriterion) -> EncounterDetector {
+        EncounterDetector {
+            criterion,
+            active: HashMap::new(),
+            finished: 0,
+        }
+    }
+
+    // Updates the encounters after a tick of length dt ending at time, returning those that ended
+    pub fn step(&mut self, system: &[Particle], time: f64, dt: f64) -> Vec<Encounter> {
+        let Some(primary) = system
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.mass.total_cmp(&b.mass))
+            .map(|(i, _)| i)
+        else {
+            return Vec::new();
+        };
+        let criterion = self.criterion;
+        // This tick's closest approach of every pair inside the encounter distance
+        let close: Vec<Encounter> = (0..system.len())
+            .into_par_iter()
+            .filter(|i| system[*i].mass > COLLISION_MIN_MASS)
+            .flat_map_iter(|i| {
+                (i + 1..system.len()).filter_map(move |j| {
+                    if system[j].mass <= COLLISION_MIN_MASS {
+                        return None;
+                    }
+                    let threshold = criterion.threshold(system, primary, i, j)?;
+                    let separation = system[j].position - system[i].position;
+                    if separation.length() > threshold {
+                        return None;
+                    }
+                    let velocity = system[j].velocity - system[i].velocity;
+                    let offset = if velocity.length_squared() > 0. {
+                        (-separation.dot(velocity) / velocity.length_squared()).clamp(-dt, 0.)
+                    } else {
+                        0.
+                    };
+                    Some(Encounter {
+                        bodies: (i, j),
+                        threshold,
+                        start_time: time,
+                        end_time: None,
+                        closest_time: time + offset,
+                        min_distance: (separation + velocity * offset).length(),
+                        relative_speed: velocity.length(),
+                    })
+                })
+            })
+            .collect();
+
+        let mut still_close = HashSet::with_capacity(close.len());
+        for candidate in close {
+            still_close.insert(candidate.bodies);
+            let encounter = self
+                .active
+                .entry(candidate.bodies)
+                .or_insert(candidate.clone());
+            if candidate.min_distance < encounter.min_distance {
+                encounter.min_distance = candidate.min_distance;
+                encounter.closest_time = candidate.closest_time;
+                encounter.relative_speed = candidate.relative_speed;
+            }
+        }
+
+        let ended: Vec<(usize, usize)> = self
+            .active
+            .keys()
+            .filter(|pair| !still_close.contains(pair))
+            .copied()
+            .collect();
+        let mut finished: Vec<Encounter> = ended
+            .into_iter()
+            .filter_map(|pair| self.active.remove(&pair))
+            .map(|mut encounter| {
+                encounter.end_time = Some(time);
+                encounter
+            })
+            .collect();
+        finished.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+        self.finished += finished.len();
+        finished
+    }
+
+    pub fn active_pairs(&self) -> Vec<(usize, usize)> {
+        self.active.keys().copied().collect()
+    }
+
+    // Encounters still going when the run stops, without an end time
+    pub fn finish(&mut self) -> Vec<Encounter> {
+        let mut remaining: Vec<Encounter> = self
+            .active
+            .drain()
+            .map(|(_, encounter)| encounter)
+            .collect();
+        remaining.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+        remaining
+    }
+}
+
+pub fn choose_encounter_criterion() -> EncounterCriterion {
+    loop {
+        match get_int_from_user(
+            "What counts as an encounter? \n[0] Within a number of mutual Hill radii\n[1] Within a fixed distance",
+        ) {
+            0 => {
+                return EncounterCriterion::HillRadii(
+                    get_number_from_user("How many Hill radii?") as f64
+                );
+            }
+            1 => {
+                return EncounterCriterion::Distance(
+                    get_number_from_user("What distance, in AU?") as f64 * AU,
+                );
+            }
+            _ => println!("Invalid choice"),
+        }
+    }
+}
+
+// Opens {prefix}_encounters.csv, one row per encounter as it ends
+pub fn create_encounter_writer(prefix: &str) -> io::Result<Writer<File>> {
+    let mut wtr = Writer::from_writer(File::create(format!("{}_encounters.csv", prefix))?);
+    add_encounter_topline(&mut wtr)?;
+    Ok(wtr)
+}
+
+// Writes the encounters that ended, and stops writing them as soon as that fails
+pub fn add_encounters<W: Write>(
+    wtr: &mut Option<Writer<W>>,
+    encounters: &[Encounter],
+    system: &[Particle],
+) {
+    let Some(w) = wtr else {
+        return;
+    };
+    for encounter in encounters {
+        if let Err(e) = add_encounter_data(encounter, system, w) {
+            print_message(&format!("Stopped writing encounters: {}", e));
+            *wtr = None;
+            return;
+        }
+    }
+}
+
+pub fn add_encounter_topline<W: Write>(wtr: &mut Writer<W>) -> io::Result<()> {
+    wtr.write_record([
+        "Body A",
+        "Body B",
+        "Start Time",
+        "End Time",
+        "Closest Approach Time",
+        "Minimum Separation",
+        "Relative Speed",
+        "Encounter Distance",
+    ])?;
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn add_encounter_data<W: Write>(
+    encounter: &Encounter,
+    system: &[Particle],
+    wtr: &mut Writer<W>,
+) -> io::Result<()> {
+    let name = |i: usize| {
+        if system[i].name.is_empty() {
+            i.to_string()
+        } else {
+            system[i].name.clone()
+        }
+    };
+    wtr.write_record([
+        name(encounter.bodies.0),
+        name(encounter.bodies.1),
+        encounter.start_time.to_string(),
+        encounter
+            .end_time
+            .map(|time| time.to_string())
+            .unwrap_or_default(),
+        encounter.closest_time.to_string(),
+        encounter.min_distance.to_string(),
+        encounter.relative_speed.to_string(),
+        encounter.threshold.to_string(),
+    ])?;
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+    use macroquad::color::WHITE;
+    use macroquad::math::DVec2;
+
+    fn body(mass: f64, position: DVec2, velocity: DVec2) -> Particle {
+        Particle {
+            mass,
+            radius: 1.,
+            position,
+            velocity,
+            color: WHITE,
+            name: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_flyby_encounter() {
+        // Two bodies passing 1000 km apart at 10 km/s, moving in straight lines
+        let speed = 1e4;
+        let state = |time: f64| {
+            vec![
+                body(EARTH_MASS, DVec2::new(0., 0.), DVec2::ZERO),
+                body(1e3, DVec2::new(speed * time, 1e6), DVec2::new(speed, 0.)),
+            ]
+        };
+        let mut detector = EncounterDetector::new(EncounterCriterion::Distance(1e7));
+        let dt = 7.;
+        let mut finished = Vec::new();
+        let mut time = -2000.;
+        while time < 2000. {
+            time += dt;
+            finished.extend(detector.step(&state(time), time, dt));
+        }
+        assert_eq!(finished.len(), 1);
+        let encounter = &finished[0];
+        assert_eq!(encounter.bodies, (0, 1));
+        assert!((encounter.min_distance / 1e6 - 1.).abs() < 1e-9);
+        assert!(encounter.closest_time.abs() < 1e-6);
+        assert_eq!(encounter.relative_speed, speed);
+        assert!(detector.finish().is_empty());
+    }
+
+    #[test]
+    fn test_hill_radius_criterion() {
+        let star = body(STAR_MASS, DVec2::ZERO, DVec2::ZERO);
+        let planet = body(EARTH_MASS, DVec2::new(AU, 0.), DVec2::ZERO);
+        let hill_radius = AU * (EARTH_MASS / (3. * STAR_MASS)).cbrt();
+        let mut detector = EncounterDetector::new(EncounterCriterion::HillRadii(1.));
+        // A moonlet well inside the Hill sphere, ignoring the star
+        let moonlet = body(1e3, DVec2::new(AU + 0.1 * hill_radius, 0.), DVec2::ZERO);
+        detector.step(&[star.clone(), planet.clone(), moonlet], 0., 1.);
+        assert_eq!(detector.active_pairs(), [(1, 2)]);
+
+        let remaining = detector.finish();
+        assert_eq!(remaining.len(), 1);
+        assert!(remaining[0].end_time.is_none());
+        let outside = body(1e3, DVec2::new(AU + 2. * hill_radius, 0.), DVec2::ZERO);
+        assert!(detector.step(&[star, planet, outside], 1., 1.).is_empty());
+        assert!(detector.active_pairs().is_empty());
+    }
+
+    // A writer that fails like a full disk
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("no space left"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::other("no space left"))
+        }
+    }
+
+    #[test]
+    fn test_encounter_rows() {
+        let system = [
+            body(EARTH_MASS, DVec2::ZERO, DVec2::ZERO),
+            body(1e3, DVec2::new(1e6, 0.), DVec2::new(0., 1e4)),
+        ];
+        let encounter = Encounter {
+            bodies: (0, 1),
+            threshold: 1e7,
+            start_time: 10.,
+            end_time: None,
+            closest_time: 12.,
+            min_distance: 1e6,
+            relative_speed: 1e4,
+        };
+        let mut wtr = Some(Writer::from_writer(Vec::new()));
+        add_encounter_topline(wtr.as_mut().unwrap()).unwrap();
+        add_encounters(&mut wtr, std::slice::from_ref(&encounter), &system);
+        let text = String::from_utf8(wtr.unwrap().into_inner().unwrap()).unwrap();
+        let rows: Vec<&str> = text.lines().collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1], "0,1,10,,12,1000000,10000,10000000");
+
+        let mut failing = Some(Writer::from_writer(FailingWriter));
+        add_encounters(&mut failing, &[encounter], &system);
+        assert!(failing.is_none());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::constants::{HDF5_SNAPSHOT_INTERVAL, SECONDS_IN_DAY};
+use crate::helpers::Particle;
+use crate::horizon::julian_date;
+use crate::output_sink::OutputSink;
+use chrono::NaiveDateTime;
+use hdf5_pure::{AttrValue, FileBuilder};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/*
+Snapshots in the GADGET/AREPO HDF5 layout that yt, pynbody and similar tools read: one file per
+snapshot in {prefix}_snapshots, named snapshot_0000.hdf5 and so on, each with
+  /Header     attributes Time, NumPart_ThisFile, NumPart_Total, MassTable, the unit system and,
+              when the scenario has an epoch, JulianDate
+  /PartType1  ParticleIDs, Masses, Coordinates (N x 3), Velocities (N x 3), plus Radii and Names
+Every body is PartType1 and z is always zero. Units are SI, which the Unit* attributes give in
+the cgs form those tools expect. Only every HDF5_SNAPSHOT_INTERVAL-th sample becomes a snapshot.
+ */
+pub struct Hdf5Snapshots {
+    directory: PathBuf,
+    epoch_julian_date: Option<f64>,
+    samples_seen: usize,
+    snapshots_written: usize,
+}
+
+impl Hdf5Snapshots {
+    pub fn create(prefix: &str, epoch: Option<NaiveDateTime>) -> io::Result<Hdf5Snapshots> {
+        let directory = PathBuf::from(format!("{}_snapshots", prefix));
+        fs::create_dir_all(&directory)?;
+        Ok(Hdf5Snapshots {
+            directory,
+            epoch_julian_date: epoch.map(|epoch| julian_date(&epoch)),
+            samples_seen: 0,
+            snapshots_written: 0,
+        })
+    }
+}
+
+impl OutputSink for Hdf5Snapshots {
+    fn add_data(&mut self, system: &[Particle], time: f64) -> io::Result<()> {
+        self.samples_seen += 1;
+        if !(self.samples_seen - 1).is_multiple_of(HDF5_SNAPSHOT_INTERVAL) {
+            return Ok(());
+        }
+        let path = self
+            .directory
+            .join(format!("snapshot_{:04}.hdf5", self.snapshots_written));
+        snapshot_file(system, time, self.epoch_julian_date)
+            .write(path)
+            .map_err(io::Error::other)?;
+        self.snapshots_written += 1;
+        Ok(())
+    }
+
+    // Every snapshot is complete as soon as it is written
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn snapshot_file(system: &[Particle], time: f64, epoch_julian_date: Option<f64>) -> FileBuilder {
+    let count = system.len() as u64;
+    let mut number_of_particles = vec![0u32; 6];
+    number_of_particles[1] = count as u32;
+
+    let mut builder = FileBuilder::new();
+    let mut header = builder.create_group("Header");
+    header.set_attr("Time", AttrValue::F64(time));
+    header.set_attr("Redshift", AttrValue::F64(0.));
+    header.set_attr("BoxSize", AttrValue::F64(0.));
+    header.set_attr("NumFilesPerSnapshot", AttrValue::I32(1));
+    header.set_attr(
+        "NumPart_ThisFile",
+        AttrValue::U32Array(number_of_particles.clone()),
+    );
+    header.set_attr("NumPart_Total", AttrValue::U32Array(number_of_particles));
+    header.set_attr("NumPart_Total_HighWord", AttrValue::U32Array(vec![0; 6]));
+    header.set_attr("MassTable", AttrValue::F64Array(vec![0.; 6]));
+    header.set_attr("UnitLength_in_cm", AttrValue::F64(100.));
+    header.set_attr("UnitMass_in_g", AttrValue::F64(1000.));
+    header.set_attr("UnitVelocity_in_cm_per_s", AttrValue::F64(100.));
+    if let Some(epoch) = epoch_julian_date {
+        header.set_attr("JulianDate", AttrValue::F64(epoch + time / SECONDS_IN_DAY));
+    }
+    builder.add_group(header.finish());
+
+    let mut particles = builder.create_group("PartType1");
+    let ids: Vec<u64> = (0..count).collect();
+    let masses: Vec<f64> = system.iter().map(|body| body.mass).collect();
+    let radii: Vec<f64> = system.iter().map(|body| body.radius).collect();
+    let coordinates: Vec<f64> = system
+        .iter()
+        .flat_map(|body| [body.position.x, body.position.y, 0.])
+        .collect();
+    let velocities: Vec<f64> = system
+        .iter()
+        .flat_map(|body| [body.velocity.x, body.velocity.y, 0.])
+        .collect();
+    let names: Vec<&str> = system.iter().map(|body| body.name.as_str()).collect();
+    particles.create_dataset("ParticleIDs").with_u64_data(&ids);
+    particles.create_dataset("Masses").with_f64_data(&masses);
+    particles.create_dataset("Radii").with_f64_data(&radii);
+    particles
+        .create_dataset("Coordinates")
+        .with_f64_data(&coordinates)
+        .with_shape(&[count, 3]);
+    particles
+        .create_dataset("Velocities")
+        .with_f64_data(&velocities)
+        .with_shape(&[count, 3]);
+    particles.create_dataset("Names").with_vlen_strings(&names);
+    builder.add_group(particles.finish());
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+    use crate::output_sink::test_support::{test_prefix, test_system};
+
+    #[test]
+    fn test_hdf5_snapshots() {
+        let system = test_system();
+        let prefix = test_prefix("hdf5");
+        let mut snapshots = Hdf5Snapshots::create(&prefix, None).unwrap();
+        for i in 0..=HDF5_SNAPSHOT_INTERVAL {
+            snapshots.add_data(&system, i as f64).unwrap();
+        }
+        assert_eq!(snapshots.snapshots_written, 2);
+
+        let file = hdf5_pure::File::open(snapshots.directory.join("snapshot_0001.hdf5")).unwrap();
+        let header = file.group("Header").unwrap().attrs().unwrap();
+        assert_eq!(
+            header.get("Time"),
+            Some(&AttrValue::F64(HDF5_SNAPSHOT_INTERVAL as f64))
+        );
+        assert!(!header.contains_key("JulianDate"));
+        let coordinates = file.dataset("PartType1/Coordinates").unwrap();
+        assert_eq!(coordinates.shape().unwrap(), vec![2, 3]);
+        assert_eq!(coordinates.read_f64().unwrap()[3], AU);
+        let ids = file.dataset("PartType1/ParticleIDs").unwrap();
+        assert_eq!(ids.read_u64().unwrap(), vec![0, 1]);
+        fs::remove_dir_all(&snapshots.directory).unwrap();
+    }
+}
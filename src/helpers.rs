@@ -1,13 +1,17 @@
+use crate::body_columns::BodyColumns;
 use crate::constants::*;
+use crate::horizon::describe_epoch;
 use crate::init_helpers::*;
 
+use chrono::NaiveDateTime;
 use csv::Writer;
 use macroquad::prelude::*;
 use macroquad::{color, color::Color, math::DVec2};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use std::fs::File;
-use std::io;
+use rayon::prelude::*;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+#[derive(Clone)]
 pub struct Particle {
     //Particle struct representing different values of bodies being simulated
     pub mass: f64,       //kg
@@ -31,7 +35,7 @@ impl Particle {
 
     // Method calculating total force acting upon a body from the input of the array of
     // all the system's bodies
-    pub fn calculate_g_force(&self, system: &Vec<Particle>, self_index: usize) -> DVec2 {
+    pub fn calculate_g_force(&self, system: &[Particle], self_index: usize) -> DVec2 {
         let mut force_vector = DVec2::new(0., 0.);
 
         for body_number in 0..system.len() {
@@ -51,9 +55,23 @@ impl Particle {
         }
         force_vector
     }
+    // Acceleration felt by a massless test particle from every massive body in the system
+    pub fn calculate_g_acceleration(&self, system: &[Particle]) -> DVec2 {
+        let mut acceleration = DVec2::new(0., 0.);
+
+        for body in system.iter() {
+            if body.mass > COLLISION_MIN_MASS {
+                let displacement = body.position - self.position;
+                let distance: f64 = displacement.length();
+                acceleration += displacement / distance * G * body.mass
+                    / (distance * distance + EPSILON * EPSILON);
+            }
+        }
+        acceleration
+    }
     pub fn find_potential_gravitational_energy(
         &self,
-        system: &Vec<Particle>,
+        system: &[Particle],
         self_index: usize,
     ) -> f64 {
         let mut energy: f64 = 0.;
@@ -76,6 +94,11 @@ impl Particle {
         self.velocity * self.mass
     }
 
+    // About the origin, out of the plane
+    pub fn calculate_angular_momentum(&self) -> f64 {
+        self.mass * self.position.perp_dot(self.velocity)
+    }
+
     pub fn generate_visible_radius(&self) -> f32 {
         let log_min = SMALL_RADIUS.log10() as f32;
         let log_max = STAR_RADIUS.log10() as f32;
@@ -86,6 +109,58 @@ impl Particle {
     }
 }
 
+// One kick-drift-kick leapfrog step, with the forces calculated in parallel
+pub fn leapfrog_step(system: &mut [Particle], dt: f64) {
+    let forces: Vec<DVec2> = (0..system.len())
+        .into_par_iter()
+        .map(|i| system[i].calculate_g_force(system, i))
+        .collect();
+    for (body, force) in system.iter_mut().zip(forces) {
+        body.kick(force, dt);
+    }
+    for body in system.iter_mut() {
+        body.drift(dt);
+    }
+    let forces: Vec<DVec2> = (0..system.len())
+        .into_par_iter()
+        .map(|i| system[i].calculate_g_force(system, i))
+        .collect();
+    for (body, force) in system.iter_mut().zip(forces) {
+        body.kick(force, dt);
+    }
+}
+
+// Leapfrog step for the massive bodies together with test particles, which feel gravity from
+// the massive bodies but exert none, so they only cost O(test particles * massive bodies)
+pub fn leapfrog_step_with_test_particles(
+    system: &mut [Particle],
+    test_particles: &mut [Particle],
+    dt: f64,
+) {
+    kick_test_particles(system, test_particles, dt);
+    test_particles.par_iter_mut().for_each(|p| p.drift(dt));
+    leapfrog_step(system, dt);
+    kick_test_particles(system, test_particles, dt);
+}
+
+fn kick_test_particles(system: &[Particle], test_particles: &mut [Particle], dt: f64) {
+    test_particles.par_iter_mut().for_each(|particle| {
+        particle.velocity += 0.5 * particle.calculate_g_acceleration(system) * dt;
+    });
+}
+
+// Test particles that hit a massive body are absorbed without changing it
+pub fn test_particle_collisions(system: &[Particle], test_particles: &mut Vec<Particle>) -> u32 {
+    let particles_before = test_particles.len();
+    test_particles.retain(|particle| {
+        !system.iter().any(|body| {
+            body.mass > COLLISION_MIN_MASS
+                && (body.position - particle.position).length() < body.radius + particle.radius
+        })
+    });
+    (particles_before - test_particles.len()) as u32
+}
+
 pub fn calculate_orbital_speed(
     center_object_mass: &f64,
     center_object_position: &DVec2,
@@ -96,7 +171,7 @@ pub fn calculate_orbital_speed(
     speed
 }
 
-pub fn find_system_kinetic_energy(system: &Vec<Particle>) -> f64 {
+pub fn find_system_kinetic_energy(system: &[Particle]) -> f64 {
     let mut total_energy: f64 = 0.;
     for i in 0..system.len() {
         if system[i].mass != 0.0 {
@@ -106,7 +181,7 @@ pub fn find_system_kinetic_energy(system: &Vec<Particle>) -> f64 {
     total_energy
 }
 
-pub fn find_system_momentum(system: &Vec<Particle>) -> DVec2 {
+pub fn find_system_momentum(system: &[Particle]) -> DVec2 {
     let mut total_momentum: DVec2 = DVec2::ZERO;
     for i in 0..system.len() {
         if system[i].mass != 0.0 {
@@ -116,6 +191,37 @@ pub fn find_system_momentum(system: &Vec<Particle>) -> DVec2 {
     total_momentum
 }
 
+pub fn find_system_angular_momentum(system: &[Particle]) -> f64 {
+    system
+        .iter()
+        .map(|body| body.calculate_angular_momentum())
+        .sum()
+}
+
+pub struct SystemTotals {
+    pub kinetic_energy: f64,   // Joules
+    pub potential_energy: f64, // Joules
+    pub momentum: DVec2,       // kilograms meters/second
+}
+
+impl SystemTotals {
+    pub fn total_energy(&self) -> f64 {
+        self.kinetic_energy + self.potential_energy
+    }
+}
+
+pub fn find_system_totals(system: &[Particle]) -> SystemTotals {
+    let gravitational_energies: Vec<f64> = (0..system.len())
+        .into_par_iter()
+        .map(|i| system[i].find_potential_gravitational_energy(system, i))
+        .collect();
+    SystemTotals {
+        kinetic_energy: find_system_kinetic_energy(system),
+        potential_energy: gravitational_energies.iter().sum(),
+        momentum: find_system_momentum(system),
+    }
+}
+
 pub fn collision_engine(system: &mut Vec<Particle>) -> u32 {
     let mut number_of_collisions: u32 = 0;
     for i in 0..system.len() {
@@ -158,32 +264,32 @@ pub fn collision_engine(system: &mut Vec<Particle>) -> u32 {
     number_of_collisions
 }
 
-pub fn add_physical_data(system: &Vec<Particle>, time: f64, wtr: &mut Writer<File>, rows: usize) {
-    let mut newline = vec!["".to_string(); system.len() * COLUMNS_PER_OBJECT + LEFT_PAD];
+pub fn add_physical_data<W: Write>(
+    system: &[Particle],
+    columns: &BodyColumns,
+    time: f64,
+    wtr: &mut Writer<W>,
+    rows: usize,
+) -> io::Result<()> {
+    let width = columns.width();
+    let mut newline = vec!["".to_string(); system.len() * width + LEFT_PAD];
 
     newline[0] = time.to_string();
 
-    for i in 0..system.len() {
-        newline[COLUMNS_PER_OBJECT * i + LEFT_PAD] = system[i].position[0].to_string();
-        newline[COLUMNS_PER_OBJECT * i + LEFT_PAD + 1] = system[i].position[1].to_string();
+    for (i, values) in columns.values(system).into_iter().enumerate() {
+        for (j, value) in values.into_iter().enumerate() {
+            newline[width * i + LEFT_PAD + j] = value.to_string();
+        }
     }
 
     if rows % PHYSICAL_DATA_INTERVAL == 0 {
-        let total_kinetic_energy = find_system_kinetic_energy(&system);
-        let total_momentum = find_system_momentum(&system);
-
-        let gravitational_energies: Vec<f64> = (0..system.len())
-            .into_par_iter()
-            .map(|i| system[i].find_potential_gravitational_energy(&system, i))
-            .collect();
-        let total_gravitational_energy: f64 = gravitational_energies.iter().sum();
-        let total_energy: f64 = total_kinetic_energy + total_gravitational_energy;
-
-        newline[1] = total_kinetic_energy.to_string();
-        newline[2] = total_gravitational_energy.to_string();
-        newline[3] = total_energy.to_string();
-        newline[4] = total_momentum.x.to_string();
-        newline[5] = total_momentum.y.to_string();
+        let totals = find_system_totals(system);
+
+        newline[1] = totals.kinetic_energy.to_string();
+        newline[2] = totals.potential_energy.to_string();
+        newline[3] = totals.total_energy().to_string();
+        newline[4] = totals.momentum.x.to_string();
+        newline[5] = totals.momentum.y.to_string();
     } else {
         newline[1] = String::from("NaN");
         newline[2] = String::from("NaN");
@@ -192,31 +298,40 @@ pub fn add_physical_data(system: &Vec<Particle>, time: f64, wtr: &mut Writer<Fil
         newline[5] = String::from("NaN");
     }
 
-    wtr.write_record(newline).unwrap();
-    wtr.flush().unwrap();
+    wtr.write_record(newline)?;
+    Ok(())
 }
 
-pub fn add_topline_data(system: &Vec<Particle>, wtr: &mut Writer<File>) -> io::Result<()> {
-    let mut newline = vec!["".to_string(); system.len() * COLUMNS_PER_OBJECT + LEFT_PAD];
+pub fn add_topline_data<W: Write>(
+    system: &[Particle],
+    columns: &BodyColumns,
+    epoch: Option<NaiveDateTime>,
+    wtr: &mut Writer<W>,
+) -> io::Result<()> {
+    let width = columns.width();
+    let mut newline = vec!["".to_string(); system.len() * width + LEFT_PAD];
+    if let Some(epoch) = epoch {
+        newline[0] = format!("Epoch: {}", describe_epoch(&epoch));
+    }
 
     for i in 0..system.len() {
-        newline[COLUMNS_PER_OBJECT * i + LEFT_PAD] = String::from(format!("{}", system[i].name));
-        newline[COLUMNS_PER_OBJECT * i + LEFT_PAD + 1] =
-            String::from(format!("{}", system[i].name));
+        for j in 0..width {
+            newline[width * i + LEFT_PAD + j] = system[i].name.clone();
+        }
     }
     wtr.write_record(newline)?;
     wtr.flush()?;
-    let mut newline = vec!["".to_string(); system.len() * COLUMNS_PER_OBJECT + LEFT_PAD];
+    let mut newline = vec!["".to_string(); system.len() * width + LEFT_PAD];
     newline[0] = String::from("Mass ->");
 
     for i in 0..system.len() {
-        newline[COLUMNS_PER_OBJECT * i + LEFT_PAD] = String::from(format!("{:2e}", system[i].mass));
-        newline[COLUMNS_PER_OBJECT * i + LEFT_PAD + 1] =
-            String::from(format!("{:2e}", system[i].mass));
+        for j in 0..width {
+            newline[width * i + LEFT_PAD + j] = format!("{:2e}", system[i].mass);
+        }
     }
     wtr.write_record(newline)?;
     wtr.flush()?;
-    let mut newline = vec!["".to_string(); system.len() * COLUMNS_PER_OBJECT + LEFT_PAD];
+    let mut newline = vec!["".to_string(); system.len() * width + LEFT_PAD];
     newline[0] = String::from("Time");
     newline[1] = String::from("Kinetic Energy");
     newline[2] = String::from("Gravitational Potential Energy");
@@ -225,8 +340,9 @@ pub fn add_topline_data(system: &Vec<Particle>, wtr: &mut Writer<File>) -> io::R
     newline[5] = String::from("Y Momentum");
 
     for i in 0..system.len() {
-        newline[COLUMNS_PER_OBJECT * i + LEFT_PAD] = String::from("X".to_string());
-        newline[COLUMNS_PER_OBJECT * i + LEFT_PAD + 1] = String::from("Y".to_string());
+        for (j, name) in columns.wide_names().into_iter().enumerate() {
+            newline[width * i + LEFT_PAD + j] = name.to_string();
+        }
     }
     wtr.write_record(newline)?;
     wtr.flush()?;
@@ -234,11 +350,27 @@ pub fn add_topline_data(system: &Vec<Particle>, wtr: &mut Writer<File>) -> io::R
     Ok(())
 }
 
+// Set once samples are written to standard output, after which prompts and messages go to
+// standard error so the data stays readable
+static STDOUT_IS_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+pub fn reserve_stdout_for_output() {
+    STDOUT_IS_OUTPUT.store(true, Ordering::Relaxed);
+}
+
+pub fn print_message(text: &str) {
+    if STDOUT_IS_OUTPUT.load(Ordering::Relaxed) {
+        eprintln!("{}", text);
+    } else {
+        println!("{}", text);
+    }
+}
+
 pub fn take_user_choice(question: &str) -> bool {
     let answer;
     let mut input = String::new();
     loop {
-        println!("{}", question);
+        print_message(question);
         input.clear();
         io::stdin()
             .read_line(&mut input)
@@ -254,7 +386,7 @@ pub fn take_user_choice(question: &str) -> bool {
                 answer = false;
                 break;
             }
-            _ => println!("Invalid input"),
+            _ => print_message("Invalid input"),
         }
     }
     answer
@@ -274,30 +406,39 @@ pub fn velocity_to_color(velocity: DVec2, minimum_speed_log: f32, maximum_speed_
 pub fn get_number_from_user(text: &str) -> f32 {
     loop {
         let mut user_input: String = String::new();
-        println!("{}", text);
+        print_message(text);
         io::stdin()
             .read_line(&mut user_input)
             .expect("Failed to read line");
         match user_input.trim().parse::<f32>() {
             Ok(number) => return number,
-            Err(_) => println!("Invalid input. Please enter a valid number."),
+            Err(_) => print_message("Invalid input. Please enter a valid number."),
         }
     }
 }
 pub fn get_int_from_user(text: &str) -> u32 {
     loop {
         let mut user_input: String = String::new();
-        println!("{}", text);
+        print_message(text);
         io::stdin()
             .read_line(&mut user_input)
             .expect("Failed to read line");
         match user_input.trim().parse::<u32>() {
             Ok(number) => return number,
-            Err(_) => println!("Invalid input. Please enter a valid number."),
+            Err(_) => print_message("Invalid input. Please enter a valid number."),
         }
     }
 }
 
+pub fn get_text_from_user(text: &str) -> String {
+    let mut user_input: String = String::new();
+    print_message(text);
+    io::stdin()
+        .read_line(&mut user_input)
+        .expect("Failed to read line");
+    user_input.trim().to_string()
+}
+
 pub fn accelerate_dt(dt: &mut f64, dt_origin: f64) {
     let shift = is_key_down(KeyCode::LeftShift);
     let zed = is_key_down(KeyCode::Z);
@@ -310,3 +451,52 @@ pub fn accelerate_dt(dt: &mut f64, dt_origin: f64) {
         *dt = dt_origin;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(mass: f64, position: DVec2, velocity: DVec2) -> Particle {
+        Particle {
+            mass,
+            position,
+            velocity,
+            radius: EARTH_RADIUS,
+            color: WHITE,
+            name: String::from("Body"),
+        }
+    }
+
+    #[test]
+    fn test_particles_follow_light_massive_bodies() {
+        let position = DVec2::new(2.5 * AU, 0.);
+        let velocity = DVec2::new(
+            0.,
+            calculate_orbital_speed(&STAR_MASS, &DVec2::ZERO, position),
+        );
+        let sun = body(STAR_MASS, DVec2::ZERO, DVec2::ZERO);
+        let jupiter_position = DVec2::new(0., JUPITER_ORBITAL_RADIUS);
+        let jupiter_velocity = DVec2::new(
+            -calculate_orbital_speed(&STAR_MASS, &DVec2::ZERO, jupiter_position),
+            0.,
+        );
+        let jupiter = body(JUPITER_MASS, jupiter_position, jupiter_velocity);
+
+        let mut full_system = vec![
+            sun.clone(),
+            jupiter.clone(),
+            body(2. * COLLISION_MIN_MASS, position, velocity),
+        ];
+        let mut system = vec![sun, jupiter];
+        let mut test_particles = vec![body(0., position, velocity)];
+
+        let dt = 1e-3 * SECONDS_IN_YEAR;
+        for _ in 0..4000 {
+            leapfrog_step(&mut full_system, dt);
+            leapfrog_step_with_test_particles(&mut system, &mut test_particles, dt);
+        }
+        let difference = (full_system[2].position - test_particles[0].position).length();
+        assert!(difference < 1e-6 * AU, "{}", difference / AU);
+        assert!((full_system[1].position - system[1].position).length() < 1.);
+    }
+}
@@ -1,16 +1,19 @@
+use crate::constants::{G, HORIZONS_CACHE_MAX_AGE_DAYS, SECONDS_IN_DAY};
 use crate::helpers::take_user_choice;
+use crate::horizons_cache::{CacheEntry, CachePolicy, HorizonsCache};
+use crate::horizons_error::{HorizonsError, api_message};
+use crate::horizons_source::{
+    CachedSource, FixtureSource, HorizonsSource, HttpSource, fetch_all, summarize_origins,
+};
 use crate::horizons_table::*;
+use crate::spk::{SpkKernels, spk_ephemerides};
 use chrono::prelude::*;
 use macroquad::color::Color;
 use macroquad::prelude::*;
 use phf;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::io;
-use std::path::Path;
+use std::f64::consts::PI;
 use std::ptr::eq;
-use std::time::Duration;
-use ureq::Agent;
 
 /*
 $$SOE
@@ -28,181 +31,764 @@ $$EOE
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OutputValues {
     pub name: String,
+    pub kind: BodyKind,
     pub x: f64,
     pub y: f64,
     pub vx: f64,
     pub vy: f64,
+    pub physical_data: PhysicalData,
 }
 
-pub fn get_horizons_data() -> Vec<OutputValues> {
-    let times = date_time_range();
-    let mut body_values: Vec<OutputValues> = Vec::new();
+/*
+PHYSICAL DATA (updated 2019-Oct-29):
+ Vol. mean radius (km) = 3389.92+-0.04   Density (g/cm^3)      =  3.933(5+-4)
+ Mass x10^23 (kg)      =    6.4171       Flattening, f         =  1/169.779
+ GM (km^3/s^2)         = 42828.375214    Mass ratio (Sun/Mars) = 3098703.59
+ GM 1-sigma (km^3/s^2) = +- 0.00028      Mass of atmosphere (kg)= ~ 2.5 x 10^16
+*/
+
+// Physical properties from the object data block, in SI units
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PhysicalData {
+    pub gm: Option<f64>,      // meters^3 seconds^-2
+    pub radius: Option<f64>,  // meters
+    pub density: Option<f64>, // kilograms/meters^3
+}
+
+impl PhysicalData {
+    // Prefers GM, then falls back to the density times the volume
+    pub fn mass(&self) -> Option<f64> {
+        self.gm.map(|gm| gm / G).or_else(|| {
+            self.density
+                .zip(self.radius)
+                .map(|(density, radius)| density * 4. / 3. * PI * radius.powi(3))
+        })
+    }
+}
 
-    let mut horizon_data_files_names = Vec::new();
-    let can_use_cached_file = MAJOR_BODIES.iter().all(|body| {
-        let horizons_data_file = format!("target/cache/{}_data.txt", body);
-        horizon_data_files_names.push(horizons_data_file.clone());
-        Path::new(&horizons_data_file).exists()
-    });
-
-    let cache_choice: bool = if can_use_cached_file {
-        if Path::new("target/cache/CacheInfo.txt").exists() {
-            println!(
-                "Cache Info: {}",
-                fs::read_to_string("target/cache/CacheInfo.txt").unwrap()
-            );
+// A Horizons COMMAND, e.g. "499" for Mars, "2099942" for Apophis, "DES=1P;CAP" for Halley's
+// comet or "-31" for Voyager 1, together with the name its data is cached under
+#[derive(Debug, Clone)]
+pub struct HorizonsTarget {
+    pub name: String,
+    pub command: String,
+}
+
+impl HorizonsTarget {
+    pub fn major_body(name: &str) -> HorizonsTarget {
+        HorizonsTarget {
+            name: name.to_string(),
+            command: HORIZONS_IDS[name].to_string(),
         }
-        !take_user_choice("Get new data? ")
-    } else {
-        false
-    };
-    if !can_use_cached_file {
-        println!("Incomplete cache, retrieving new data");
     }
 
-    for body in MAJOR_BODIES.iter() {
-        let horizons_data = fetch_horizons_data(body.to_string(), cache_choice, &times);
-        match horizons_data {
-            Ok(s) => {
-                body_values.push(parse_horizons_body_data(s, body.to_string()));
+    pub fn from_command(command: &str) -> HorizonsTarget {
+        let name: String = command
+            .trim()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        HorizonsTarget {
+            name,
+            command: command.trim().to_string(),
+        }
+    }
+
+    pub fn is_major_body(&self) -> bool {
+        HORIZONS_IDS.contains_key(self.name.as_str())
+    }
+}
+
+// Horizons' center code for the Sun's body center, so all vectors are heliocentric
+const HORIZONS_CENTER: &str = "500@10";
+
+// Everything that decides what a vector table request returns
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HorizonsQuery {
+    pub command: String,
+    pub center: String,
+    pub start_time: String,
+    pub stop_time: String,
+    pub step_size: String,
+}
+
+impl HorizonsQuery {
+    pub fn new(
+        target: &HorizonsTarget,
+        times: &(String, String),
+        step_size: &str,
+    ) -> HorizonsQuery {
+        HorizonsQuery {
+            command: target.command.clone(),
+            center: HORIZONS_CENTER.to_string(),
+            start_time: times.0.clone(),
+            stop_time: times.1.clone(),
+            step_size: step_size.to_string(),
+        }
+    }
+
+    pub fn url(&self, base_url: &str) -> String {
+        format!(
+            "{}?format=json&COMMAND='{}'\
+            &OBJ_DATA='YES'&MAKE_EPHEM='YES'&EPHEM_TYPE='VECTORS'&CENTER='{}'&START_TIME='{}'\
+            &STOP_TIME='{}'&STEP_SIZE='{}'&VEC_TABLE='2'",
+            base_url,
+            encode_query_value(&self.command),
+            encode_query_value(&self.center),
+            encode_query_value(&self.start_time),
+            encode_query_value(&self.stop_time),
+            encode_query_value(&self.step_size)
+        )
+    }
+
+    /*
+    A file name unique to the query. It starts with the command so the cache can be browsed by
+    hand, and ends with an FNV-1a hash of every field since sanitizing the command can make two
+    different commands look the same.
+     */
+    pub fn cache_key(&self) -> String {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for field in [
+            &self.command,
+            &self.center,
+            &self.start_time,
+            &self.stop_time,
+            &self.step_size,
+        ] {
+            for byte in field.bytes().chain([0]) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
             }
-            Err(e) => eprintln!("Error: {}", e),
         }
+        format!(
+            "{}_{:016x}",
+            HorizonsTarget::from_command(&self.command).name,
+            hash
+        )
     }
+}
+
+// One state from a vector table, in km and km/s like OutputValues
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateVector {
+    pub julian_date: f64,
+    pub x: f64,
+    pub y: f64,
+    pub vx: f64,
+    pub vy: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BodyKind {
+    MajorBody,
+    SmallBody,
+    Comet,
+    Spacecraft,
+}
+
+pub fn get_horizons_data(
+    extra_targets: &[HorizonsTarget],
+    epoch: &NaiveDateTime,
+) -> Vec<OutputValues> {
+    let times = date_time_range(epoch);
+    let targets: Vec<HorizonsTarget> = MAJOR_BODIES
+        .iter()
+        .map(|body| HorizonsTarget::major_body(body))
+        .chain(extra_targets.iter().cloned())
+        .collect();
+
+    // Bodies the local SPK kernels cover never need Horizons
+    let (mut body_values, targets): (Vec<OutputValues>, Vec<HorizonsTarget>) =
+        match SpkKernels::from_env() {
+            Some(kernels) => {
+                let (ephemerides, remaining) =
+                    spk_ephemerides(&kernels, &targets, &[julian_date(epoch)]);
+                let spk_values = ephemerides.into_iter().map(|(values, _)| values).collect();
+                (spk_values, remaining)
+            }
+            None => (Vec::new(), targets),
+        };
+    let spk_bodies: Vec<String> = body_values
+        .iter()
+        .map(|values| values.name.clone())
+        .collect();
+    if targets.is_empty() {
+        println!("{}", summarize_origins(&[], &spk_bodies));
+        return body_values;
+    }
+
+    if let Some(fixtures) = FixtureSource::from_env() {
+        println!(
+            "Reading Horizons responses from {}",
+            fixtures.directory.display()
+        );
+        let (fixture_values, failures) =
+            horizons_data_from_source(&fixtures, &targets, &times, &spk_bodies);
+        report_failures(&failures, spk_bodies.len() + targets.len());
+        body_values.extend(fixture_values);
+        return body_values;
+    }
+
+    let cache = HorizonsCache::default_locations();
+    let cached_entries: Vec<CacheEntry> = targets
+        .iter()
+        .filter_map(|target| cache.load(&HorizonsQuery::new(target, &times, "1 d")))
+        .collect();
+    let policy = if let Some(oldest) = cached_entries.iter().map(|entry| entry.retrieved).min() {
+        println!(
+            "Cache Info: {} of {} bodies cached for {}, oldest retrieved {}",
+            cached_entries.len(),
+            targets.len(),
+            describe_epoch(epoch),
+            oldest.format("%Y-%m-%d %H:%M UTC")
+        );
+        if take_user_choice("Get new data? ") {
+            CachePolicy::Refresh
+        } else {
+            CachePolicy::MaxAge(chrono::Duration::days(HORIZONS_CACHE_MAX_AGE_DAYS))
+        }
+    } else {
+        println!("No cached data for this epoch, retrieving new data");
+        CachePolicy::Refresh
+    };
 
+    let source = CachedSource {
+        cache,
+        policy,
+        upstream: HttpSource::from_env(),
+    };
+    let (horizons_values, failures) =
+        horizons_data_from_source(&source, &targets, &times, &spk_bodies);
+    report_failures(&failures, spk_bodies.len() + targets.len());
+    body_values.extend(horizons_values);
     body_values
 }
 
-fn parse_horizons_body_data(body_result: String, body_name: String) -> OutputValues {
-    let soe = body_result.find("$$SOE").expect("Could not find '$$SOE'");
-    let eoe = body_result.find("$$EOE").expect("Could not find '$$EOE'");
+// A body that could not be loaded and why
+pub struct HorizonsFailure {
+    pub name: String,
+    pub error: HorizonsError,
+}
+
+// Each target's state at the end of the time range, and the targets that failed. The summary
+// also counts the bodies already read from SPK kernels.
+pub fn horizons_data_from_source(
+    source: &dyn HorizonsSource,
+    targets: &[HorizonsTarget],
+    times: &(String, String),
+    spk_bodies: &[String],
+) -> (Vec<OutputValues>, Vec<HorizonsFailure>) {
+    let queries: Vec<HorizonsQuery> = targets
+        .iter()
+        .map(|target| HorizonsQuery::new(target, times, "1 d"))
+        .collect();
+    let responses = fetch_all(source, targets, &queries);
+    println!("{}", summarize_origins(&responses, spk_bodies));
+
+    let mut body_values: Vec<OutputValues> = Vec::new();
+    let mut failures: Vec<HorizonsFailure> = Vec::new();
+    for (target, response) in targets.iter().zip(responses) {
+        let horizons_data =
+            response.and_then(|response| parse_horizons_body_data(&response.text, target));
+        match horizons_data {
+            Ok(values) => body_values.push(values),
+            Err(error) => failures.push(HorizonsFailure {
+                name: target.name.clone(),
+                error,
+            }),
+        }
+    }
+
+    (body_values, failures)
+}
+
+pub fn report_failures(failures: &[HorizonsFailure], total: usize) {
+    if failures.is_empty() {
+        return;
+    }
+    println!("Skipped {} of {} bodies:", failures.len(), total);
+    for failure in failures.iter() {
+        println!("  {}: {}", failure.name, failure.error);
+    }
+}
+
+fn parse_horizons_body_data(
+    body_result: &str,
+    target: &HorizonsTarget,
+) -> Result<OutputValues, HorizonsError> {
+    let states = parse_state_vectors(body_result)?;
+    let state = states[states.len() - 1];
+    let header = &body_result[..body_result.find("$$SOE").unwrap_or(0)];
+
+    // Major bodies keep their table names, anything else is named after Horizons' target name
+    let target_name = parse_target_name(header);
+    let name = match target_name {
+        Some(ref target_name) if !target.is_major_body() => target_name.clone(),
+        _ => target.name.clone(),
+    };
+    Ok(OutputValues {
+        name,
+        kind: parse_body_kind(target, header),
+        x: state.x,
+        y: state.y,
+        vx: state.vx,
+        vy: state.vy,
+        physical_data: parse_physical_data(header),
+    })
+}
+
+/*
+Every state between $$SOE and $$EOE. Each starts with a date line, followed by lines of
+"label = value" fields of which X, Y, VX and VY are needed. Anything else, like the light
+time line of VEC_TABLE 3, is ignored. Without $$SOE the result is Horizons' error message.
+ */
+pub fn parse_state_vectors(body_result: &str) -> Result<Vec<StateVector>, HorizonsError> {
+    let (Some(soe), Some(eoe)) = (body_result.find("$$SOE"), body_result.find("$$EOE")) else {
+        return Err(HorizonsError::Api(api_message(body_result)));
+    };
 
-    let body_ephemeris = &body_result[5 + soe..eoe].trim();
-    let ephemeris_lines: Vec<&str> = body_ephemeris
+    let mut states = Vec::new();
+    let mut current_state: Option<(f64, Vec<(&str, f64)>)> = None;
+    for line in body_result[5 + soe..eoe]
         .lines()
         .map(|l| l.trim())
         .filter(|l| !l.is_empty())
-        .collect();
+    {
+        if line.contains("A.D.") || line.contains("B.C.") {
+            if let Some((julian_date, components)) = current_state.take() {
+                states.push(state_from_components(julian_date, &components)?);
+            }
+            let julian_date = parse_leading_number(line)
+                .ok_or_else(|| HorizonsError::InvalidNumber(line.to_string()))?;
+            current_state = Some((julian_date, Vec::new()));
+        } else if let Some((_, components)) = current_state.as_mut() {
+            components.extend(parse_vector_components(line)?);
+        }
+    }
+    if let Some((julian_date, components)) = current_state {
+        states.push(state_from_components(julian_date, &components)?);
+    }
 
-    let mut body_values: OutputValues = OutputValues {
-        name: body_name,
-        x: 0.0,
-        y: 0.0,
-        vx: 0.0,
-        vy: 0.0,
+    if states.is_empty() {
+        return Err(HorizonsError::MissingComponent("every state".to_string()));
+    }
+    Ok(states)
+}
+
+fn state_from_components(
+    julian_date: f64,
+    components: &[(&str, f64)],
+) -> Result<StateVector, HorizonsError> {
+    let component = |label: &str| {
+        components
+            .iter()
+            .find(|(component_label, _)| *component_label == label)
+            .map(|(_, value)| *value)
+            .ok_or_else(|| {
+                HorizonsError::MissingComponent(format!("{} at JD {}", label, julian_date))
+            })
     };
-    let n = ephemeris_lines.len();
-    body_values.x  = parse_data_component("X",  ephemeris_lines[n - 2]);
-    body_values.y  = parse_data_component("Y",  ephemeris_lines[n - 2]);
-    body_values.vx = parse_data_component("VX", ephemeris_lines[n - 1]);
-    body_values.vy = parse_data_component("VY", ephemeris_lines[n - 1]);
+    Ok(StateVector {
+        julian_date,
+        x: component("X")?,
+        y: component("Y")?,
+        vx: component("VX")?,
+        vy: component("VY")?,
+    })
+}
 
+// Reads every "label = value" field of a line like " X = 6.1E+07 Y = 2.2E+08" or
+// "VX=-2.2E+01 VY= 8.5E+00", however the fields happen to be spaced
+fn parse_vector_components(line: &str) -> Result<Vec<(&str, f64)>, HorizonsError> {
+    let segments: Vec<&str> = line.split('=').collect();
+    let mut components = Vec::new();
+    for pair in segments.windows(2) {
+        let Some(label) = pair[0].split_whitespace().last() else {
+            continue;
+        };
+        let value_text = pair[1].split_whitespace().next().unwrap_or_default();
+        let value = value_text
+            .parse::<f64>()
+            .map_err(|_| HorizonsError::InvalidNumber(format!("{} = {}", label, value_text)))?;
+        components.push((label, value));
+    }
+    Ok(components)
+}
 
-    body_values
+/*
+A vector table saved from Horizons for a single body, which has to be identified from its own
+header. The ID Horizons puts in the last parentheses of the target name, like "Mars (499)" or
+"Voyager 1 (spacecraft) (-31)", is used as the command. Returns the state and its Julian date.
+ */
+pub fn parse_saved_horizons_text(text: &str) -> Result<(OutputValues, f64), HorizonsError> {
+    let header = &text[..text.find("$$SOE").unwrap_or(text.len())];
+    let target_name = parse_target_name(header).unwrap_or_default();
+    let command = target_name
+        .rsplit_once('(')
+        .and_then(|(_, id)| id.split(')').next())
+        .map(|id| id.trim())
+        .filter(|id| id.parse::<i64>().is_ok())
+        .unwrap_or(&target_name);
+    let target = match HORIZONS_IDS
+        .entries()
+        .find(|(_, id)| id.to_string() == command)
+    {
+        Some((body, _)) => HorizonsTarget::major_body(body),
+        None => HorizonsTarget::from_command(command),
+    };
+
+    let values = parse_horizons_body_data(text, &target)?;
+    let states = parse_state_vectors(text)?;
+    Ok((values, states[states.len() - 1].julian_date))
+}
+
+// "Target body name: 99942 Apophis (2004 MN4)      {source: JPL#220}" -> "99942 Apophis (2004 MN4)"
+fn parse_target_name(header: &str) -> Option<String> {
+    let line = header
+        .lines()
+        .find(|line| line.trim_start().starts_with("Target body name:"))?;
+    let name = line.trim_start()["Target body name:".len()..]
+        .split("{source")
+        .next()?
+        .trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
 }
 
-fn parse_data_component(req_value: &str, line: &str) -> f64 {
-    let value_index_start: usize = line
-        .find(req_value)
-        .expect(&format!("Could not find '{}'", req_value))
-        + 3;
-    let value_index_end = value_index_start + 22;
-    line[value_index_start..value_index_end]
-        .to_string()
+fn parse_body_kind(target: &HorizonsTarget, header: &str) -> BodyKind {
+    let target_name = parse_target_name(header).unwrap_or_default();
+    if target.is_major_body() {
+        BodyKind::MajorBody
+    } else if target.command.starts_with('-') {
+        BodyKind::Spacecraft
+    } else if target_name.contains("P/") || target_name.contains("C/") || header.contains(" M1=") {
+        BodyKind::Comet
+    } else {
+        BodyKind::SmallBody
+    }
+}
+
+/*
+Object data comes as one or two "label = value" fields per line, in several different layouts
+depending on the kind of body. Values may carry uncertainties like "3389.92+-0.04" or
+"3.933(5+-4)", which are cut off. Horizons always uses km, km^3/s^2 and g/cm^3 here.
+ */
+pub fn parse_physical_data(object_data: &str) -> PhysicalData {
+    let mut physical_data = PhysicalData::default();
+    let mut radius_priority = usize::MAX;
+
+    for line in object_data.lines() {
+        for (label, value) in label_value_pairs(line) {
+            let label = label.to_lowercase();
+            let first_word = label
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .find(|word| !word.is_empty())
+                .unwrap_or("");
+
+            if first_word == "gm" && !label.contains("sigma") {
+                physical_data.gm.get_or_insert(value * 1e9);
+            } else if label.contains("density") {
+                physical_data.density.get_or_insert(value * 1e3);
+            } else {
+                let priority = if label.contains("mean radius") {
+                    0
+                } else if first_word == "rad" || first_word == "radius" {
+                    1
+                } else if first_word.starts_with("equ") && label.contains("radius") {
+                    2
+                } else {
+                    continue;
+                };
+                if priority < radius_priority {
+                    radius_priority = priority;
+                    physical_data.radius = Some(value * 1e3);
+                }
+            }
+        }
+    }
+    physical_data
+}
+
+// Every "label = number" field in a line. Fields whose value isn't a number are skipped.
+fn label_value_pairs(line: &str) -> Vec<(&str, f64)> {
+    let mut pairs = Vec::new();
+    let mut rest = line;
+    while let Some(equals) = rest.find('=') {
+        let label = rest[..equals].trim();
+        let after = rest[equals + 1..].trim_start_matches([' ', '~']);
+        let token_end = after.find(char::is_whitespace).unwrap_or(after.len());
+        if let Some(value) = parse_leading_number(&after[..token_end]) {
+            pairs.push((label, value));
+        }
+        rest = &after[token_end..];
+    }
+    pairs
+}
+
+// Parses the longest prefix of the text that is a number, e.g. "3.933" from "3.933(5+-4)"
+fn parse_leading_number(text: &str) -> Option<f64> {
+    let bytes = text.as_bytes();
+    let mut end = 0;
+    if end < bytes.len() && (bytes[end] == b'-' || bytes[end] == b'+') {
+        end += 1;
+    }
+    while end < bytes.len() && (bytes[end].is_ascii_digit() || bytes[end] == b'.') {
+        end += 1;
+    }
+    if end < bytes.len() && (bytes[end] == b'e' || bytes[end] == b'E') {
+        let mut exponent_end = end + 1;
+        if exponent_end < bytes.len()
+            && (bytes[exponent_end] == b'-' || bytes[exponent_end] == b'+')
+        {
+            exponent_end += 1;
+        }
+        if exponent_end < bytes.len() && bytes[exponent_end].is_ascii_digit() {
+            end = exponent_end;
+            while end < bytes.len() && bytes[end].is_ascii_digit() {
+                end += 1;
+            }
+        }
+    }
+    text[..end].parse::<f64>().ok()
+}
+
+// Julian date of 1970-01-01 00:00
+const UNIX_EPOCH_JULIAN_DATE: f64 = 2440587.5;
+
+/*
+Reads an epoch typed by the user. Calendar dates look like "2006-01-01", "2006-01-01 12:30"
+or "2006-01-01T12:30:00", Julian dates like "JD2453736.5" or just "2453736.5".
+Horizons reads vector table times as TDB, so the epoch is too.
+ */
+pub fn parse_epoch(text: &str) -> Option<NaiveDateTime> {
+    let text = text.trim();
+    for format in [
+        "%Y-%m-%dT%H:%M:%S%.f",
+        "%Y-%m-%d %H:%M:%S%.f",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M",
+    ] {
+        if let Ok(epoch) = NaiveDateTime::parse_from_str(text, format) {
+            return Some(epoch);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return date.and_hms_opt(0, 0, 0);
+    }
+
+    let julian_date: f64 = text
+        .strip_prefix("JD")
+        .or_else(|| text.strip_prefix("jd"))
+        .unwrap_or(text)
         .trim()
-        .parse::<f64>()
+        .parse()
+        .ok()?;
+    epoch_from_julian_date(julian_date)
+}
+
+pub fn epoch_from_julian_date(julian_date: f64) -> Option<NaiveDateTime> {
+    let milliseconds = ((julian_date - UNIX_EPOCH_JULIAN_DATE) * SECONDS_IN_DAY * 1e3).round();
+    if !milliseconds.is_finite() {
+        return None;
+    }
+    DateTime::from_timestamp_millis(milliseconds as i64).map(|epoch| epoch.naive_utc())
+}
+
+pub fn julian_date(epoch: &NaiveDateTime) -> f64 {
+    epoch.and_utc().timestamp_millis() as f64 / 1e3 / SECONDS_IN_DAY + UNIX_EPOCH_JULIAN_DATE
+}
+
+// Midnight yesterday, the most recent day Horizons is sure to have data for
+pub fn default_epoch() -> NaiveDateTime {
+    (Utc::now() - chrono::Duration::days(1))
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
         .unwrap()
 }
 
-fn date_time_range() -> (String, String) {
-    let now = Utc::now();
-    let start = now - chrono::Duration::days(2);
-    let stop = now - chrono::Duration::days(1);
+pub fn describe_epoch(epoch: &NaiveDateTime) -> String {
+    format!(
+        "{} TDB (JD {})",
+        epoch.format("%Y-%m-%d %H:%M:%S"),
+        julian_date(epoch)
+    )
+}
+
+// Horizons returns a state every day from start to stop, and the last one is used
+fn date_time_range(epoch: &NaiveDateTime) -> (String, String) {
+    let start = *epoch - chrono::Duration::days(1);
     (
-        start.date_naive().to_string(),
-        stop.date_naive().to_string(),
+        start.format("%Y-%m-%d %H:%M:%S").to_string(),
+        epoch.format("%Y-%m-%d %H:%M:%S").to_string(),
     )
 }
 
-fn fetch_horizons_data(
-    body_name: String,
-    cache_choice: bool,
-    times: &(String, String),
-) -> io::Result<String> {
-    fs::create_dir_all("target/cache").map_err(io::Error::other)?;
-    let body_id = HORIZONS_IDS[body_name.as_str()];
+// Fetches every major body's states from the start to the stop time, one every step_days
+pub fn get_horizons_ephemerides(
+    start: &NaiveDateTime,
+    stop: &NaiveDateTime,
+    step_days: u32,
+) -> Vec<(OutputValues, Vec<StateVector>)> {
+    let times = (
+        start.format("%Y-%m-%d %H:%M:%S").to_string(),
+        stop.format("%Y-%m-%d %H:%M:%S").to_string(),
+    );
+    let step_size = format!("{} d", step_days);
+    // Past states of the major bodies barely change between ephemeris releases
+    let source: Box<dyn HorizonsSource> = match FixtureSource::from_env() {
+        Some(fixtures) => Box::new(fixtures),
+        None => Box::new(CachedSource {
+            cache: HorizonsCache::default_locations(),
+            policy: CachePolicy::Forever,
+            upstream: HttpSource::from_env(),
+        }),
+    };
+    let targets: Vec<HorizonsTarget> = MAJOR_BODIES
+        .iter()
+        .map(|body| HorizonsTarget::major_body(body))
+        .collect();
 
-    let horizons_data_file = format!("target/cache/{}_data.txt", body_name);
+    // Horizons' samples run from the start to the stop in whole steps
+    let (start_julian_date, stop_julian_date) = (julian_date(start), julian_date(stop));
+    let julian_dates: Vec<f64> = (0..)
+        .map(|k| start_julian_date + (k * step_days) as f64)
+        .take_while(|sample| *sample <= stop_julian_date + 1e-9)
+        .collect();
+    let (mut ephemerides, targets) = match SpkKernels::from_env() {
+        Some(kernels) => spk_ephemerides(&kernels, &targets, &julian_dates),
+        None => (Vec::new(), targets),
+    };
+    let spk_bodies: Vec<String> = ephemerides
+        .iter()
+        .map(|(values, _)| values.name.clone())
+        .collect();
+    if targets.is_empty() {
+        println!("{}", summarize_origins(&[], &spk_bodies));
+        return ephemerides;
+    }
 
-    let data = if cache_choice {
-        let data = fs::read_to_string(horizons_data_file).map_err(io::Error::other)?;
+    let queries: Vec<HorizonsQuery> = targets
+        .iter()
+        .map(|target| HorizonsQuery::new(target, &times, &step_size))
+        .collect();
+    let responses = fetch_all(source.as_ref(), &targets, &queries);
+    println!("{}", summarize_origins(&responses, &spk_bodies));
 
-        data
-    } else {
-        let ureq_agent_config = Agent::config_builder()
-            .timeout_global(Some(Duration::from_secs(5)))
-            .build();
-        let ureq_agent: Agent = ureq_agent_config.into();
-        let http_url = format!(
-            "https://ssd.jpl.nasa.gov/api/horizons.api?format=json&COMMAND='{}'\
-            &OBJ_DATA='YES'&MAKE_EPHEM='YES'&EPHEM_TYPE='VECTORS'&CENTER='500@10'&START_TIME='{}'\
-            &STOP_TIME='{}'&STEP_SIZE='1%20d'&VEC_TABLE='2'",
-            body_id, times.0, times.1
-        );
-        let json_output: String = ureq_agent
-            .get(&http_url)
-            .call()
-            .map_err(io::Error::other)?
-            .body_mut()
-            .read_to_string()
-            .map_err(io::Error::other)?;
-
-        let parsed_data: serde_json::Value =
-            serde_json::from_str(&json_output).map_err(io::Error::other)?;
-        let data_result = get_data_result(parsed_data)?;
-        fs::write(horizons_data_file, &data_result).map_err(io::Error::other)?;
-        let time_text = format!("{}, {}", times.0, times.1);
-        fs::write("target/cache/CacheInfo.txt", time_text).map_err(io::Error::other)?;
-        data_result
-    };
+    let mut failures = Vec::new();
+    for (target, response) in targets.iter().zip(responses) {
+        let horizons_data = response.and_then(|response| {
+            Ok((
+                parse_horizons_body_data(&response.text, target)?,
+                parse_state_vectors(&response.text)?,
+            ))
+        });
+        match horizons_data {
+            Ok(ephemeris) => ephemerides.push(ephemeris),
+            Err(error) => failures.push(HorizonsFailure {
+                name: target.name.clone(),
+                error,
+            }),
+        }
+    }
+    report_failures(&failures, spk_bodies.len() + targets.len());
 
-    Ok(data)
+    ephemerides
 }
 
-fn get_data_result(parsed_data: serde_json::Value) -> io::Result<String> {
-    let data_result = if let Some(result_data) = parsed_data["result"].as_str() {
-        result_data.to_string()
-    } else {
-        serde_json::to_string_pretty(&parsed_data).map_err(io::Error::other)?
-    };
-    Ok(data_result)
+// Percent encodes everything but unreserved characters, for commands like "DES=1P;CAP"
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::horizons_source::HORIZONS_API_URL;
+
+    // Yesterday's data for a major body, if it has been cached
+    fn cached_horizons_data(body: &str) -> Option<String> {
+        let query = HorizonsQuery::new(
+            &HorizonsTarget::major_body(body),
+            &date_time_range(&default_epoch()),
+            "1 d",
+        );
+        HorizonsCache::default_locations()
+            .load(&query)
+            .map(|entry| entry.data)
+    }
 
     #[test]
     fn test_horizons_data() {
-        let result = fetch_horizons_data("mars".to_string(), true, &date_time_range());
+        let result = cached_horizons_data("mars");
         println!("{:?}", result);
     }
     #[test]
     fn test_date_time_range() {
-        let result = date_time_range();
+        let result = date_time_range(&default_epoch());
         println!("{:?}", result);
     }
     #[test]
     fn test_get_horizons_data() {
-        let result = get_horizons_data();
-        println!("{:?}", result);
+        let directory = std::env::temp_dir().join(format!(
+            "nbodyproblem_horizons_fixtures_{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(
+            directory.join("499.txt"),
+            " Target body name: Mars (499)                      {source: mar097}\n\
+             GM (km^3/s^2)         = 42828.375214   Mass Mars (10^23 kg)  = 6.4171\n\
+            $$SOE\n\
+            2453736.500000000 = A.D. 2006-Jan-01 00:00:00.0000 TDB \n\
+             X = 6.108336946835414E+07 Y = 2.207576654727506E+08 Z = 3.124955669833437E+06\n\
+             VX=-2.243445381356987E+01 VY= 8.522324624760257E+00 VZ= 7.296978814338950E-01\n\
+            $$EOE\n",
+        )
+        .unwrap();
+
+        // The Sun has no fixture, so it is reported and skipped
+        let (result, failures) = horizons_data_from_source(
+            &FixtureSource {
+                directory: directory.clone(),
+            },
+            &[
+                HorizonsTarget::major_body("sun"),
+                HorizonsTarget::major_body("mars"),
+            ],
+            &date_time_range(&parse_epoch("2006-01-01").unwrap()),
+            &[],
+        );
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].name, "mars");
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "sun");
+        assert_eq!(result[0].x, 6.108336946835414E+07);
+        assert_eq!(result[0].physical_data.gm, Some(42828.375214e9));
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 
     #[test]
     fn test_parse_radius_debug() {
         // change body name to whichever is failing
-        let result = fetch_horizons_data("mercury".to_string(), true, &date_time_range());
-        if let Ok(text) = result {
+        let result = cached_horizons_data("mercury");
+        if let Some(text) = result {
             // print just the physical properties section
             let start = text.find("Physical").or_else(|| text.find("PHYSICAL"));
             if let Some(s) = start {
@@ -214,11 +800,179 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_physical_data_planet() {
+        let text = "
+ PHYSICAL DATA (updated 2019-Oct-29):
+  Vol. mean radius (km) = 3389.92+-0.04   Density (g/cm^3)      =  3.933(5+-4)
+  Mass x10^23 (kg)      =    6.4171       Flattening, f         =  1/169.779
+  Volume (x10^10 km^3)  =   16.318        Equatorial radius (km)=  3396.19
+  Sidereal rot. period  =   24.622962 hr  Sidereal rot. rate, rad/s = 0.0000708822
+  GM (km^3/s^2)         = 42828.375214    Mass ratio (Sun/Mars) = 3098703.59
+  GM 1-sigma (km^3/s^2) = +- 0.00028      Mass of atmosphere (kg)= ~ 2.5 x 10^16
+";
+        let result = parse_physical_data(text);
+        assert_eq!(result.gm, Some(42828.375214e9));
+        assert_eq!(result.radius, Some(3389.92e3));
+        assert_eq!(result.density, Some(3933.));
+        let mass = result.mass().unwrap();
+        assert!((mass / 6.4171e23 - 1.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_parse_physical_data_comma_labels() {
+        let text = "
+ PHYSICAL DATA (updated 2018-Aug-15):
+  Vol. Mean Radius, km  = 1737.53+-0.03    Mass, x10^22 kg       =    7.349
+  Radius (gravity), km  = 1738.0           Surface emissivity    =    0.92
+  Radius (IAU), km      = 1737.4           GM, km^3/s^2          = 4902.800066
+  Density, g/cm^3       =    3.3437        GM 1-sigma, km^3/s^2  =  +-0.0001
+";
+        let result = parse_physical_data(text);
+        assert_eq!(result.gm, Some(4902.800066e9));
+        assert_eq!(result.radius, Some(1737.53e3));
+        assert!((result.density.unwrap() - 3343.7).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_parse_physical_data_small_body() {
+        let text = "
+  Keplerian GM   : 1.3271244004127939E+11 km^3/s^2
+   GM= n.a.                RAD= 1200
+   ROTPER= 15.786          H= -1.2
+";
+        let result = parse_physical_data(text);
+        assert_eq!(result.gm, None);
+        assert_eq!(result.radius, Some(1.2e6));
+        assert_eq!(result.mass(), None);
+    }
+
+    #[test]
+    fn test_query_cache_key() {
+        let times = date_time_range(&parse_epoch("2006-01-01").unwrap());
+        let halley = HorizonsQuery::new(&HorizonsTarget::from_command("DES=1P;CAP"), &times, "1 d");
+        let lookalike =
+            HorizonsQuery::new(&HorizonsTarget::from_command("DES_1P_CAP"), &times, "1 d");
+        assert!(halley.cache_key().starts_with("DES_1P_CAP_"));
+        assert_ne!(halley.cache_key(), lookalike.cache_key());
+        assert_eq!(
+            halley.cache_key(),
+            HorizonsQuery::new(&HorizonsTarget::from_command("DES=1P;CAP"), &times, "1 d")
+                .cache_key()
+        );
+        assert!(
+            halley
+                .url(HORIZONS_API_URL)
+                .contains("COMMAND='DES%3D1P%3BCAP'")
+        );
+        assert!(
+            halley
+                .url(HORIZONS_API_URL)
+                .contains("START_TIME='2005-12-31%2000%3A00%3A00'")
+        );
+    }
+
+    #[test]
+    fn test_parse_state_vectors_tolerantly() {
+        let squeezed = "$$SOE\n2453736.5 = A.D. 2006-Jan-01 00:00:00.0000 TDB\n\
+            X=6.1E+07  Y =-2.2E+08 Z = 3.1E+06\n VX=-22.4 VY=8.5\tVZ= 0.7\n$$EOE";
+        let states = parse_state_vectors(squeezed).unwrap();
+        assert_eq!(states[0].y, -2.2E+08);
+        assert_eq!(states[0].vx, -22.4);
+        assert_eq!(states[0].vy, 8.5);
+
+        let missing = "$$SOE\n2453736.5 = A.D. 2006-Jan-01 00:00:00.0000 TDB\n\
+            X = 6.1E+07 Y = 2.2E+08 Z = 3.1E+06\n VX=-22.4\n$$EOE";
+        assert!(matches!(
+            parse_state_vectors(missing),
+            Err(HorizonsError::MissingComponent(component)) if component.starts_with("VY")
+        ));
+        let garbled = "$$SOE\n2453736.5 = A.D. 2006-Jan-01 00:00:00.0000 TDB\n\
+            X = 6.1E+07 Y = n/a Z = 3.1E+06\n$$EOE";
+        assert!(matches!(
+            parse_state_vectors(garbled),
+            Err(HorizonsError::InvalidNumber(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_state_vectors() {
+        let body_result = "header\n$$SOE\n\
+            2453736.500000000 = A.D. 2006-Jan-01 00:00:00.0000 TDB \n\
+             X = 6.108336946835414E+07 Y = 2.207576654727506E+08 Z = 3.124955669833437E+06\n\
+             VX=-2.243445381356987E+01 VY= 8.522324624760257E+00 VZ= 7.296978814338950E-01\n\
+             LT= 7.641085627402825E+02 RG= 2.290739842027565E+08 RR= 2.240659203471744E+00\n\
+            2453737.500000000 = A.D. 2006-Jan-02 00:00:00.0000 TDB \n\
+             X = 5.914254439884686E+07 Y = 2.214848947595732E+08 Z = 3.187872023810804E+06\n\
+             VX=-2.249172010691555E+01 VY= 8.311660149666960E+00 VZ= 7.266905055652093E-01\n\
+             LT= 7.647538810867583E+02 RG= 2.292674457760389E+08 RR= 2.237588564276273E+00\n\
+            $$EOE\n";
+        let states = parse_state_vectors(body_result).unwrap();
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].julian_date, 2453736.5);
+        assert_eq!(states[0].x, 6.108336946835414E+07);
+        assert_eq!(states[1].julian_date, 2453737.5);
+        assert_eq!(states[1].vy, 8.31166014966696);
+        assert!(matches!(
+            parse_state_vectors("No matches found."),
+            Err(HorizonsError::Api(message)) if message == "No matches found."
+        ));
+    }
+
+    #[test]
+    fn test_parse_epoch() {
+        let new_year = NaiveDate::from_ymd_opt(2006, 1, 1)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap();
+        assert_eq!(parse_epoch("2006-01-01"), Some(new_year));
+        assert_eq!(parse_epoch("JD2453736.5"), Some(new_year));
+        assert_eq!(parse_epoch(" 2453736.5 "), Some(new_year));
+        assert_eq!(parse_epoch("2000-01-01T12:00"), parse_epoch("JD 2451545.0"));
+        assert_eq!(
+            julian_date(&parse_epoch("2000-01-01 12:00:00").unwrap()),
+            2451545.0
+        );
+        assert_eq!(parse_epoch("next tuesday"), None);
+
+        let times = date_time_range(&new_year);
+        assert_eq!(times.0, "2005-12-31 00:00:00");
+        assert_eq!(times.1, "2006-01-01 00:00:00");
+    }
+
+    #[test]
+    fn test_parse_target_name_and_kind() {
+        let asteroid = "
+ Target body name: 99942 Apophis (2004 MN4)        {source: JPL#220}
+ Center body name: Sun (10)                        {source: DE441}
+";
+        let target = HorizonsTarget::from_command("DES=99942;");
+        assert_eq!(target.name, "DES_99942_");
+        assert_eq!(
+            parse_target_name(asteroid),
+            Some("99942 Apophis (2004 MN4)".to_string())
+        );
+        assert_eq!(parse_body_kind(&target, asteroid), BodyKind::SmallBody);
+
+        let comet = " Target body name: 1P/Halley                  {source: JPL#75}";
+        let target = HorizonsTarget::from_command("DES=1P;CAP");
+        assert_eq!(parse_body_kind(&target, comet), BodyKind::Comet);
+        assert_eq!(encode_query_value(&target.command), "DES%3D1P%3BCAP");
+
+        let spacecraft = " Target body name: Voyager 1 (spacecraft) (-31) {source: Voyager_1_ST}";
+        let target = HorizonsTarget::from_command("-31");
+        assert_eq!(parse_body_kind(&target, spacecraft), BodyKind::Spacecraft);
+
+        let target = HorizonsTarget::major_body("mars");
+        assert_eq!(target.command, "499");
+        assert_eq!(parse_body_kind(&target, ""), BodyKind::MajorBody);
+    }
+
     #[test]
     fn test_parse_gm_all_bodies() {
         for body in MAJOR_BODIES.iter() {
-            let result = fetch_horizons_data(body.to_string(), true, &date_time_range());
-            if let Ok(text) = result {
+            let result = cached_horizons_data(body);
+            if let Some(text) = result {
                 for line in text.lines() {
                     if line.to_lowercase().contains("gm") {
                         println!("{}: '{}'", body, line);
@@ -0,0 +1,182 @@
+use crate::horizon::HorizonsQuery;
+use chrono::TimeDelta;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const HORIZONS_CACHE_DIR: &str = "target/cache/horizons";
+// Copy HORIZONS_CACHE_DIR here, or point the HORIZONS_BUNDLE environment variable at a copy,
+// to run the Solar System scenario on a machine that can't reach Horizons
+pub const HORIZONS_BUNDLE_DIR: &str = "horizons_bundle";
+
+// One Horizons response together with the query that produced it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub query: HorizonsQuery,
+    pub retrieved: DateTime<Utc>,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CachePolicy {
+    Refresh,           // Always ask Horizons, cached entries are only used if that fails
+    MaxAge(TimeDelta), // Reuse entries retrieved less than this long ago
+    Forever,           // Reuse any entry
+}
+
+impl CachePolicy {
+    pub fn allows(&self, entry: &CacheEntry, now: DateTime<Utc>) -> bool {
+        match self {
+            CachePolicy::Refresh => false,
+            CachePolicy::MaxAge(max_age) => now - entry.retrieved < *max_age,
+            CachePolicy::Forever => true,
+        }
+    }
+}
+
+/*
+Responses are stored one per file, named after the query, so each body and epoch is cached
+on its own and a partial cache is still useful. The bundle directory is only ever read.
+ */
+pub struct HorizonsCache {
+    directory: PathBuf,
+    bundle: Option<PathBuf>,
+}
+
+impl HorizonsCache {
+    pub fn new(directory: impl Into<PathBuf>, bundle: Option<PathBuf>) -> HorizonsCache {
+        HorizonsCache {
+            directory: directory.into(),
+            bundle,
+        }
+    }
+
+    pub fn default_locations() -> HorizonsCache {
+        let bundle = env::var_os("HORIZONS_BUNDLE")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(HORIZONS_BUNDLE_DIR)))
+            .filter(|bundle| bundle.is_dir());
+        HorizonsCache::new(HORIZONS_CACHE_DIR, bundle)
+    }
+
+    // The most recently retrieved entry for the query from either the cache or the bundle
+    pub fn load(&self, query: &HorizonsQuery) -> Option<CacheEntry> {
+        [Some(&self.directory), self.bundle.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter_map(|directory| read_entry(&entry_path(directory, query)))
+            .filter(|entry| entry.query == *query)
+            .max_by_key(|entry| entry.retrieved)
+    }
+
+    // Writes to a temporary file first so an interrupted run never leaves half an entry behind
+    pub fn store(&self, entry: &CacheEntry) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        let path = entry_path(&self.directory, &entry.query);
+        let temporary_path = path.with_extension("json.tmp");
+        let text = serde_json::to_string(entry).map_err(io::Error::other)?;
+        fs::write(&temporary_path, text)?;
+        fs::rename(&temporary_path, &path)
+    }
+}
+
+fn entry_path(directory: &Path, query: &HorizonsQuery) -> PathBuf {
+    directory.join(format!("{}.json", query.cache_key()))
+}
+
+fn read_entry(path: &Path) -> Option<CacheEntry> {
+    let text = fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&text) {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            eprintln!("Ignoring unreadable cache entry {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::horizon::HorizonsTarget;
+
+    fn test_query(command: &str, stop_time: &str) -> HorizonsQuery {
+        HorizonsQuery::new(
+            &HorizonsTarget::from_command(command),
+            &("2006-01-01 00:00:00".to_string(), stop_time.to_string()),
+            "1 d",
+        )
+    }
+
+    fn test_directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!(
+            "nbodyproblem_cache_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn test_cache_keys_entries_by_query() {
+        let directory = test_directory("keys");
+        let cache = HorizonsCache::new(&directory, None);
+        let query = test_query("499", "2006-01-02 00:00:00");
+        let entry = CacheEntry {
+            query: query.clone(),
+            retrieved: Utc::now(),
+            data: "mars".to_string(),
+        };
+        cache.store(&entry).unwrap();
+
+        assert_eq!(cache.load(&query).unwrap().data, "mars");
+        assert!(
+            cache
+                .load(&test_query("499", "2006-01-03 00:00:00"))
+                .is_none()
+        );
+        assert!(
+            cache
+                .load(&test_query("599", "2006-01-02 00:00:00"))
+                .is_none()
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_bundle_and_expiry() {
+        let directory = test_directory("cache");
+        let bundle = test_directory("bundle");
+        let query = test_query("DES=1P;CAP", "2006-01-02 00:00:00");
+        let now = Utc::now();
+        let old_entry = CacheEntry {
+            query: query.clone(),
+            retrieved: now - TimeDelta::days(100),
+            data: "old".to_string(),
+        };
+        HorizonsCache::new(&bundle, None).store(&old_entry).unwrap();
+
+        let cache = HorizonsCache::new(&directory, Some(bundle.clone()));
+        let loaded = cache.load(&query).unwrap();
+        assert_eq!(loaded.data, "old");
+        assert!(!CachePolicy::MaxAge(TimeDelta::days(30)).allows(&loaded, now));
+        assert!(CachePolicy::Forever.allows(&loaded, now));
+        assert!(!CachePolicy::Refresh.allows(&loaded, now));
+
+        // Newer entries in the writable cache win over the bundle
+        cache
+            .store(&CacheEntry {
+                retrieved: now,
+                data: "new".to_string(),
+                ..old_entry
+            })
+            .unwrap();
+        assert_eq!(cache.load(&query).unwrap().data, "new");
+        fs::remove_dir_all(&directory).unwrap();
+        fs::remove_dir_all(&bundle).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+use std::fmt;
+use std::io;
+
+// Everything that can go wrong between asking Horizons for a body and having its state
+#[derive(Debug)]
+pub enum HorizonsError {
+    Network(ureq::Error),
+    // Reading fixtures, or cache files that exist but can't be read
+    Io(io::Error),
+    // The API's JSON wrapper could not be read
+    InvalidJson(String),
+    // Horizons answered, but with a message instead of an ephemeris
+    Api(String),
+    // A state in the ephemeris lacks X, Y, VX or VY
+    MissingComponent(String),
+    // The text after "=" in a state is not a number
+    InvalidNumber(String),
+}
+
+impl fmt::Display for HorizonsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HorizonsError::Network(e) => write!(f, "network error: {}", e),
+            HorizonsError::Io(e) => write!(f, "{}", e),
+            HorizonsError::InvalidJson(e) => write!(f, "unreadable API response: {}", e),
+            HorizonsError::Api(message) => write!(f, "Horizons says: {}", message),
+            HorizonsError::MissingComponent(component) => {
+                write!(f, "ephemeris is missing {}", component)
+            }
+            HorizonsError::InvalidNumber(text) => {
+                write!(f, "could not read '{}' in the ephemeris as a number", text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HorizonsError {}
+
+impl From<io::Error> for HorizonsError {
+    fn from(error: io::Error) -> HorizonsError {
+        HorizonsError::Io(error)
+    }
+}
+
+impl From<ureq::Error> for HorizonsError {
+    fn from(error: ureq::Error) -> HorizonsError {
+        HorizonsError::Network(error)
+    }
+}
+
+/*
+When Horizons can't produce an ephemeris it still answers, with a result like
+"No matches found." or "Multiple major-bodies match string" somewhere after its API banner.
+This picks out the first few lines that aren't part of the banner.
+ */
+pub fn api_message(result: &str) -> String {
+    let message: Vec<&str> = result
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| {
+            !line.is_empty()
+                && !line.starts_with("API VERSION")
+                && !line.starts_with("API SOURCE")
+                && !line.chars().all(|c| c == '*' || c == '-' || c == '=')
+        })
+        .take(3)
+        .collect();
+    if message.is_empty() {
+        "empty response".to_string()
+    } else {
+        message.join(" ")
+    }
+}
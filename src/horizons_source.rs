@@ -0,0 +1,515 @@
+use crate::constants::*;
+use crate::horizon::{HorizonsQuery, HorizonsTarget};
+use crate::horizons_cache::{CacheEntry, CachePolicy, HorizonsCache};
+use crate::horizons_error::{HorizonsError, api_message};
+use chrono::prelude::*;
+use rayon::ThreadPoolBuilder;
+use rayon::prelude::*;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use ureq::Agent;
+
+pub const HORIZONS_API_URL: &str = "https://ssd.jpl.nasa.gov/api/horizons.api";
+
+// Where a response ended up coming from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataOrigin {
+    Network,
+    Cache,
+    StaleCache, // Cached data used because the network failed
+    Fixture,
+}
+
+impl fmt::Display for DataOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            DataOrigin::Network => "network",
+            DataOrigin::Cache => "cache",
+            DataOrigin::StaleCache => "stale cache",
+            DataOrigin::Fixture => "fixture",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+pub struct HorizonsResponse {
+    pub text: String,
+    pub origin: DataOrigin,
+}
+
+// Anything that can answer a Horizons query with the text of its result. Sources are shared
+// between download workers, so they have to be Sync.
+pub trait HorizonsSource: Sync {
+    fn fetch(&self, query: &HorizonsQuery) -> Result<HorizonsResponse, HorizonsError>;
+}
+
+/*
+The Horizons API, or a stand-in server with the same interface if HORIZONS_URL is set.
+The one agent is shared by every download worker so they reuse each other's connections.
+Rate limits, server errors, timeouts and dropped connections are retried with exponential
+backoff. Anything else, like an unknown host, fails straight away.
+ */
+pub struct HttpSource {
+    agent: Agent,
+    base_url: String,
+    retries: u32,
+    initial_backoff: Duration,
+}
+
+impl HttpSource {
+    pub fn new(base_url: &str, retries: u32, initial_backoff: Duration) -> HttpSource {
+        let agent_config = Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(HORIZONS_TIMEOUT_SECONDS)))
+            .build();
+        HttpSource {
+            agent: agent_config.into(),
+            base_url: base_url.to_string(),
+            retries,
+            initial_backoff,
+        }
+    }
+
+    pub fn from_env() -> HttpSource {
+        let base_url = env::var("HORIZONS_URL").unwrap_or_else(|_| HORIZONS_API_URL.to_string());
+        HttpSource::new(
+            &base_url,
+            HORIZONS_RETRIES,
+            Duration::from_millis(HORIZONS_INITIAL_BACKOFF_MILLISECONDS),
+        )
+    }
+
+    fn request(&self, url: &str) -> Result<String, ureq::Error> {
+        self.agent.get(url).call()?.body_mut().read_to_string()
+    }
+}
+
+impl HorizonsSource for HttpSource {
+    fn fetch(&self, query: &HorizonsQuery) -> Result<HorizonsResponse, HorizonsError> {
+        let url = query.url(&self.base_url);
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            match self.request(&url) {
+                Ok(json_output) => {
+                    return get_data_result(&json_output).map(|text| HorizonsResponse {
+                        text,
+                        origin: DataOrigin::Network,
+                    });
+                }
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    eprintln!(
+                        "Horizons request for {} failed ({}), retrying in {:.1} s",
+                        query.command,
+                        e,
+                        backoff.as_secs_f64()
+                    );
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+fn is_transient(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::StatusCode(code) => *code == 429 || *code >= 500,
+        ureq::Error::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::Interrupted
+        ),
+        ureq::Error::Timeout(_) | ureq::Error::ConnectionFailed | ureq::Error::BodyStalled => true,
+        _ => false,
+    }
+}
+
+// The API wraps the text of the result in JSON, or answers with a JSON error message.
+// Results without an ephemeris are errors too, so they never end up in the cache.
+pub fn get_data_result(json_output: &str) -> Result<String, HorizonsError> {
+    let parsed_data: serde_json::Value =
+        serde_json::from_str(json_output).map_err(|e| HorizonsError::InvalidJson(e.to_string()))?;
+    if let Some(error) = parsed_data["error"].as_str() {
+        return Err(HorizonsError::Api(error.trim().to_string()));
+    }
+    let Some(result_data) = parsed_data["result"].as_str() else {
+        return Err(HorizonsError::InvalidJson(
+            "no \"result\" field".to_string(),
+        ));
+    };
+    if !result_data.contains("$$SOE") {
+        return Err(HorizonsError::Api(api_message(result_data)));
+    }
+    Ok(result_data.to_string())
+}
+
+// Answers from the cache when the policy allows it. Otherwise asks the upstream source and
+// caches its answer, falling back on whatever is cached if the upstream source fails.
+pub struct CachedSource<S: HorizonsSource> {
+    pub cache: HorizonsCache,
+    pub policy: CachePolicy,
+    pub upstream: S,
+}
+
+impl<S: HorizonsSource> HorizonsSource for CachedSource<S> {
+    fn fetch(&self, query: &HorizonsQuery) -> Result<HorizonsResponse, HorizonsError> {
+        let cached = self.cache.load(query);
+        if let Some(entry) = &cached
+            && self.policy.allows(entry, Utc::now())
+        {
+            return Ok(HorizonsResponse {
+                text: entry.data.clone(),
+                origin: DataOrigin::Cache,
+            });
+        }
+
+        match self.upstream.fetch(query) {
+            Ok(response) => {
+                let entry = CacheEntry {
+                    query: query.clone(),
+                    retrieved: Utc::now(),
+                    data: response.text,
+                };
+                if let Err(e) = self.cache.store(&entry) {
+                    eprintln!("Could not cache data for {}: {}", query.command, e);
+                }
+                Ok(HorizonsResponse {
+                    text: entry.data,
+                    origin: response.origin,
+                })
+            }
+            Err(e) => match cached {
+                Some(entry) => {
+                    eprintln!(
+                        "Could not reach Horizons for {} ({}), using data retrieved {}",
+                        query.command,
+                        e,
+                        entry.retrieved.format("%Y-%m-%d %H:%M UTC")
+                    );
+                    Ok(HorizonsResponse {
+                        text: entry.data,
+                        origin: DataOrigin::StaleCache,
+                    })
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/*
+Fetches every query on a pool of HORIZONS_WORKERS threads, which is kept small to go easy on
+Horizons, and prints each body as it arrives. Results are in the same order as the queries.
+ */
+pub fn fetch_all(
+    source: &dyn HorizonsSource,
+    targets: &[HorizonsTarget],
+    queries: &[HorizonsQuery],
+) -> Vec<Result<HorizonsResponse, HorizonsError>> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(HORIZONS_WORKERS)
+        .build()
+        .expect("Could not start the Horizons download workers");
+    let finished = AtomicUsize::new(0);
+    pool.install(|| {
+        targets
+            .par_iter()
+            .zip(queries.par_iter())
+            .map(|(target, query)| {
+                let started = Instant::now();
+                let result = source.fetch(query);
+                let count = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                let outcome = match &result {
+                    Ok(response) => format!("from {}", response.origin),
+                    Err(_) => "failed".to_string(),
+                };
+                println!(
+                    "[{:>2}/{}] {} {} in {:.1} s",
+                    count,
+                    queries.len(),
+                    target.name,
+                    outcome,
+                    started.elapsed().as_secs_f64()
+                );
+                result
+            })
+            .collect()
+    })
+}
+
+// "28 bodies: 20 from cache, 6 from network, 2 failed"
+pub fn summarize_origins(
+    results: &[Result<HorizonsResponse, HorizonsError>],
+    spk_bodies: &[String],
+) -> String {
+    let mut parts = Vec::new();
+    if !spk_bodies.is_empty() {
+        parts.push(format!(
+            "{} from SPK kernels ({})",
+            spk_bodies.len(),
+            spk_bodies.join(", ")
+        ));
+    }
+    for origin in [
+        DataOrigin::Cache,
+        DataOrigin::StaleCache,
+        DataOrigin::Network,
+        DataOrigin::Fixture,
+    ] {
+        let count = results
+            .iter()
+            .filter(|result| matches!(result, Ok(response) if response.origin == origin))
+            .count();
+        if count > 0 {
+            parts.push(format!("{} from {}", count, origin));
+        }
+    }
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    if failed > 0 {
+        parts.push(format!("{} failed", failed));
+    }
+    format!(
+        "{} bodies: {}",
+        spk_bodies.len() + results.len(),
+        parts.join(", ")
+    )
+}
+
+/*
+Recorded responses in a directory, for tests and machines without network access. A response
+is looked up as "{cache key}.txt" for that exact query, then as "{command}.txt" with the
+command sanitized like a target name, e.g. "499.txt" for Mars at any epoch. Serving the second
+prints a warning naming the epoch it holds. Files may hold either the raw API JSON or just the
+text of its result.
+ */
+pub struct FixtureSource {
+    pub directory: PathBuf,
+}
+
+impl FixtureSource {
+    // Set HORIZONS_FIXTURES to a directory to use it instead of Horizons
+    pub fn from_env() -> Option<FixtureSource> {
+        env::var_os("HORIZONS_FIXTURES").map(|directory| FixtureSource {
+            directory: PathBuf::from(directory),
+        })
+    }
+}
+
+impl HorizonsSource for FixtureSource {
+    fn fetch(&self, query: &HorizonsQuery) -> Result<HorizonsResponse, HorizonsError> {
+        let command_name = HorizonsTarget::from_command(&query.command).name;
+        let mut exact = true;
+        let text = fs::read_to_string(self.directory.join(format!("{}.txt", query.cache_key())))
+            .or_else(|_| {
+                exact = false;
+                fs::read_to_string(self.directory.join(format!("{}.txt", command_name)))
+            })
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "no fixture for {} in {}",
+                        query.command,
+                        self.directory.display()
+                    ),
+                )
+            })?;
+        let text = if text.trim_start().starts_with('{') {
+            get_data_result(&text)?
+        } else {
+            text
+        };
+        if !exact {
+            eprintln!(
+                "No fixture for {} at {}, using {}.txt with states for {}",
+                query.command,
+                query.stop_time,
+                command_name,
+                last_state_date(&text).unwrap_or("an unknown epoch")
+            );
+        }
+        Ok(HorizonsResponse {
+            text,
+            origin: DataOrigin::Fixture,
+        })
+    }
+}
+
+// The calendar date of the last state in a result, e.g. "A.D. 2006-Jan-01 00:00:00.0000 TDB"
+fn last_state_date(text: &str) -> Option<&str> {
+    let start = text.find("$$SOE")?;
+    let end = text.find("$$EOE").unwrap_or(text.len());
+    text[start..end].lines().rev().find_map(|line| {
+        let (_, date) = line.split_once(" = ")?;
+        let date = date.trim();
+        (date.starts_with("A.D.") || date.starts_with("B.C.")).then_some(date)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    fn test_query() -> HorizonsQuery {
+        HorizonsQuery::new(
+            &HorizonsTarget::major_body("mars"),
+            &(
+                "2005-12-31 00:00:00".to_string(),
+                "2006-01-01 00:00:00".to_string(),
+            ),
+            "1 d",
+        )
+    }
+
+    // Stand-in server that answers the first request with a 503 and the second with a result
+    fn serve_after_one_failure() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let responses = [
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+                {
+                    let body = r#"{"result": "$$SOE Mars $$EOE"}"#;
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                },
+            ];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{}/api/horizons.api", address)
+    }
+
+    #[test]
+    fn test_http_source_retries() {
+        let query = test_query();
+        let url = serve_after_one_failure();
+        let source = HttpSource::new(&url, 2, Duration::from_millis(10));
+        assert_eq!(source.fetch(&query).unwrap().text, "$$SOE Mars $$EOE");
+
+        let url = serve_after_one_failure();
+        let impatient_source = HttpSource::new(&url, 0, Duration::from_millis(10));
+        assert!(impatient_source.fetch(&query).is_err());
+    }
+
+    #[test]
+    fn test_fetch_all_keeps_order() {
+        let directory = env::temp_dir().join(format!(
+            "nbodyproblem_fetch_all_test_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&directory).unwrap();
+        let targets: Vec<HorizonsTarget> = (1..=9)
+            .map(|i| HorizonsTarget::from_command(&format!("{}99", i)))
+            .collect();
+        for target in targets.iter().skip(1) {
+            fs::write(directory.join(format!("{}.txt", target.name)), &target.name).unwrap();
+        }
+        let times = (
+            "2005-12-31 00:00:00".to_string(),
+            "2006-01-01 00:00:00".to_string(),
+        );
+        let queries: Vec<HorizonsQuery> = targets
+            .iter()
+            .map(|target| HorizonsQuery::new(target, &times, "1 d"))
+            .collect();
+
+        let results = fetch_all(
+            &FixtureSource {
+                directory: directory.clone(),
+            },
+            &targets,
+            &queries,
+        );
+        assert!(results[0].is_err());
+        for (target, result) in targets.iter().zip(results.iter()).skip(1) {
+            assert_eq!(result.as_ref().unwrap().text, target.name);
+        }
+        assert_eq!(
+            summarize_origins(&results, &[]),
+            "9 bodies: 8 from fixture, 1 failed"
+        );
+        assert_eq!(
+            summarize_origins(&results, &["earth".to_string(), "moon".to_string()]),
+            "11 bodies: 2 from SPK kernels (earth, moon), 8 from fixture, 1 failed"
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_api_errors() {
+        assert!(matches!(
+            get_data_result(r#"{"error": "Unknown parameter"}"#),
+            Err(HorizonsError::Api(message)) if message == "Unknown parameter"
+        ));
+        let unknown_body = serde_json::json!({
+            "result": "API VERSION: 1.2\nAPI SOURCE: NASA/JPL Horizons API\n\n****\nNo matches found.\n"
+        });
+        assert!(matches!(
+            get_data_result(&unknown_body.to_string()),
+            Err(HorizonsError::Api(message)) if message == "No matches found."
+        ));
+        assert!(matches!(
+            get_data_result("<html>"),
+            Err(HorizonsError::InvalidJson(_))
+        ));
+    }
+
+    #[test]
+    fn test_fixture_source() {
+        let directory =
+            env::temp_dir().join(format!("nbodyproblem_fixture_test_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let source = FixtureSource {
+            directory: directory.clone(),
+        };
+        let query = test_query();
+        assert!(source.fetch(&query).is_err());
+
+        fs::write(
+            directory.join("499.txt"),
+            r#"{"result": "$$SOE any epoch $$EOE"}"#,
+        )
+        .unwrap();
+        assert_eq!(source.fetch(&query).unwrap().text, "$$SOE any epoch $$EOE");
+        fs::write(
+            directory.join(format!("{}.txt", query.cache_key())),
+            "this epoch",
+        )
+        .unwrap();
+        assert_eq!(source.fetch(&query).unwrap().text, "this epoch");
+        fs::remove_dir_all(&directory).unwrap();
+
+        let text = "$$SOE\n2453736.500000000 = A.D. 2006-Jan-01 00:00:00.0000 TDB \n\
+                    X = 6.1E+07 Y = 2.2E+08 Z = 3.1E+06\n$$EOE\n";
+        assert_eq!(
+            last_state_date(text),
+            Some("A.D. 2006-Jan-01 00:00:00.0000 TDB")
+        );
+        assert_eq!(last_state_date("$$SOE any epoch $$EOE"), None);
+    }
+}
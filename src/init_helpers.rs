@@ -1,13 +1,25 @@
+use crate::cluster::*;
 use crate::constants::*;
-use crate::helpers::{Particle, calculate_orbital_speed, get_number_from_user, take_user_choice};
-use crate::horizon::get_horizons_data;
+use crate::helpers::{
+    Particle, calculate_orbital_speed, get_int_from_user, get_number_from_user, get_text_from_user,
+    take_user_choice,
+};
+use crate::horizon::{
+    BodyKind, HorizonsTarget, OutputValues, default_epoch, describe_epoch, get_horizons_data,
+    parse_epoch,
+};
 use crate::horizons_table::*;
 use crate::init_helpers::CenterObjectValues::CenterObjectExists;
+use crate::periodic_orbits_table::*;
+use crate::periodicity::ReturnMapValues;
 use crate::render::ScreenValues;
+use crate::state_import::{ImportedSystem, import_system, shortest_orbital_period};
+use chrono::NaiveDateTime;
 use macroquad::color::*;
 use macroquad::math::{DVec2, Vec2};
 use macroquad::rand::gen_range;
-use std::f64::consts::TAU;
+use std::f64::consts::{PI, TAU};
+use std::path::Path;
 use std::string::ToString;
 
 #[derive(Debug)]
@@ -30,11 +42,14 @@ pub struct ConfigValues {
     pub color_vel_range: (f64, f64),
     pub trail_length: usize,
     pub years_of_writing: f32,
+    pub return_map_values: Option<ReturnMapValues>,
+    pub epoch: Option<NaiveDateTime>, // Date of the initial conditions, if they came from Horizons
 }
 
 pub fn initialize_from_scenario(
     scenario: usize,
     system: &mut Vec<Particle>,
+    test_particles: &mut Vec<Particle>,
     scenario_list: &Vec<ScenarioKey>,
     screen_values: &mut ScreenValues,
 ) -> ConfigValues {
@@ -55,6 +70,8 @@ pub fn initialize_from_scenario(
     let mut trail_length: usize = 0;
     let mut screen_size_meters: u32 = 0;
     let mut offset_pixels = Vec2::ZERO;
+    let mut return_map_values: Option<ReturnMapValues> = None;
+    let mut epoch: Option<NaiveDateTime> = None;
 
     match scenario_name {
         "Spirograph" => {
@@ -149,9 +166,61 @@ pub fn initialize_from_scenario(
             minimum_speed_color = system[0].velocity.length().log10();
             maximum_speed_color = system[2].velocity.length().log10();
             screen_values.initialize(SCREEN_SIZE_PIXELS, SCREEN_SIZE_FIG8_METERS);
+
+            let figure_8 = find_periodic_orbit("Figure 8").expect("Figure 8 missing");
+            return_map_values = Some(periodic_orbit_return_map_values(
+                figure_8,
+                EARTH_ORBITAL_RADIUS,
+                EARTH_MASS,
+            ));
+        }
+        "Periodic Three Body" => {
+            let orbit = choose_periodic_orbit();
+            let periods = loop {
+                let periods = get_number_from_user("How many periods to run?");
+                if periods.is_finite() && periods > 0. {
+                    break periods;
+                }
+                println!("Invalid number of periods: {}", periods);
+            };
+            let bodies_values_delta = initialize_periodic_orbit(
+                system,
+                orbit,
+                &EARTH_ORBITAL_RADIUS,
+                &EARTH_MASS,
+                &EARTH_RADIUS,
+            );
+            total_bodies_added += bodies_values_delta.0;
+            important_bodies_added += bodies_values_delta.1;
+
+            let time_scale = periodic_orbit_time_scale(EARTH_ORBITAL_RADIUS, EARTH_MASS);
+            let period_seconds = orbit.period * time_scale;
+            println!(
+                "{} orbit initialized with {} bodies and a period of {:.2} years",
+                orbit.name,
+                total_bodies_added,
+                period_seconds / SECONDS_IN_YEAR
+            );
+
+            sim_seconds_per_frame = PERIODIC_ORBIT_TIME_UNITS_PER_FRAME * time_scale;
+            ticks_per_frame = TICKS_PER_FRAME_FIG8;
+            years_of_writing = (periods as f64 * period_seconds / SECONDS_IN_YEAR) as f32;
+            trail_length = ((orbit.period / PERIODIC_ORBIT_TIME_UNITS_PER_FRAME) as usize)
+                .clamp(OLD_FRAME_LIMIT_FIG8, OLD_FRAME_LIMIT_PERIODIC_ORBIT_MAX);
+            (minimum_speed_color, maximum_speed_color) =
+                speed_color_range(system, (G * EARTH_MASS / EARTH_ORBITAL_RADIUS).sqrt());
+            screen_values.initialize_to_fit(SCREEN_SIZE_PIXELS, system);
+            return_map_values = Some(periodic_orbit_return_map_values(
+                orbit,
+                EARTH_ORBITAL_RADIUS,
+                EARTH_MASS,
+            ));
         }
         "Solar System" => {
-            let bodies_values_delta = initialize_solar_system(system);
+            let solar_system_epoch = choose_epoch();
+            let bodies_values_delta =
+                initialize_solar_system(system, test_particles, &solar_system_epoch);
+            epoch = Some(solar_system_epoch);
             total_bodies_added += bodies_values_delta.0;
             important_bodies_added += bodies_values_delta.1;
             years_of_writing = YEARS_OF_WRITING_SOLAR_SYSTEM;
@@ -159,10 +228,166 @@ pub fn initialize_from_scenario(
             sim_seconds_per_frame = SOLAR_SYS_SECONDS_PER_FRAME;
             trail_length = OLD_FRAME_LIMIT_SOLAR_SYS;
 
-            minimum_speed_color = system[8].velocity.length().log10();
-            maximum_speed_color = system[1].velocity.length().log10();
+            // Neptune and Mercury bound the speeds, unless Horizons couldn't provide them
+            let log_speed = |name: &str| {
+                system
+                    .iter()
+                    .find(|body| body.name == name)
+                    .map(|body| body.velocity.length().log10())
+            };
+            (minimum_speed_color, maximum_speed_color) =
+                match (log_speed("neptune"), log_speed("mercury")) {
+                    (Some(minimum), Some(maximum)) => (minimum, maximum),
+                    _ => speed_color_range(system, (G * STAR_MASS / EARTH_ORBITAL_RADIUS).sqrt()),
+                };
             screen_values.initialize(SCREEN_SIZE_PIXELS, SCREEN_SIZE_SOLAR_SYS_METERS);
         }
+        "Restricted Three Body" => {
+            let bodies_values_delta = initialize_sun_jupiter(system);
+            total_bodies_added += bodies_values_delta.0;
+            important_bodies_added += bodies_values_delta.1;
+            let sun = find_sun(system).expect("Sun missing from the restricted scenario");
+            let asteroids_added = initialize_asteroids(
+                system,
+                test_particles,
+                sun,
+                &AU,
+                Variance::WithVariance(KIRKWOOD_INNER_RADIUS_AU, KIRKWOOD_OUTER_RADIUS_AU),
+                true,
+            );
+            println!(
+                "Restricted three body scenario initialized with {} bodies and {} asteroids",
+                total_bodies_added, asteroids_added
+            );
+
+            years_of_writing = YEARS_OF_WRITING_RESTRICTED;
+            ticks_per_frame = TICKS_PER_FRAME_RESTRICTED;
+            sim_seconds_per_frame = RESTRICTED_SECONDS_PER_FRAME;
+            trail_length = OLD_FRAME_LIMIT_SOLAR_SYS;
+            minimum_speed_color = system[1].velocity.length().log10();
+            maximum_speed_color = calculate_orbital_speed(
+                &system[0].mass,
+                &system[0].position,
+                DVec2::new(KIRKWOOD_INNER_RADIUS_AU * AU, 0.),
+            )
+            .log10();
+            screen_values.initialize(SCREEN_SIZE_PIXELS, SCREEN_SIZE_RESTRICTED_METERS);
+        }
+        "Imported File" => {
+            let imported = import_from_user();
+            epoch = imported.epoch;
+            total_bodies_added += imported.bodies.len();
+            important_bodies_added += imported.bodies.len();
+            system.extend(imported.bodies);
+            println!(
+                "Imported {} bodies{}",
+                total_bodies_added,
+                epoch
+                    .map(|epoch| format!(" at {}", describe_epoch(&epoch)))
+                    .unwrap_or_default()
+            );
+
+            // Resolve the fastest orbit in the file, whatever its scale
+            ticks_per_frame = TICKS_PER_FRAME_IMPORTED;
+            sim_seconds_per_frame =
+                shortest_orbital_period(system) / IMPORTED_TICKS_PER_ORBIT * ticks_per_frame as f64;
+            if !sim_seconds_per_frame.is_finite() {
+                sim_seconds_per_frame = SOLAR_SYS_SECONDS_PER_FRAME;
+            }
+            years_of_writing = (ROW_LIMIT as f64 * sim_seconds_per_frame / SECONDS_IN_YEAR) as f32;
+            trail_length = OLD_FRAME_LIMIT_SOLAR_SYS;
+            (minimum_speed_color, maximum_speed_color) =
+                speed_color_range(system, (G * STAR_MASS / EARTH_ORBITAL_RADIUS).sqrt());
+            screen_values.initialize_to_fit(SCREEN_SIZE_PIXELS, system);
+        }
+        "Plummer Sphere" | "Cold Collapse" | "King Model" | "Disk Galaxy" => {
+            let star_number: usize = loop {
+                let star_number = get_int_from_user(&format!(
+                    "How many stars? (max {})",
+                    CLUSTER_STAR_NUMBER_MAX
+                )) as usize;
+                if star_number > 0 && star_number <= CLUSTER_STAR_NUMBER_MAX {
+                    break star_number;
+                }
+                println!("Invalid star number: {}", star_number);
+            };
+
+            let (bodies_values_delta, total_mass, radius) = match scenario_name {
+                "Plummer Sphere" => (
+                    initialize_plummer_sphere(
+                        system,
+                        &star_number,
+                        &CLUSTER_MASS,
+                        &CLUSTER_HALF_MASS_RADIUS,
+                        &WHITE,
+                    ),
+                    CLUSTER_MASS,
+                    CLUSTER_HALF_MASS_RADIUS,
+                ),
+                "Cold Collapse" => (
+                    initialize_cold_collapse(
+                        system,
+                        &star_number,
+                        &CLUSTER_MASS,
+                        &CLUSTER_HALF_MASS_RADIUS,
+                        &SKYBLUE,
+                    ),
+                    CLUSTER_MASS,
+                    CLUSTER_HALF_MASS_RADIUS,
+                ),
+                "King Model" => (
+                    initialize_king_model(
+                        system,
+                        &star_number,
+                        &CLUSTER_MASS,
+                        &CLUSTER_HALF_MASS_RADIUS,
+                        &KING_CENTRAL_POTENTIAL,
+                        &GOLD,
+                    ),
+                    CLUSTER_MASS,
+                    CLUSTER_HALF_MASS_RADIUS,
+                ),
+                _ => {
+                    let bulge_star_number = (BULGE_STAR_FRACTION * star_number as f64) as usize;
+                    (
+                        initialize_exponential_disk(
+                            system,
+                            &GalaxyComponent {
+                                bodies: star_number - bulge_star_number,
+                                mass: DISK_GALAXY_MASS,
+                                scale: DISK_SCALE_LENGTH,
+                                color: SKYBLUE,
+                            },
+                            &GalaxyComponent {
+                                bodies: bulge_star_number,
+                                mass: BULGE_MASS,
+                                scale: BULGE_SCALE_RADIUS,
+                                color: ORANGE,
+                            },
+                        ),
+                        DISK_GALAXY_MASS + BULGE_MASS,
+                        DISK_SCALE_LENGTH,
+                    )
+                }
+            };
+            total_bodies_added += bodies_values_delta.0;
+            important_bodies_added += bodies_values_delta.1;
+            println!(
+                "{} scenario initialized with {} stars",
+                scenario_name, total_bodies_added
+            );
+
+            let dynamical_time = dynamical_time(total_mass, radius);
+            ticks_per_frame = TICKS_PER_FRAME_CLUSTER;
+            sim_seconds_per_frame = DYNAMICAL_TIMES_PER_FRAME_CLUSTER * dynamical_time;
+            years_of_writing =
+                (DYNAMICAL_TIMES_OF_WRITING_CLUSTER * dynamical_time / SECONDS_IN_YEAR) as f32;
+            trail_length = OLD_FRAME_LIMIT_CLUSTER;
+
+            (minimum_speed_color, maximum_speed_color) =
+                speed_color_range(system, radius / dynamical_time);
+            screen_values.initialize_to_fit(SCREEN_SIZE_PIXELS, system);
+        }
 
         _ => {
             unreachable!("Initialization failed")
@@ -178,6 +403,8 @@ pub fn initialize_from_scenario(
         color_vel_range: (minimum_speed_color, maximum_speed_color),
         trail_length,
         years_of_writing,
+        return_map_values,
+        epoch,
     };
 
     config_values
@@ -251,35 +478,26 @@ pub fn initialize_figure_8_scenario(
     body_masses: &f64,
     body_radii: &f64,
 ) -> (usize, usize) {
-    let canonical_figure_8_positions = [
-        DVec2::new(-0.97000436, 0.24308753),
-        DVec2::new(0.97000436, -0.24308753),
-        DVec2::new(0.0, 0.0),
-        // Unitless
-    ];
-    let canonical_figure_8_velocities = [
-        DVec2::new(-0.46620368, -0.43236573),
-        DVec2::new(-0.46620368, -0.43236573),
-        DVec2::new(0.93240737, 0.86473146),
-        // Unitless
-    ];
-    let mut real_positions: [DVec2; 3] = [DVec2::ZERO; 3];
-    for i in 0..3 {
-        real_positions[i] = *length_scale * canonical_figure_8_positions[i];
-    }
-    let mut real_velocities: [DVec2; 3] = [DVec2::ZERO; 3];
-    for i in 0..3 {
-        real_velocities[i] =
-            canonical_figure_8_velocities[i] * (G * body_masses / *length_scale).powf(0.5);
-    }
+    let figure_8 = find_periodic_orbit("Figure 8").expect("Figure 8 missing from the catalogue");
+    initialize_periodic_orbit(system, figure_8, length_scale, body_masses, body_radii)
+}
+
+pub fn initialize_periodic_orbit(
+    system: &mut Vec<Particle>,
+    orbit: &PeriodicOrbit,
+    length_scale: &f64,
+    body_masses: &f64,
+    body_radii: &f64,
+) -> (usize, usize) {
+    let velocity_scale = (G * body_masses / *length_scale).powf(0.5);
     for i in 0..3 {
         let new_body = Particle {
             mass: *body_masses,
-            position: real_positions[i],
-            velocity: real_velocities[i],
+            position: *length_scale * orbit.positions[i],
+            velocity: orbit.velocities[i] * velocity_scale,
             radius: *body_radii,
             color: [RED, BLUE, GREEN][i],
-            name: format!("Figure 8 Body {}", i),
+            name: format!("{} Body {}", orbit.name, i),
         };
         system.push(new_body);
     }
@@ -287,48 +505,268 @@ pub fn initialize_figure_8_scenario(
     (3, 3)
 }
 
-pub fn initialize_solar_system(system: &mut Vec<Particle>) -> (usize, usize) {
-    let horizons_values = get_horizons_data();
-    for value in horizons_values.iter() {
-        let new_body = Particle {
-            mass: BODY_MASS_KG[&value.name],
-            radius: BODY_RADIUS_M[&value.name],
-            position: DVec2::new(km_to_meters(value.x), km_to_meters(value.y)),
-            velocity: DVec2::new(
-                km_per_s_to_meters_per_second(value.vx),
-                km_per_s_to_meters_per_second(value.vy),
-            ),
-            color: HORIZONS_COLORS.get(&value.name).copied().unwrap_or(GRAY),
-            name: value.name.clone(),
+// Converts a period from the catalogue's G = m = 1 units to seconds
+pub fn periodic_orbit_time_scale(length_scale: f64, body_masses: f64) -> f64 {
+    (length_scale.powi(3) / (G * body_masses)).sqrt()
+}
+
+pub fn periodic_orbit_return_map_values(
+    orbit: &PeriodicOrbit,
+    length_scale: f64,
+    body_masses: f64,
+) -> ReturnMapValues {
+    let time_scale = periodic_orbit_time_scale(length_scale, body_masses);
+    ReturnMapValues {
+        period: orbit.period * time_scale,
+        length_scale,
+        velocity_scale: length_scale / time_scale,
+        rotation: orbit.rotation,
+    }
+}
+
+pub fn find_periodic_orbit(name: &str) -> Option<&'static PeriodicOrbit> {
+    PERIODIC_ORBITS
+        .iter()
+        .find(|orbit| orbit.name.eq_ignore_ascii_case(name.trim()))
+}
+
+fn choose_periodic_orbit() -> &'static PeriodicOrbit {
+    let mut names_of_orbits: String = "".to_string();
+    for (i, orbit) in PERIODIC_ORBITS.iter().enumerate() {
+        names_of_orbits.push_str(&format!("\n[{}] {}", i, orbit.name));
+    }
+    loop {
+        let answer = get_text_from_user(&format!(
+            "Which orbit? (name or number) {}",
+            names_of_orbits
+        ));
+        let orbit = match answer.parse::<usize>() {
+            Ok(index) => PERIODIC_ORBITS.get(index),
+            Err(_) => find_periodic_orbit(&answer),
         };
-        system.push(new_body);
+        if let Some(orbit) = orbit {
+            return orbit;
+        }
+        println!("Unknown orbit: {}", answer);
     }
+}
 
-    let mut asteroids_added = 0;
+pub fn choose_epoch() -> NaiveDateTime {
+    loop {
+        let text = get_text_from_user(
+            "Epoch for the initial conditions? Give a date like 2006-01-01 or 2006-01-01 12:00, \
+            or a Julian date like JD2453736.5. Leave empty for yesterday.",
+        );
+        if text.trim().is_empty() {
+            return default_epoch();
+        }
+        match parse_epoch(&text) {
+            Some(epoch) => {
+                println!("Using epoch {}", describe_epoch(&epoch));
+                return epoch;
+            }
+            None => println!("Could not read '{}' as a date or Julian date", text.trim()),
+        }
+    }
+}
 
-    if take_user_choice("Add fake asteroids? ") {
-        asteroids_added += initialize_bodies_spiro(
-            &1200,
-            &system.len(),
-            &(2.5 * AU),
-            &10e12,
-            &LIGHTGRAY,
-            &(25e5),
-            &1.,
+pub fn import_from_user() -> ImportedSystem {
+    loop {
+        let text = get_text_from_user(
+            "File of initial conditions? A .csv or .json of state vectors, a text file saved \
+            from Horizons, or a directory of them.",
+        );
+        match import_system(Path::new(text.trim())) {
+            Ok(imported) if !imported.bodies.is_empty() => return imported,
+            Ok(_) => println!("No bodies found in '{}'", text.trim()),
+            Err(e) => println!("Could not import '{}': {}", text.trim(), e),
+        }
+    }
+}
+
+pub fn initialize_solar_system(
+    system: &mut Vec<Particle>,
+    test_particles: &mut Vec<Particle>,
+    epoch: &NaiveDateTime,
+) -> (usize, usize) {
+    let extra_targets: Vec<HorizonsTarget> = get_text_from_user(
+        "Extra Horizons bodies? Separate Horizons commands with commas, e.g. \
+        2099942 (Apophis), DES=1P;CAP (Halley's comet), -31 (Voyager 1). Leave empty for none.",
+    )
+    .split(',')
+    .filter(|command| !command.trim().is_empty())
+    .map(HorizonsTarget::from_command)
+    .collect();
+
+    let first_body = system.len();
+    let horizons_values = get_horizons_data(&extra_targets, epoch);
+    for value in horizons_values.iter() {
+        system.push(horizons_particle(value));
+    }
+    // The vectors are centered on the Sun, so without Horizons it is still at rest at the origin
+    let sun = find_sun(system).unwrap_or_else(|| {
+        println!("No state for the sun, placing it at the origin");
+        system.push(Particle {
+            mass: BODY_MASS_KG["sun"],
+            radius: BODY_RADIUS_M["sun"],
+            position: DVec2::ZERO,
+            velocity: DVec2::ZERO,
+            color: HORIZONS_COLORS["sun"],
+            name: String::from("sun"),
+        });
+        system.len() - 1
+    });
+    let bodies_added = system.len() - first_body;
+
+    let asteroids_added = if take_user_choice("Add fake asteroids? ") {
+        let massless = take_user_choice("Make asteroids massless test particles? ");
+        initialize_asteroids(
             system,
-            &0.,
+            test_particles,
+            sun,
+            &(2.5 * AU),
             Variance::WithVariance(0.8, 1.8),
-            Variance::WithVariance(0.8, 1.2),
-            &CenterObjectExists(system[0].mass, system[0].position),
-            "Asteroids",
+            massless,
         )
-        .0
+    } else {
+        0
+    };
+
+    (bodies_added + asteroids_added, bodies_added)
+}
+
+pub fn find_sun(system: &[Particle]) -> Option<usize> {
+    system
+        .iter()
+        .position(|body| body.name.eq_ignore_ascii_case("sun"))
+}
+
+// A body at the position and velocity Horizons gave for it
+pub fn horizons_particle(value: &OutputValues) -> Particle {
+    let (mass, radius) = resolve_mass_and_radius(value);
+    Particle {
+        mass,
+        radius,
+        position: DVec2::new(km_to_meters(value.x), km_to_meters(value.y)),
+        velocity: DVec2::new(
+            km_per_s_to_meters_per_second(value.vx),
+            km_per_s_to_meters_per_second(value.vy),
+        ),
+        color: HORIZONS_COLORS
+            .get(&value.name)
+            .copied()
+            .unwrap_or(match value.kind {
+                BodyKind::MajorBody => GRAY,
+                BodyKind::SmallBody => LIGHTGRAY,
+                BodyKind::Comet => SKYBLUE,
+                BodyKind::Spacecraft => GREEN,
+            }),
+        name: value.name.clone(),
     }
+}
 
-    (
-        horizons_values.len() + asteroids_added,
-        horizons_values.len(),
+// Mass and radius from the Horizons object data, then from the tables, then from defaults for
+// the kind of body
+fn resolve_mass_and_radius(value: &OutputValues) -> (f64, f64) {
+    let radius = value
+        .physical_data
+        .radius
+        .or_else(|| BODY_RADIUS_M.get(&value.name).copied())
+        .unwrap_or_else(|| {
+            println!("No radius found for {}, using a default", value.name);
+            match value.kind {
+                BodyKind::Spacecraft => DEFAULT_SPACECRAFT_RADIUS,
+                BodyKind::Comet => COMET_RADIUS,
+                _ => DEFAULT_SMALL_BODY_RADIUS,
+            }
+        });
+    let mass = value
+        .physical_data
+        .mass()
+        .or_else(|| BODY_MASS_KG.get(&value.name).copied())
+        .unwrap_or_else(|| {
+            println!("No mass found for {}, using a default", value.name);
+            match value.kind {
+                BodyKind::Spacecraft => DEFAULT_SPACECRAFT_MASS,
+                _ => DEFAULT_SMALL_BODY_DENSITY * 4. / 3. * PI * radius.powi(3),
+            }
+        });
+    (mass, radius)
+}
+
+// Sun and Jupiter on a circular orbit around their barycenter
+pub fn initialize_sun_jupiter(system: &mut Vec<Particle>) -> (usize, usize) {
+    let total_mass = STAR_MASS + JUPITER_MASS;
+    let angular_speed = (G * total_mass / JUPITER_ORBITAL_RADIUS.powi(3)).sqrt();
+    let sun_distance = JUPITER_ORBITAL_RADIUS * JUPITER_MASS / total_mass;
+    let jupiter_distance = JUPITER_ORBITAL_RADIUS - sun_distance;
+
+    system.push(Particle {
+        mass: STAR_MASS,
+        position: DVec2::new(-sun_distance, 0.),
+        velocity: DVec2::new(0., -angular_speed * sun_distance),
+        radius: STAR_RADIUS,
+        color: YELLOW,
+        name: String::from("Sun"),
+    });
+    system.push(Particle {
+        mass: JUPITER_MASS,
+        position: DVec2::new(jupiter_distance, 0.),
+        velocity: DVec2::new(0., angular_speed * jupiter_distance),
+        radius: JUPITER_RADIUS,
+        color: HORIZONS_COLORS["jupiter"],
+        name: String::from("jupiter"),
+    });
+
+    (2, 2)
+}
+
+// Asteroids on circular orbits around system[center], either as massless test particles or as
+// massive bodies that take part in the full force calculation
+pub fn initialize_asteroids(
+    system: &mut Vec<Particle>,
+    test_particles: &mut Vec<Particle>,
+    center: usize,
+    orbital_radius: &f64,
+    orbital_radius_variance: Variance,
+    massless: bool,
+) -> usize {
+    let max_asteroids = if massless {
+        TEST_PARTICLE_NUMBER_MAX
+    } else {
+        MASSIVE_ASTEROID_NUMBER_MAX
+    };
+    let asteroid_number: usize = loop {
+        let asteroid_number =
+            get_int_from_user(&format!("How many asteroids? (max {})", max_asteroids)) as usize;
+        if asteroid_number <= max_asteroids {
+            break asteroid_number;
+        }
+        println!("Invalid asteroid number: {}", asteroid_number);
+    };
+
+    let center_object_values = CenterObjectExists(system[center].mass, system[center].position);
+    let (asteroids, mass) = if massless {
+        (test_particles, 0.)
+    } else {
+        (system, ASTEROID_MASS)
+    };
+    initialize_bodies_spiro(
+        &asteroid_number,
+        &asteroids.len(),
+        orbital_radius,
+        &mass,
+        &LIGHTGRAY,
+        &ASTEROID_RADIUS,
+        &1.,
+        asteroids,
+        &0.,
+        orbital_radius_variance,
+        Variance::WithVariance(0.8, 1.2),
+        &center_object_values,
+        "Asteroids",
     )
+    .0
 }
 
 fn km_to_meters(distance_km: f64) -> f64 {
@@ -0,0 +1,222 @@
+use crate::constants::*;
+use crate::helpers::{Particle, collision_engine, leapfrog_step, print_message};
+use csv::Writer;
+use macroquad::math::DVec2;
+use macroquad::rand::gen_range;
+use std::fs::File;
+use std::io::{self, Write};
+
+/*
+Estimates the maximal Lyapunov exponent with the Benettin et al. (1980) shadow trajectory method.
+A copy of the system is displaced by a tiny distance in phase space and integrated alongside
+the real one. Every so often the separation is measured, its growth is added to a running sum
+of logarithms, and the shadow is pulled back to the initial separation along the same direction.
+ */
+pub struct LyapunovEstimator {
+    shadow: Vec<Particle>,
+    length_scale: f64,
+    velocity_scale: f64,
+    initial_separation: f64,
+    renormalization_ticks: usize,
+    ticks: usize,
+    log_growth_sum: f64,
+    pub elapsed_time: f64,
+    pub exponent: Option<f64>, // In 1/seconds
+}
+
+impl LyapunovEstimator {
+    pub fn new(
+        system: &[Particle],
+        initial_separation: f64,
+        renormalization_ticks: usize,
+    ) -> LyapunovEstimator {
+        let (length_scale, velocity_scale) = characteristic_scales(system);
+        let mut estimator = LyapunovEstimator {
+            shadow: Vec::new(),
+            length_scale,
+            velocity_scale,
+            initial_separation,
+            renormalization_ticks: renormalization_ticks.max(1),
+            ticks: 0,
+            log_growth_sum: 0.,
+            elapsed_time: 0.,
+            exponent: None,
+        };
+        estimator.seed_shadow(system);
+        estimator
+    }
+
+    // Starts the shadow again at the initial separation from the system, in a random direction
+    fn seed_shadow(&mut self, system: &[Particle]) {
+        self.shadow = system.to_vec();
+        for body in self.shadow.iter_mut() {
+            body.position += DVec2::new(gen_range(-1., 1.), gen_range(-1., 1.)) * self.length_scale;
+            body.velocity +=
+                DVec2::new(gen_range(-1., 1.), gen_range(-1., 1.)) * self.velocity_scale;
+        }
+        self.renormalize(system, self.separation(system));
+    }
+
+    // Unitless phase space distance between the system and its shadow
+    pub fn separation(&self, system: &[Particle]) -> f64 {
+        system
+            .iter()
+            .zip(self.shadow.iter())
+            .map(|(body, shadow_body)| {
+                ((shadow_body.position - body.position) / self.length_scale).length_squared()
+                    + ((shadow_body.velocity - body.velocity) / self.velocity_scale)
+                        .length_squared()
+            })
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    // Advances the shadow by one tick. Returns the new estimate whenever it renormalizes.
+    pub fn step(&mut self, system: &[Particle], dt: f64, collisions: bool) -> Option<f64> {
+        leapfrog_step(&mut self.shadow, dt);
+        if collisions {
+            collision_engine(&mut self.shadow);
+        }
+        self.ticks += 1;
+        self.elapsed_time += dt;
+        // A merge in only one of the two leaves the bodies unpaired, so the shadow starts again
+        if self.shadow.len() != system.len() {
+            self.seed_shadow(system);
+            return None;
+        }
+        if !self.ticks.is_multiple_of(self.renormalization_ticks) {
+            return None;
+        }
+
+        let separation = self.separation(system);
+        if separation > 0. {
+            self.log_growth_sum += (separation / self.initial_separation).ln();
+            self.renormalize(system, separation);
+        }
+        self.exponent = Some(self.log_growth_sum / self.elapsed_time);
+        self.exponent
+    }
+
+    fn renormalize(&mut self, system: &[Particle], separation: f64) {
+        let factor = if separation > 0. {
+            self.initial_separation / separation
+        } else {
+            0.
+        };
+        for (shadow_body, body) in self.shadow.iter_mut().zip(system.iter()) {
+            shadow_body.position = body.position + (shadow_body.position - body.position) * factor;
+            shadow_body.velocity = body.velocity + (shadow_body.velocity - body.velocity) * factor;
+        }
+    }
+}
+
+// RMS distance from the center of mass and the matching orbital speed sqrt(G M / R)
+pub fn characteristic_scales(system: &[Particle]) -> (f64, f64) {
+    let total_mass: f64 = system.iter().map(|body| body.mass).sum();
+    let center_of_mass = system
+        .iter()
+        .map(|body| body.position * body.mass)
+        .sum::<DVec2>()
+        / total_mass;
+    let length_scale = (system
+        .iter()
+        .map(|body| (body.position - center_of_mass).length_squared())
+        .sum::<f64>()
+        / system.len() as f64)
+        .sqrt();
+    (length_scale, (G * total_mass / length_scale).sqrt())
+}
+
+pub fn create_lyapunov_writer(prefix: &str) -> io::Result<Writer<File>> {
+    let mut wtr = Writer::from_writer(File::create(format!("{}_lyapunov.csv", prefix))?);
+    add_lyapunov_topline(&mut wtr)?;
+    Ok(wtr)
+}
+
+// Writes one estimate, and stops writing estimates as soon as that fails
+pub fn add_lyapunov_estimate<W: Write>(wtr: &mut Option<Writer<W>>, time: f64, exponent: f64) {
+    let Some(w) = wtr else {
+        return;
+    };
+    if let Err(e) = add_lyapunov_data(time, exponent, w) {
+        print_message(&format!("Stopped writing Lyapunov estimates: {}", e));
+        *wtr = None;
+    }
+}
+
+pub fn add_lyapunov_topline<W: Write>(wtr: &mut Writer<W>) -> io::Result<()> {
+    wtr.write_record(["Time", "Lyapunov Exponent (1/s)", "Lyapunov Time (years)"])?;
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn add_lyapunov_data<W: Write>(
+    time: f64,
+    exponent: f64,
+    wtr: &mut Writer<W>,
+) -> io::Result<()> {
+    wtr.write_record([
+        time.to_string(),
+        exponent.to_string(),
+        (1. / exponent / SECONDS_IN_YEAR).to_string(),
+    ])?;
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::init_helpers::*;
+
+    // Lyapunov exponent in units of the orbit's time scale after the given number of time scales
+    fn estimate_exponent(orbit_name: &str, time_scales: f64) -> f64 {
+        let mut system: Vec<Particle> = Vec::new();
+        let orbit = find_periodic_orbit(orbit_name).unwrap();
+        initialize_periodic_orbit(
+            &mut system,
+            orbit,
+            &EARTH_ORBITAL_RADIUS,
+            &EARTH_MASS,
+            &EARTH_RADIUS,
+        );
+        let time_scale = periodic_orbit_time_scale(EARTH_ORBITAL_RADIUS, EARTH_MASS);
+        let mut estimator = LyapunovEstimator::new(&system, LYAPUNOV_INITIAL_SEPARATION, 100);
+        assert!((estimator.separation(&system) / LYAPUNOV_INITIAL_SEPARATION - 1.).abs() < 1e-6);
+
+        let dt = 1e-3 * time_scale;
+        let mut exponent = None;
+        for _ in 0..(time_scales / 1e-3) as usize {
+            leapfrog_step(&mut system, dt);
+            exponent = estimator.step(&system, dt, false).or(exponent);
+        }
+        exponent.unwrap() * time_scale
+    }
+
+    #[test]
+    fn test_unstable_orbit_has_larger_exponent() {
+        // The equal mass Lagrange triangle is unstable while the figure 8 is linearly stable
+        let lagrange = estimate_exponent("Lagrange", 40.);
+        let figure_8 = estimate_exponent("Figure 8", 40.);
+        assert!(lagrange > 3. * figure_8, "{} {}", lagrange, figure_8);
+    }
+
+    #[test]
+    fn test_shadow_reseeds_after_unmatched_merge() {
+        let mut system: Vec<Particle> = Vec::new();
+        let orbit = find_periodic_orbit("Figure 8").unwrap();
+        initialize_periodic_orbit(
+            &mut system,
+            orbit,
+            &EARTH_ORBITAL_RADIUS,
+            &EARTH_MASS,
+            &EARTH_RADIUS,
+        );
+        let mut estimator = LyapunovEstimator::new(&system, LYAPUNOV_INITIAL_SEPARATION, 1);
+        // Only the system loses a body, as if it merged there and not in the shadow
+        system.pop();
+        assert!(estimator.step(&system, 1., false).is_none());
+        assert!((estimator.separation(&system) / LYAPUNOV_INITIAL_SEPARATION - 1.).abs() < 1e-6);
+        assert!(estimator.step(&system, 1., false).is_some());
+    }
+}
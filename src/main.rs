@@ -1,18 +1,43 @@
 use macroquad::prelude::*;
-use rayon::prelude::*;
-use std::fs::File;
 
+mod analysis;
+mod body_columns;
+mod cluster;
 mod constants;
+mod drift_monitor;
+mod encounters;
 use constants::*;
 
+mod hdf5_output;
 mod helpers;
 pub mod horizon;
+mod horizons_cache;
+mod horizons_error;
+mod horizons_source;
 mod horizons_table;
 mod init_helpers;
+mod lyapunov;
+mod output_schedule;
+mod output_sink;
+mod parquet_output;
+mod periodic_orbits_table;
+mod periodicity;
 mod render;
+mod socket_output;
+mod spk;
+mod state_import;
+mod tidy_output;
+mod validation;
 
+use body_columns::*;
+use drift_monitor::DriftMonitor;
+use encounters::*;
 use helpers::*;
 use init_helpers::*;
+use lyapunov::*;
+use output_schedule::OutputSchedule;
+use output_sink::*;
+use periodicity::*;
 use render::*;
 // TODO: Fix the small moons borking themselves
 fn gravity_conf() -> Conf {
@@ -25,15 +50,69 @@ fn gravity_conf() -> Conf {
 }
 #[macroquad::main(gravity_conf)]
 async fn main() {
+    if std::env::args().nth(1).as_deref() == Some("validate") {
+        validation::run_validation();
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("analyze") {
+        let arguments: Vec<String> = std::env::args().skip(2).collect();
+        analysis::run_analysis(&arguments);
+        return;
+    }
+
     let scenario_key_list: Vec<ScenarioKey> = vec![
         ScenarioKey("Spirograph".to_string(), 0),
         ScenarioKey("Figure 8".to_string(), 1),
         ScenarioKey("Solar System".to_string(), 2),
+        ScenarioKey("Plummer Sphere".to_string(), 3),
+        ScenarioKey("Cold Collapse".to_string(), 4),
+        ScenarioKey("King Model".to_string(), 5),
+        ScenarioKey("Disk Galaxy".to_string(), 6),
+        ScenarioKey("Periodic Three Body".to_string(), 7),
+        ScenarioKey("Restricted Three Body".to_string(), 8),
+        ScenarioKey("Imported File".to_string(), 9),
     ];
 
     let file_write = take_user_choice("Do you want to write to a file? ");
+    let output_format = if file_write {
+        choose_output_format()
+    } else {
+        OutputFormat::WideCsv
+    };
+    let destination = if file_write
+        && (output_format == OutputFormat::WideCsv || output_format == OutputFormat::LongCsv)
+    {
+        choose_destination()
+    } else {
+        Destination::File
+    };
+    let body_columns = if file_write && output_format != OutputFormat::Hdf5 {
+        choose_body_columns(&default_body_columns(output_format).quantities)
+    } else {
+        default_body_columns(output_format)
+    };
+    let interpolate_output =
+        file_write && take_user_choice("Do you want to interpolate samples to exact times? ");
     let trails = take_user_choice("Do you want to have trails? ");
     let collisions = take_user_choice("Do you want to have collisions? ");
+    let lyapunov_choice = take_user_choice("Do you want to estimate the Lyapunov exponent? ");
+    let drift_threshold = get_number_from_user(&format!(
+        "Relative energy and angular momentum error to warn at? Enter 0 for {:.0e}",
+        DRIFT_THRESHOLD
+    )) as f64;
+    let drift_threshold = if drift_threshold > 0. {
+        drift_threshold
+    } else {
+        DRIFT_THRESHOLD
+    };
+    let halve_dt_on_drift = take_user_choice("Do you want dt halved when the error passes it? ");
+    let encounter_criterion = if take_user_choice("Do you want to detect close encounters? ") {
+        Some(choose_encounter_criterion())
+    } else {
+        None
+    };
+    let highlight_encounters = encounter_criterion.is_some()
+        && take_user_choice("Do you want close encounters highlighted? ");
     let mut names_of_scenarios: String = "".to_string();
     for ScenarioKey(a, b) in &scenario_key_list {
         names_of_scenarios.push_str(&format!("\n[{}] {} Scenario", b, a));
@@ -58,10 +137,12 @@ async fn main() {
     };
 
     let mut system: Vec<Particle> = Vec::new();
+    let mut test_particles: Vec<Particle> = Vec::new();
 
-    let init_output = initialize_from_scenario(
+    let mut init_output = initialize_from_scenario(
         scenario,
         &mut system,
+        &mut test_particles,
         &scenario_key_list,
         &mut screen_values,
     );
@@ -70,8 +151,7 @@ async fn main() {
     let sim_seconds_per_data_row: f64 =
         init_output.years_of_writing as f64 * SECONDS_IN_YEAR / ROW_LIMIT as f64;
     let mut dt = init_output.dt;
-    let dt_origin = dt;
-    let data_interval: usize = (sim_seconds_per_data_row / dt) as usize;
+    let mut dt_origin = dt;
 
     // Generates a number of comets with varying masses, positions, and velocities
 
@@ -86,39 +166,116 @@ async fn main() {
     let mut trail_values: Vec<Vec<(DVec2, Color)>> =
         vec![vec![(DVec2::new(0., 0.), WHITE); init_output.trail_length]; important_bodies_added];
 
-    let my_file = if file_write {
-        Some(
-            File::create(
-                format!(
-                    "target/orbital_simulation_{}_accuracy_{}.csv",
-                    init_output.scenario_name, ticks_per_frame
-                )
-                .replace(' ', ""),
-            )
-            .unwrap(),
-        )
+    let default_prefix = format!(
+        "target/orbital_simulation_{}_accuracy_{}",
+        init_output.scenario_name, ticks_per_frame
+    )
+    .replace(' ', "");
+    let output_prefix = if file_write && output_format != OutputFormat::Socket {
+        choose_output_prefix(&default_prefix)
     } else {
-        None
+        default_prefix
     };
     let mut rows_added = 0;
-
-    let mut wtr = my_file.map(|f| csv::Writer::from_writer(f));
+    let mut output_sink: Option<Box<dyn OutputSink>> = None;
     if file_write {
-        if let Some(ref mut w) = wtr {
-            add_topline_data(&system, w);
-            add_physical_data(&system, seconds_passed_in_sim, w, rows_added);
+        match create_sink(
+            output_format,
+            destination,
+            &output_prefix,
+            &system,
+            &body_columns,
+            init_output.epoch,
+        ) {
+            Ok(sink) => {
+                // Some formats are only readable once finished, so closing the window has to go
+                // through the loop
+                prevent_quit();
+                output_sink = Some(sink);
+            }
+            Err(e) => println!("Could not open the output: {}", e),
         }
-        rows_added += 1;
+        add_sample(
+            &mut output_sink,
+            &system,
+            seconds_passed_in_sim,
+            &mut rows_added,
+        );
     }
+    // Made once the sink is open, so a warning about the interval goes to stderr if stdout
+    // carries samples
+    let mut output_schedule = OutputSchedule::new(sim_seconds_per_data_row, interpolate_output);
+    let mut return_map = init_output
+        .return_map_values
+        .take()
+        .map(|values| ReturnMap::new(&system, values, RETURN_MAP_TOLERANCE));
+    let mut return_map_wtr = if file_write && return_map.is_some() {
+        match create_return_map_writer(&output_prefix) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                print_message(&format!("Could not open the return map file: {}", e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut lyapunov = if lyapunov_choice {
+        Some(LyapunovEstimator::new(
+            &system,
+            LYAPUNOV_INITIAL_SEPARATION,
+            LYAPUNOV_RENORMALIZATION_TICKS,
+        ))
+    } else {
+        None
+    };
+    let mut lyapunov_wtr = if file_write && lyapunov_choice {
+        match create_lyapunov_writer(&output_prefix) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                print_message(&format!("Could not open the Lyapunov file: {}", e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut lyapunov_rows_added = 0;
+
+    let mut encounter_detector = encounter_criterion.map(EncounterDetector::new);
+    let mut encounter_wtr = if file_write && encounter_detector.is_some() {
+        match create_encounter_writer(&output_prefix) {
+            Ok(w) => Some(w),
+            Err(e) => {
+                print_message(&format!("Could not open the encounter events file: {}", e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut drift_monitor = DriftMonitor::new(&system, drift_threshold, halve_dt_on_drift);
+    let mut collisions_at_baseline = collision_counter;
+
     draw_bodies(&system, &screen_values);
     let mut time_to_wait = get_number_from_user("How long to wait?");
     while time_to_wait > 0.0 {
+        draw_test_particles(&test_particles, &screen_values);
         draw_bodies(&system, &screen_values);
         next_frame().await;
         time_to_wait -= get_frame_time().min(0.1);
     }
     let mut paused = false;
     loop {
+        if is_quit_requested() {
+            finish_sink(&mut output_sink);
+            if let Some(ref mut detector) = encounter_detector {
+                add_encounters(&mut encounter_wtr, &detector.finish(), &system);
+            }
+            break;
+        }
         clear_background(BLACK);
         if is_key_released(KeyCode::Space) {
             paused = !paused;
@@ -128,47 +285,65 @@ async fn main() {
             trails,
             &mut screen_values,
             &mut system,
+            &test_particles,
             &init_output,
-            important_bodies_added,
             &mut trail_point_counter,
             &mut trail_values,
         );
+        if highlight_encounters && let Some(ref detector) = encounter_detector {
+            draw_encounters(&system, &detector.active_pairs(), &screen_values);
+        }
         if !paused {
             for _i in 0..ticks_per_frame {
                 total_physics_ticks += 1;
-                /* Parallel calculation of all the forces acting on the bodies using the
-                calculate_g_force method */
-                let forces: Vec<DVec2> = (0..system.len())
-                    .into_par_iter()
-                    .map(|i| system[i].calculate_g_force(&system, i))
-                    .collect();
-
-                // Applies forces to the system
-                for i in 0..system.len() {
-                    system[i].kick(forces[i], dt);
-                }
-                for i in 0..system.len() {
-                    system[i].drift(dt);
-                }
-                let forces: Vec<DVec2> = (0..system.len())
-                    .into_par_iter()
-                    .map(|i| system[i].calculate_g_force(&system, i))
-                    .collect();
-                for i in 0..system.len() {
-                    system[i].kick(forces[i], dt);
-                }
+                let state_before_step = if output_sink.is_some()
+                    && output_schedule.is_due(seconds_passed_in_sim, dt)
+                {
+                    Some(system.clone())
+                } else {
+                    None
+                };
+                leapfrog_step_with_test_particles(&mut system, &mut test_particles, dt);
                 if collisions {
-                    collision_counter += collision_engine(&mut system)
+                    collision_counter += collision_engine(&mut system);
+                    collision_counter += test_particle_collisions(&system, &mut test_particles);
                 };
-                if file_write {
-                    if let Some(ref mut w) = wtr {
-                        if rows_added < ROW_LIMIT && total_physics_ticks % data_interval == 0 {
-                            add_physical_data(&system, seconds_passed_in_sim, w, rows_added);
-                            rows_added += 1;
-                        }
-                    }
+                if let Some(ref mut lyapunov) = lyapunov
+                    && let Some(exponent) = lyapunov.step(&system, dt, collisions)
+                    && lyapunov_rows_added < ROW_LIMIT
+                {
+                    add_lyapunov_estimate(&mut lyapunov_wtr, lyapunov.elapsed_time, exponent);
+                    lyapunov_rows_added += 1;
                 }
                 seconds_passed_in_sim += dt;
+                if let Some(before) = state_before_step {
+                    for (time, sample) in output_schedule.samples(
+                        &before,
+                        seconds_passed_in_sim - dt,
+                        &system,
+                        seconds_passed_in_sim,
+                    ) {
+                        add_sample(&mut output_sink, &sample, time, &mut rows_added);
+                    }
+                }
+                if let Some(ref mut detector) = encounter_detector {
+                    let ended = detector.step(&system, seconds_passed_in_sim, dt);
+                    add_encounters(&mut encounter_wtr, &ended, &system);
+                }
+                if let Some(ref mut return_map) = return_map
+                    && let Some(distance) = return_map.check(&system, seconds_passed_in_sim)
+                {
+                    add_return(&mut return_map_wtr, return_map, seconds_passed_in_sim, distance);
+                }
+            }
+        }
+
+        if !paused {
+            if collision_counter != collisions_at_baseline {
+                drift_monitor.rebase(&system);
+                collisions_at_baseline = collision_counter;
+            } else {
+                drift_monitor.check(&system, &mut dt_origin);
             }
         }
 
@@ -177,14 +352,50 @@ async fn main() {
             "Years Passed: {:.5}/{:.2} | Total Physics Ticks: {}",
             &years_passed_in_sim, &init_output.years_of_writing, total_physics_ticks
         );
+        info_on_screen.push_str(&format!(
+            " | Energy Error: {:.2e} | Angular Momentum Error: {:.2e}",
+            drift_monitor.energy_error, drift_monitor.angular_momentum_error
+        ));
+        if drift_monitor.over_threshold {
+            info_on_screen.push_str(" (over threshold)");
+        }
+        if drift_monitor.halvings > 0 {
+            info_on_screen.push_str(&format!(" (dt halved {} times)", drift_monitor.halvings));
+        }
 
         if file_write {
             info_on_screen.push_str(&format!(
                 " | Still Writing: {} (with {} rows)",
-                rows_added < ROW_LIMIT,
+                output_sink.is_some(),
                 rows_added
             ));
         }
+        if let Some(ref return_map) = return_map {
+            if let Some(distance) = return_map.last_distance {
+                info_on_screen.push_str(&format!(" | Return Distance: {:.2e}", distance));
+            }
+            if let Some(period) = return_map.diverged_at_period {
+                info_on_screen.push_str(&format!(" (diverged at period {})", period));
+            }
+        }
+        if let Some(ref lyapunov) = lyapunov
+            && let Some(exponent) = lyapunov.exponent
+        {
+            info_on_screen.push_str(&format!(
+                " | Lyapunov Time: {:.3e} years",
+                1. / exponent / SECONDS_IN_YEAR
+            ));
+        }
+        if !test_particles.is_empty() {
+            info_on_screen.push_str(&format!(" | Test Particles: {}", test_particles.len()));
+        }
+        if let Some(ref detector) = encounter_detector {
+            info_on_screen.push_str(&format!(
+                " | Encounters: {} ({} ongoing)",
+                detector.finished,
+                detector.active_pairs().len()
+            ));
+        }
         if collision_counter > 0 {
             info_on_screen.push_str(&format!(" | Collision Count: {}", collision_counter));
         }
@@ -0,0 +1,173 @@
+use crate::helpers::{Particle, print_message};
+
+/*
+Decides when samples are written, by simulation time rather than by tick count, so the cadence
+holds when dt changes and when the interval is shorter than dt. Sample k is due at k * interval
+seconds. Without interpolation a due sample takes the state at the end of the step that reaches
+it, stamped with that step's time, and any other samples due within the same step are skipped.
+With interpolation every due sample is written at its exact time, using a cubic Hermite
+interpolation between the positions and velocities at either end of the step.
+ */
+pub struct OutputSchedule {
+    interval: Option<f64>, // Seconds of simulation time between samples, None writes no more
+    interpolate: bool,
+    next_sample: u64,
+}
+
+impl OutputSchedule {
+    // The sample at time zero is written before the loop starts, so the schedule starts at the next
+    pub fn new(interval: f64, interpolate: bool) -> OutputSchedule {
+        // Any other interval would never move past a step, so nothing more is written
+        let interval = Some(interval).filter(|interval| interval.is_finite() && *interval > 0.);
+        if interval.is_none() {
+            print_message(
+                "The sample interval isn't a positive number, so no more samples will be written",
+            );
+        }
+        OutputSchedule {
+            interval,
+            interpolate,
+            next_sample: 1,
+        }
+    }
+
+    fn next_time(&self) -> f64 {
+        self.interval
+            .map_or(f64::INFINITY, |interval| self.next_sample as f64 * interval)
+    }
+
+    // Whether the step from time to time + dt reaches a sample, in which case the caller has to
+    // keep the state from before the step for samples()
+    pub fn is_due(&self, time: f64, dt: f64) -> bool {
+        self.next_time() <= time + dt * (1. + 1e-9)
+    }
+
+    // The samples due in the step from (before, before_time) to (after, after_time)
+    pub fn samples(
+        &mut self,
+        before: &[Particle],
+        before_time: f64,
+        after: &[Particle],
+        after_time: f64,
+    ) -> Vec<(f64, Vec<Particle>)> {
+        let step = after_time - before_time;
+        let mut samples = Vec::new();
+        while self.next_time() <= after_time + step * 1e-9 {
+            let time = self.next_time();
+            self.next_sample += 1;
+            if self.interpolate {
+                samples.push((
+                    time,
+                    interpolate_system(before, after, (time - before_time) / step, step),
+                ));
+            } else if samples.is_empty() {
+                samples.push((after_time, after.to_vec()));
+            }
+        }
+        samples
+    }
+}
+
+// Positions and velocities at a fraction s of a step of length dt. Bodies that collided during
+// the step take the state from after it, since their positions can't be interpolated.
+fn interpolate_system(before: &[Particle], after: &[Particle], s: f64, dt: f64) -> Vec<Particle> {
+    let s = s.clamp(0., 1.);
+    let (s2, s3) = (s * s, s * s * s);
+    let h00 = 2. * s3 - 3. * s2 + 1.;
+    let h10 = s3 - 2. * s2 + s;
+    let h01 = -2. * s3 + 3. * s2;
+    let h11 = s3 - s2;
+    // Derivatives of the basis with respect to s
+    let d00 = 6. * s2 - 6. * s;
+    let d10 = 3. * s2 - 4. * s + 1.;
+    let d01 = -6. * s2 + 6. * s;
+    let d11 = 3. * s2 - 2. * s;
+
+    before
+        .iter()
+        .zip(after)
+        .map(|(start, end)| {
+            let mut body = end.clone();
+            if start.mass == end.mass {
+                body.position = h00 * start.position
+                    + h10 * dt * start.velocity
+                    + h01 * end.position
+                    + h11 * dt * end.velocity;
+                body.velocity = (d00 * start.position + d01 * end.position) / dt
+                    + d10 * start.velocity
+                    + d11 * end.velocity;
+            }
+            body
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macroquad::color::WHITE;
+    use macroquad::math::DVec2;
+
+    fn body(position: DVec2, velocity: DVec2) -> Particle {
+        Particle {
+            mass: 1.,
+            radius: 1.,
+            position,
+            velocity,
+            color: WHITE,
+            name: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_interval_shorter_than_dt() {
+        let before = vec![body(DVec2::ZERO, DVec2::new(1., 0.))];
+        let after = vec![body(DVec2::new(10., 0.), DVec2::new(1., 0.))];
+
+        let mut schedule = OutputSchedule::new(2.5, true);
+        assert!(schedule.is_due(0., 10.));
+        let samples = schedule.samples(&before, 0., &after, 10.);
+        let times: Vec<f64> = samples.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, [2.5, 5., 7.5, 10.]);
+        assert!((samples[0].1[0].position.x - 2.5).abs() < 1e-12);
+        assert!((samples[0].1[0].velocity.x - 1.).abs() < 1e-12);
+
+        let mut schedule = OutputSchedule::new(2.5, false);
+        let samples = schedule.samples(&before, 0., &after, 10.);
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].0, 10.);
+        assert!(!schedule.is_due(10., 2.));
+    }
+
+    #[test]
+    fn test_invalid_interval() {
+        let before = vec![body(DVec2::ZERO, DVec2::new(1., 0.))];
+        let after = vec![body(DVec2::new(10., 0.), DVec2::new(1., 0.))];
+        for interval in [0., -2.5, f64::NAN, f64::INFINITY] {
+            let mut schedule = OutputSchedule::new(interval, true);
+            assert!(!schedule.is_due(0., 10.));
+            assert!(schedule.samples(&before, 0., &after, 10.).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_variable_dt() {
+        // Uniform acceleration, which the cubic reproduces exactly
+        let state = |t: f64| vec![body(DVec2::new(t * t, 0.), DVec2::new(2. * t, 0.))];
+        let mut schedule = OutputSchedule::new(3., true);
+        let mut written = Vec::new();
+        let mut time = 0.;
+        for dt in [1., 1., 0.5, 0.5, 2., 2., 2.] {
+            if schedule.is_due(time, dt) {
+                written.extend(schedule.samples(&state(time), time, &state(time + dt), time + dt));
+            }
+            time += dt;
+        }
+        let times: Vec<f64> = written.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, [3., 6., 9.]);
+        for (time, system) in written {
+            assert!((system[0].position.x - time * time).abs() < 1e-9);
+            assert!((system[0].velocity.x - 2. * time).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use crate::body_columns::{BodyColumns, BodyQuantity};
+use crate::constants::ROW_LIMIT;
+use crate::hdf5_output::Hdf5Snapshots;
+use crate::helpers::{
+    Particle, add_physical_data, add_topline_data, get_int_from_user, get_text_from_user,
+    print_message, reserve_stdout_for_output,
+};
+use crate::parquet_output::ParquetOutput;
+use crate::socket_output::SocketSink;
+use crate::tidy_output::TidyWriter;
+use chrono::NaiveDateTime;
+use csv::Writer;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{self, BufWriter, Stdout, Write};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+/*
+Where the physics loop sends its samples. Each format turns a sample into rows, records or
+files, and every error is passed back so the loop can stop writing instead of panicking.
+ */
+pub trait OutputSink {
+    fn add_data(&mut self, system: &[Particle], time: f64) -> io::Result<()>;
+
+    // Flushes everything and writes any footers or compression trailers
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    WideCsv, // One row per sample, the layout the notebook reads
+    LongCsv, // One row per body per sample, plus a diagnostics file
+    Parquet, // The long format as compressed Parquet
+    Hdf5,    // A series of GADGET style HDF5 snapshots
+    Socket,  // Streamed to another process over a local socket
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    File,
+    GzipFile, // Appends .gz to the file name
+    Stdout,   // Only the main stream, the long format's diagnostics still go to a file
+}
+
+// A cloneable in-memory stream, so tests can read back what a sink wrote
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBuffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl MemoryBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Write for MemoryBuffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub enum OutputStream {
+    File(BufWriter<File>),
+    GzipFile(GzEncoder<BufWriter<File>>),
+    Stdout(Stdout),
+    #[cfg(test)]
+    Memory(MemoryBuffer),
+}
+
+impl OutputStream {
+    pub fn open(path: &str, destination: Destination) -> io::Result<OutputStream> {
+        Ok(match destination {
+            Destination::File => OutputStream::File(BufWriter::new(File::create(path)?)),
+            Destination::GzipFile => OutputStream::GzipFile(GzEncoder::new(
+                BufWriter::new(File::create(format!("{}.gz", path))?),
+                Compression::default(),
+            )),
+            // Prompts and messages move to standard error so they stay out of the data
+            Destination::Stdout => {
+                reserve_stdout_for_output();
+                OutputStream::Stdout(io::stdout())
+            }
+        })
+    }
+
+    pub fn close(self) -> io::Result<()> {
+        match self {
+            OutputStream::File(mut file) => file.flush(),
+            OutputStream::GzipFile(encoder) => encoder.finish()?.flush(),
+            OutputStream::Stdout(mut stdout) => stdout.flush(),
+            #[cfg(test)]
+            OutputStream::Memory(_) => Ok(()),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            OutputStream::File(file) => file,
+            OutputStream::GzipFile(encoder) => encoder,
+            OutputStream::Stdout(stdout) => stdout,
+            #[cfg(test)]
+            OutputStream::Memory(buffer) => buffer,
+        }
+    }
+}
+
+impl Write for OutputStream {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.inner().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+// The original layout: three header rows, then one row per sample with every body's columns
+pub struct WideCsvSink {
+    wtr: Writer<OutputStream>,
+    columns: BodyColumns,
+    rows: usize,
+}
+
+impl WideCsvSink {
+    pub fn new(
+        output: OutputStream,
+        system: &[Particle],
+        columns: &BodyColumns,
+        epoch: Option<NaiveDateTime>,
+    ) -> io::Result<WideCsvSink> {
+        let mut wtr = Writer::from_writer(output);
+        add_topline_data(system, columns, epoch, &mut wtr)?;
+        Ok(WideCsvSink {
+            wtr,
+            columns: columns.clone(),
+            rows: 0,
+        })
+    }
+}
+
+impl OutputSink for WideCsvSink {
+    fn add_data(&mut self, system: &[Particle], time: f64) -> io::Result<()> {
+        add_physical_data(system, &self.columns, time, &mut self.wtr, self.rows)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.wtr
+            .into_inner()
+            .map_err(|error| error.into_error())?
+            .close()
+    }
+}
+
+/*
+Opens the chosen format at the path prefix. CSVs get ".csv" or "_bodies.csv" and
+"_diagnostics.csv" appended, Parquet the same with ".parquet", and HDF5 snapshots go in a
+"_snapshots" directory. The destination only applies to the CSV formats, and the body columns
+to everything but HDF5, which always has positions and velocities. A socket ignores the prefix,
+asks for its address and waits for its consumer.
+ */
+pub fn create_sink(
+    format: OutputFormat,
+    destination: Destination,
+    prefix: &str,
+    system: &[Particle],
+    columns: &BodyColumns,
+    epoch: Option<NaiveDateTime>,
+) -> io::Result<Box<dyn OutputSink>> {
+    Ok(match format {
+        OutputFormat::WideCsv => Box::new(WideCsvSink::new(
+            OutputStream::open(&format!("{}.csv", prefix), destination)?,
+            system,
+            columns,
+            epoch,
+        )?),
+        OutputFormat::LongCsv => {
+            let diagnostics_destination = match destination {
+                Destination::Stdout => Destination::File,
+                other => other,
+            };
+            Box::new(TidyWriter::new(
+                OutputStream::open(&format!("{}_bodies.csv", prefix), destination)?,
+                OutputStream::open(
+                    &format!("{}_diagnostics.csv", prefix),
+                    diagnostics_destination,
+                )?,
+                columns,
+                epoch,
+            )?)
+        }
+        OutputFormat::Parquet => Box::new(ParquetOutput::new(
+            OutputStream::open(&format!("{}_bodies.parquet", prefix), Destination::File)?,
+            OutputStream::open(
+                &format!("{}_diagnostics.parquet", prefix),
+                Destination::File,
+            )?,
+            columns,
+            epoch,
+        )?),
+        OutputFormat::Hdf5 => Box::new(Hdf5Snapshots::create(prefix, epoch)?),
+        OutputFormat::Socket => Box::new(SocketSink::from_user(columns)?),
+    })
+}
+
+// Adds a sample, then closes the sink once it has ROW_LIMIT samples or as soon as it fails
+pub fn add_sample(
+    sink: &mut Option<Box<dyn OutputSink>>,
+    system: &[Particle],
+    time: f64,
+    rows_added: &mut usize,
+) {
+    let Some(output) = sink else {
+        return;
+    };
+    if let Err(e) = output.add_data(system, time) {
+        print_message(&format!("Stopped writing output: {}", e));
+        finish_sink(sink);
+        return;
+    }
+    *rows_added += 1;
+    if *rows_added >= ROW_LIMIT {
+        finish_sink(sink);
+    }
+}
+
+pub fn finish_sink(sink: &mut Option<Box<dyn OutputSink>>) {
+    if let Some(output) = sink.take()
+        && let Err(e) = output.finish()
+    {
+        print_message(&format!("Could not finish writing output: {}", e));
+    }
+}
+
+pub fn choose_output_format() -> OutputFormat {
+    let formats = [
+        ("Wide CSV, one row per sample", OutputFormat::WideCsv),
+        (
+            "Long CSV, one row per body per sample",
+            OutputFormat::LongCsv,
+        ),
+        ("Parquet, the long format compressed", OutputFormat::Parquet),
+        ("HDF5 snapshots, GADGET layout", OutputFormat::Hdf5),
+        ("Stream to a local socket", OutputFormat::Socket),
+    ];
+    let mut question = "What output format? ".to_string();
+    for (i, (description, _)) in formats.iter().enumerate() {
+        question.push_str(&format!("\n[{}] {}", i, description));
+    }
+    loop {
+        match formats.get(get_int_from_user(&question) as usize) {
+            Some((_, format)) => return *format,
+            None => println!("Invalid output format"),
+        }
+    }
+}
+
+// What each format wrote before the columns could be chosen
+pub fn default_body_columns(format: OutputFormat) -> BodyColumns {
+    match format {
+        OutputFormat::WideCsv => BodyColumns::new(&[BodyQuantity::Position]),
+        _ => BodyColumns::new(&[BodyQuantity::Position, BodyQuantity::Velocity]),
+    }
+}
+
+pub fn choose_destination() -> Destination {
+    let destinations = [
+        ("File", Destination::File),
+        ("Gzip compressed file", Destination::GzipFile),
+        ("Standard output", Destination::Stdout),
+    ];
+    let mut question = "Where should the CSV go? ".to_string();
+    for (i, (description, _)) in destinations.iter().enumerate() {
+        question.push_str(&format!("\n[{}] {}", i, description));
+    }
+    loop {
+        match destinations.get(get_int_from_user(&question) as usize) {
+            Some((_, destination)) => return *destination,
+            None => println!("Invalid destination"),
+        }
+    }
+}
+
+pub fn choose_output_prefix(default_prefix: &str) -> String {
+    let prefix = get_text_from_user(&format!(
+        "Output path, without an extension? Leave empty for {}",
+        default_prefix
+    ));
+    if prefix.is_empty() {
+        default_prefix.to_string()
+    } else {
+        prefix
+    }
+}
+
+// Fixtures shared by the tests of every output format
+#[cfg(test)]
+pub(crate) mod test_support {
+    use crate::constants::{AU, EARTH_MASS, STAR_MASS};
+    use crate::helpers::Particle;
+    use macroquad::color::WHITE;
+    use macroquad::math::DVec2;
+
+    // The sun and the earth one AU apart, both moving at 1 m/s, for the sink tests to write out
+    pub fn test_system() -> Vec<Particle> {
+        let body = |name: &str, mass: f64, x: f64| Particle {
+            mass,
+            radius: 1.,
+            position: DVec2::new(x, 0.),
+            velocity: DVec2::new(0., 1.),
+            color: WHITE,
+            name: name.to_string(),
+        };
+        vec![body("sun", STAR_MASS, 0.), body("earth", EARTH_MASS, AU)]
+    }
+
+    // An output prefix in the temporary directory that no other test or run shares
+    pub fn test_prefix(label: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("nbodyproblem_{}_{}", label, std::process::id()))
+            .display()
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::test_prefix;
+    use super::*;
+    use crate::constants::*;
+    use macroquad::color::WHITE;
+    use macroquad::math::DVec2;
+    use std::io::Read;
+
+    #[test]
+    fn test_wide_csv_sink() {
+        let system = vec![Particle {
+            mass: EARTH_MASS,
+            radius: 1.,
+            position: DVec2::new(AU, 2.),
+            velocity: DVec2::ZERO,
+            color: WHITE,
+            name: "earth".to_string(),
+        }];
+        let buffer = MemoryBuffer::default();
+        let mut sink: Box<dyn OutputSink> = Box::new(
+            WideCsvSink::new(
+                OutputStream::Memory(buffer.clone()),
+                &system,
+                &default_body_columns(OutputFormat::WideCsv),
+                None,
+            )
+            .unwrap(),
+        );
+        sink.add_data(&system, 5.).unwrap();
+        sink.finish().unwrap();
+
+        let text = String::from_utf8(buffer.contents()).unwrap();
+        let rows: Vec<&str> = text.lines().collect();
+        assert_eq!(rows.len(), 4);
+        assert!(rows[0].ends_with("earth,earth"));
+        assert!(rows[3].starts_with("5,"));
+        assert!(rows[3].ends_with(&format!("{},2", AU)));
+    }
+
+    #[test]
+    fn test_gzip_destination() {
+        let path = test_prefix("gzip");
+        let mut output = OutputStream::open(&path, Destination::GzipFile).unwrap();
+        output.write_all(b"time_s\n1\n").unwrap();
+        output.close().unwrap();
+
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(File::open(format!("{}.gz", path)).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "time_s\n1\n");
+        std::fs::remove_file(format!("{}.gz", path)).unwrap();
+    }
+}
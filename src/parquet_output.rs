@@ -0,0 +1,296 @@
+use crate::body_columns::BodyColumns;
+use crate::constants::{PARQUET_BATCH_ROWS, PARQUET_ROW_GROUP_ROWS, SECONDS_IN_DAY};
+use crate::helpers::{Particle, find_system_totals};
+use crate::horizon::julian_date;
+use crate::output_sink::{OutputSink, OutputStream};
+use crate::tidy_output::{BODY_ID_COLUMNS, DIAGNOSTIC_COLUMNS};
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt32Array};
+use arrow_schema::{DataType, Field, Schema};
+use chrono::NaiveDateTime;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use std::io;
+use std::sync::Arc;
+
+/*
+The long format of tidy_output as typed, zstd compressed Parquet, one stream for the bodies and
+one for the diagnostics with the same columns as the CSVs, including the chosen body columns.
+Rows are buffered and written in batches, and a file is only readable once finish has written
+its footer.
+ */
+pub struct ParquetOutput {
+    bodies: ArrowWriter<OutputStream>,
+    diagnostics: ArrowWriter<OutputStream>,
+    body_rows: BodyRows,
+    diagnostic_rows: DiagnosticRows,
+    columns: BodyColumns,
+    epoch_julian_date: Option<f64>,
+}
+
+struct BodyRows {
+    time: Vec<f64>,
+    julian_date: Vec<Option<f64>>,
+    body_id: Vec<u32>,
+    name: Vec<String>,
+    mass: Vec<f64>,
+    radius: Vec<f64>,
+    values: Vec<Vec<f64>>, // One vector per body column
+}
+
+impl BodyRows {
+    fn new(columns: &BodyColumns) -> BodyRows {
+        BodyRows {
+            time: Vec::new(),
+            julian_date: Vec::new(),
+            body_id: Vec::new(),
+            name: Vec::new(),
+            mass: Vec::new(),
+            radius: Vec::new(),
+            values: vec![Vec::new(); columns.width()],
+        }
+    }
+}
+
+#[derive(Default)]
+struct DiagnosticRows {
+    time: Vec<f64>,
+    julian_date: Vec<Option<f64>>,
+    kinetic_energy: Vec<f64>,
+    potential_energy: Vec<f64>,
+    total_energy: Vec<f64>,
+    momentum_x: Vec<f64>,
+    momentum_y: Vec<f64>,
+    massive_bodies: Vec<u32>,
+}
+
+fn body_schema(columns: &BodyColumns) -> Arc<Schema> {
+    let mut names = BODY_ID_COLUMNS.to_vec();
+    names.extend(columns.long_names());
+    let mut types = vec![
+        DataType::Float64,
+        DataType::Float64,
+        DataType::UInt32,
+        DataType::Utf8,
+        DataType::Float64,
+        DataType::Float64,
+    ];
+    types.resize(names.len(), DataType::Float64);
+    schema(&names, &types)
+}
+
+fn diagnostic_schema() -> Arc<Schema> {
+    let types = [
+        DataType::Float64,
+        DataType::Float64,
+        DataType::Float64,
+        DataType::Float64,
+        DataType::Float64,
+        DataType::Float64,
+        DataType::Float64,
+        DataType::UInt32,
+    ];
+    schema(&DIAGNOSTIC_COLUMNS, &types)
+}
+
+// Only julian_date can be missing, when the scenario has no epoch
+fn schema(names: &[&str], types: &[DataType]) -> Arc<Schema> {
+    let fields: Vec<Field> = names
+        .iter()
+        .zip(types)
+        .map(|(name, data_type)| Field::new(*name, data_type.clone(), *name == "julian_date"))
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+fn parquet_writer(
+    output: OutputStream,
+    schema: Arc<Schema>,
+) -> io::Result<ArrowWriter<OutputStream>> {
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .set_max_row_group_row_count(Some(PARQUET_ROW_GROUP_ROWS))
+        .build();
+    ArrowWriter::try_new(output, schema, Some(properties)).map_err(parquet_to_io)
+}
+
+fn parquet_to_io(error: ParquetError) -> io::Error {
+    io::Error::other(error)
+}
+
+impl ParquetOutput {
+    pub fn new(
+        bodies: OutputStream,
+        diagnostics: OutputStream,
+        columns: &BodyColumns,
+        epoch: Option<NaiveDateTime>,
+    ) -> io::Result<ParquetOutput> {
+        Ok(ParquetOutput {
+            bodies: parquet_writer(bodies, body_schema(columns))?,
+            diagnostics: parquet_writer(diagnostics, diagnostic_schema())?,
+            body_rows: BodyRows::new(columns),
+            diagnostic_rows: DiagnosticRows::default(),
+            columns: columns.clone(),
+            epoch_julian_date: epoch.map(|epoch| julian_date(&epoch)),
+        })
+    }
+
+    fn write_batches(&mut self) -> io::Result<()> {
+        let rows = std::mem::replace(&mut self.body_rows, BodyRows::new(&self.columns));
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(Float64Array::from(rows.time)),
+            Arc::new(Float64Array::from(rows.julian_date)),
+            Arc::new(UInt32Array::from(rows.body_id)),
+            Arc::new(StringArray::from(rows.name)),
+            Arc::new(Float64Array::from(rows.mass)),
+            Arc::new(Float64Array::from(rows.radius)),
+        ];
+        for values in rows.values {
+            columns.push(Arc::new(Float64Array::from(values)));
+        }
+        let batch =
+            RecordBatch::try_new(body_schema(&self.columns), columns).map_err(io::Error::other)?;
+        self.bodies.write(&batch).map_err(parquet_to_io)?;
+
+        let rows = std::mem::take(&mut self.diagnostic_rows);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Float64Array::from(rows.time)),
+            Arc::new(Float64Array::from(rows.julian_date)),
+            Arc::new(Float64Array::from(rows.kinetic_energy)),
+            Arc::new(Float64Array::from(rows.potential_energy)),
+            Arc::new(Float64Array::from(rows.total_energy)),
+            Arc::new(Float64Array::from(rows.momentum_x)),
+            Arc::new(Float64Array::from(rows.momentum_y)),
+            Arc::new(UInt32Array::from(rows.massive_bodies)),
+        ];
+        let batch = RecordBatch::try_new(diagnostic_schema(), columns).map_err(io::Error::other)?;
+        self.diagnostics.write(&batch).map_err(parquet_to_io)?;
+        Ok(())
+    }
+}
+
+impl OutputSink for ParquetOutput {
+    fn add_data(&mut self, system: &[Particle], time: f64) -> io::Result<()> {
+        let sample_julian_date = self
+            .epoch_julian_date
+            .map(|epoch| epoch + time / SECONDS_IN_DAY);
+
+        let rows = &mut self.body_rows;
+        for (body_id, (body, values)) in system.iter().zip(self.columns.values(system)).enumerate()
+        {
+            rows.time.push(time);
+            rows.julian_date.push(sample_julian_date);
+            rows.body_id.push(body_id as u32);
+            rows.name.push(body.name.clone());
+            rows.mass.push(body.mass);
+            rows.radius.push(body.radius);
+            for (column, value) in rows.values.iter_mut().zip(values) {
+                column.push(value);
+            }
+        }
+
+        let totals = find_system_totals(system);
+        let rows = &mut self.diagnostic_rows;
+        rows.time.push(time);
+        rows.julian_date.push(sample_julian_date);
+        rows.kinetic_energy.push(totals.kinetic_energy);
+        rows.potential_energy.push(totals.potential_energy);
+        rows.total_energy.push(totals.total_energy());
+        rows.momentum_x.push(totals.momentum.x);
+        rows.momentum_y.push(totals.momentum.y);
+        rows.massive_bodies
+            .push(system.iter().filter(|body| body.mass > 0.).count() as u32);
+
+        if self.body_rows.time.len() >= PARQUET_BATCH_ROWS {
+            self.write_batches()?;
+        }
+        Ok(())
+    }
+
+    // Writes the buffered rows and the footers
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.write_batches()?;
+        let ParquetOutput {
+            bodies,
+            diagnostics,
+            ..
+        } = *self;
+        for writer in [bodies, diagnostics] {
+            writer.into_inner().map_err(parquet_to_io)?.close()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body_columns::BodyQuantity;
+    use crate::constants::*;
+    use crate::output_sink::Destination;
+    use crate::output_sink::test_support::{test_prefix, test_system};
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::fs::{self, File};
+
+    #[test]
+    fn test_parquet_output() {
+        let system = test_system();
+        let prefix = test_prefix("parquet");
+        let mut output: Box<dyn OutputSink> = Box::new(
+            ParquetOutput::new(
+                OutputStream::open(&format!("{}_bodies.parquet", prefix), Destination::File)
+                    .unwrap(),
+                OutputStream::open(
+                    &format!("{}_diagnostics.parquet", prefix),
+                    Destination::File,
+                )
+                .unwrap(),
+                &BodyColumns::new(&[BodyQuantity::Position, BodyQuantity::Velocity]),
+                None,
+            )
+            .unwrap(),
+        );
+        // Enough samples to write more than one batch
+        let samples = PARQUET_BATCH_ROWS / system.len() + 10;
+        for i in 0..samples {
+            output.add_data(&system, i as f64).unwrap();
+        }
+        output.finish().unwrap();
+
+        let file = File::open(format!("{}_bodies.parquet", prefix)).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(|batch| batch.unwrap()).collect();
+        let rows: usize = batches.iter().map(|batch| batch.num_rows()).sum();
+        assert_eq!(rows, samples * system.len());
+        let first = &batches[0];
+        assert_eq!(first.schema().field(3).name(), "name");
+        let names = first
+            .column(3)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(names.value(1), "earth");
+        let x = first
+            .column(6)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(x.value(1), AU);
+        assert!(first.column(1).is_null(0));
+
+        let file = File::open(format!("{}_diagnostics.parquet", prefix)).unwrap();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+        assert_eq!(
+            builder.metadata().file_metadata().num_rows(),
+            samples as i64
+        );
+
+        fs::remove_file(format!("{}_bodies.parquet", prefix)).unwrap();
+        fs::remove_file(format!("{}_diagnostics.parquet", prefix)).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use macroquad::math::DVec2;
+
+// Equal mass periodic three body orbits in units where G = m = 1.
+// Positions are multiplied by the length scale and velocities by sqrt(G m / length scale),
+// so periods are in units of sqrt(length scale^3 / (G m)).
+pub struct PeriodicOrbit {
+    pub name: &'static str,
+    pub positions: [DVec2; 3],
+    pub velocities: [DVec2; 3],
+    pub period: f64,
+    pub rotation: f64, // Radians the whole orbit turns through each period, zero if it closes
+}
+
+// Šuvakov & Dmitrašinović (2013) orbits all start from the same isosceles collinear setup
+const fn suvakov_orbit(name: &'static str, p1: f64, p2: f64, period: f64) -> PeriodicOrbit {
+    PeriodicOrbit {
+        name,
+        positions: [DVec2::new(-1., 0.), DVec2::new(1., 0.), DVec2::new(0., 0.)],
+        velocities: [
+            DVec2::new(p1, p2),
+            DVec2::new(p1, p2),
+            DVec2::new(-2. * p1, -2. * p2),
+        ],
+        period,
+        rotation: 0.,
+    }
+}
+
+// Broucke (1975) orbits start collinear on the x axis with purely vertical velocities
+const fn broucke_orbit(
+    name: &'static str,
+    x: [f64; 3],
+    vy: [f64; 3],
+    period: f64,
+) -> PeriodicOrbit {
+    PeriodicOrbit {
+        name,
+        positions: [
+            DVec2::new(x[0], 0.),
+            DVec2::new(x[1], 0.),
+            DVec2::new(x[2], 0.),
+        ],
+        velocities: [
+            DVec2::new(0., vy[0]),
+            DVec2::new(0., vy[1]),
+            DVec2::new(0., vy[2]),
+        ],
+        period,
+        rotation: 0.,
+    }
+}
+
+pub static PERIODIC_ORBITS: [PeriodicOrbit; 21] = [
+    // Chenciner & Montgomery (2000)
+    PeriodicOrbit {
+        name: "Figure 8",
+        positions: [
+            DVec2::new(-0.97000436, 0.24308753),
+            DVec2::new(0.97000436, -0.24308753),
+            DVec2::new(0.0, 0.0),
+        ],
+        velocities: [
+            DVec2::new(-0.46620368, -0.43236573),
+            DVec2::new(-0.46620368, -0.43236573),
+            DVec2::new(0.93240737, 0.86473146),
+        ],
+        period: 6.32591398,
+        rotation: 0.,
+    },
+    // Lagrange equilateral triangle with unit sides
+    PeriodicOrbit {
+        name: "Lagrange",
+        positions: [
+            DVec2::new(0.5773502692, 0.0),
+            DVec2::new(-0.2886751346, 0.5),
+            DVec2::new(-0.2886751346, -0.5),
+        ],
+        velocities: [
+            DVec2::new(0.0, 1.0),
+            DVec2::new(-0.8660254038, -0.5),
+            DVec2::new(0.8660254038, -0.5),
+        ],
+        period: 3.6275987285,
+        rotation: 0.,
+    },
+    broucke_orbit(
+        "Broucke A1",
+        [-0.9892620043, 2.2096177241, -1.2203557197],
+        [1.9169244185, 0.1910268738, -2.1079512924],
+        6.283213,
+    ),
+    broucke_orbit(
+        "Broucke A2",
+        [0.3361300950, 0.7699893804, -1.1061194753],
+        [1.5324315370, -0.6287350978, -0.9036964391],
+        7.702408,
+    ),
+    broucke_orbit(
+        "Broucke R1",
+        [0.8083106230, -0.4954148566, -0.3128957664],
+        [0.9901979166, -2.7171431768, 1.7269452602],
+        5.226525,
+    ),
+    // Hénon (1976), a relative periodic orbit which only repeats in a slowly turning frame. The
+    // close binary makes it unstable, so it needs a small dt to come back within the tolerance.
+    PeriodicOrbit {
+        name: "Hénon",
+        positions: [
+            DVec2::new(-1.0207041786, 0.0),
+            DVec2::new(2.0532718983, 0.0),
+            DVec2::new(-1.0325677197, 0.0),
+        ],
+        velocities: [
+            DVec2::new(0.0, 9.1265693140),
+            DVec2::new(0.0, 0.0660238807),
+            DVec2::new(0.0, -9.1925931947),
+        ],
+        period: 4.700807,
+        rotation: 0.036293,
+    },
+    suvakov_orbit("Butterfly I", 0.306893, 0.125507, 6.235641),
+    suvakov_orbit("Butterfly II", 0.392955, 0.097579, 7.003707),
+    suvakov_orbit("Butterfly III", 0.405916, 0.230163, 13.865763),
+    suvakov_orbit("Butterfly IV", 0.350112, 0.079339, 79.475875),
+    suvakov_orbit("Bumblebee", 0.184279, 0.587188, 63.534541),
+    suvakov_orbit("Moth I", 0.464445, 0.396060, 14.893911),
+    suvakov_orbit("Moth II", 0.439166, 0.452968, 28.670278),
+    suvakov_orbit("Moth III", 0.383444, 0.377364, 25.840480),
+    suvakov_orbit("Goggles", 0.083300, 0.127889, 10.466822),
+    suvakov_orbit("Dragonfly", 0.080584, 0.588836, 21.270975),
+    suvakov_orbit("Yarn", 0.559064, 0.349192, 55.501762),
+    suvakov_orbit("Yin-Yang Ia", 0.513938, 0.304736, 17.328370),
+    suvakov_orbit("Yin-Yang Ib", 0.282699, 0.327209, 10.962563),
+    suvakov_orbit("Yin-Yang IIa", 0.416822, 0.330333, 55.789829),
+    suvakov_orbit("Yin-Yang IIb", 0.417343, 0.313100, 54.207660),
+];
@@ -0,0 +1,244 @@
+use crate::helpers::{Particle, print_message};
+use csv::Writer;
+use macroquad::math::DVec2;
+use std::fs::File;
+use std::io::{self, Write};
+
+// Scales of a periodic orbit, used to turn differences in state into a unitless distance
+pub struct ReturnMapValues {
+    pub period: f64,         // In seconds
+    pub length_scale: f64,   // In meters
+    pub velocity_scale: f64, // In meters/second
+    pub rotation: f64,       // Radians the orbit turns through each period
+}
+
+// Compares the system with its initial state once every period of a periodic orbit
+pub struct ReturnMap {
+    pub values: ReturnMapValues,
+    pub tolerance: f64,
+    initial_state: Vec<(DVec2, DVec2)>,
+    pub periods_checked: usize,
+    pub last_distance: Option<f64>,
+    pub diverged_at_period: Option<usize>,
+}
+
+impl ReturnMap {
+    pub fn new(system: &[Particle], values: ReturnMapValues, tolerance: f64) -> ReturnMap {
+        ReturnMap {
+            values,
+            tolerance,
+            initial_state: system.iter().map(|b| (b.position, b.velocity)).collect(),
+            periods_checked: 0,
+            last_distance: None,
+            diverged_at_period: None,
+        }
+    }
+
+    // Unitless distance in phase space between the system and its initial state, turned through
+    // the rotation of the periods checked so far
+    pub fn phase_space_distance(&self, system: &[Particle]) -> f64 {
+        let turn = DVec2::from_angle(self.values.rotation * self.periods_checked as f64);
+        system
+            .iter()
+            .zip(self.initial_state.iter())
+            .map(|(body, (position, velocity))| {
+                ((body.position - turn.rotate(*position)) / self.values.length_scale)
+                    .length_squared()
+                    + ((body.velocity - turn.rotate(*velocity)) / self.values.velocity_scale)
+                        .length_squared()
+            })
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    // Returns the distance if the given time has just passed a whole number of periods
+    pub fn check(&mut self, system: &[Particle], time: f64) -> Option<f64> {
+        let next_return = (self.periods_checked + 1) as f64 * self.values.period;
+        if time < next_return {
+            return None;
+        }
+        self.periods_checked += 1;
+        let distance = self.phase_space_distance(system);
+        self.last_distance = Some(distance);
+
+        if distance > self.tolerance && self.diverged_at_period.is_none() {
+            self.diverged_at_period = Some(self.periods_checked);
+            print_message(&format!(
+                "Orbit diverged after {} periods: phase space distance {:.3e} is above the tolerance {:.1e}",
+                self.periods_checked, distance, self.tolerance
+            ));
+        }
+        Some(distance)
+    }
+}
+
+pub fn create_return_map_writer(prefix: &str) -> io::Result<Writer<File>> {
+    let mut wtr = Writer::from_writer(File::create(format!("{}_return_map.csv", prefix))?);
+    add_return_map_topline(&mut wtr)?;
+    Ok(wtr)
+}
+
+// Writes one return, and stops writing returns as soon as that fails
+pub fn add_return<W: Write>(
+    wtr: &mut Option<Writer<W>>,
+    return_map: &ReturnMap,
+    time: f64,
+    distance: f64,
+) {
+    let Some(w) = wtr else {
+        return;
+    };
+    if let Err(e) = add_return_map_data(return_map, time, distance, w) {
+        print_message(&format!("Stopped writing the return map: {}", e));
+        *wtr = None;
+    }
+}
+
+pub fn add_return_map_topline<W: Write>(wtr: &mut Writer<W>) -> io::Result<()> {
+    wtr.write_record(["Period", "Time", "Phase Space Distance"])?;
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn add_return_map_data<W: Write>(
+    return_map: &ReturnMap,
+    time: f64,
+    distance: f64,
+    wtr: &mut Writer<W>,
+) -> io::Result<()> {
+    wtr.write_record([
+        return_map.periods_checked.to_string(),
+        time.to_string(),
+        distance.to_string(),
+    ])?;
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::*;
+    use crate::helpers::leapfrog_step;
+    use crate::init_helpers::*;
+    use crate::periodic_orbits_table::PERIODIC_ORBITS;
+
+    fn check_one_period(orbit_name: &str) -> f64 {
+        let mut system: Vec<Particle> = Vec::new();
+        let orbit = find_periodic_orbit(orbit_name).unwrap();
+        initialize_periodic_orbit(
+            &mut system,
+            orbit,
+            &EARTH_ORBITAL_RADIUS,
+            &EARTH_MASS,
+            &EARTH_RADIUS,
+        );
+        let time_scale = periodic_orbit_time_scale(EARTH_ORBITAL_RADIUS, EARTH_MASS);
+        let mut return_map = ReturnMap::new(
+            &system,
+            periodic_orbit_return_map_values(orbit, EARTH_ORBITAL_RADIUS, EARTH_MASS),
+            RETURN_MAP_TOLERANCE,
+        );
+
+        let dt = 2e-5 * time_scale;
+        let mut time = 0.;
+        loop {
+            leapfrog_step(&mut system, dt);
+            time += dt;
+            if let Some(distance) = return_map.check(&system, time) {
+                return distance;
+            }
+        }
+    }
+
+    #[test]
+    fn test_figure_8_returns() {
+        let distance = check_one_period("Figure 8");
+        assert!(distance < RETURN_MAP_TOLERANCE, "{}", distance);
+    }
+
+    #[test]
+    fn test_lagrange_returns() {
+        let distance = check_one_period("Lagrange");
+        assert!(distance < RETURN_MAP_TOLERANCE, "{}", distance);
+    }
+
+    #[test]
+    fn test_find_periodic_orbit() {
+        assert_eq!(find_periodic_orbit(" figure 8 ").unwrap().name, "Figure 8");
+        assert_eq!(find_periodic_orbit("BROUCKE R1").unwrap().period, 5.226525);
+        assert!(find_periodic_orbit("Figure 9").is_none());
+        // Every entry can be found by its own name, and starts at rest about its center of mass
+        for orbit in PERIODIC_ORBITS.iter() {
+            assert!(std::ptr::eq(
+                find_periodic_orbit(orbit.name).unwrap(),
+                orbit
+            ));
+            let center: DVec2 = orbit.positions.iter().sum();
+            let momentum: DVec2 = orbit.velocities.iter().sum();
+            assert!(center.length() < 1e-8, "{}", orbit.name);
+            assert!(momentum.length() < 1e-8, "{}", orbit.name);
+        }
+    }
+
+    #[test]
+    fn test_catalogue_orbits_return() {
+        for name in ["Broucke A1", "Broucke R1"] {
+            let distance = check_one_period(name);
+            assert!(distance < RETURN_MAP_TOLERANCE, "{}: {}", name, distance);
+        }
+    }
+
+    #[test]
+    fn test_rotated_return() {
+        // An orbit that comes back turned a quarter of the way around counts as returned
+        let mut system: Vec<Particle> = Vec::new();
+        let orbit = find_periodic_orbit("Hénon").unwrap();
+        initialize_periodic_orbit(
+            &mut system,
+            orbit,
+            &EARTH_ORBITAL_RADIUS,
+            &EARTH_MASS,
+            &EARTH_RADIUS,
+        );
+        let mut values = periodic_orbit_return_map_values(orbit, EARTH_ORBITAL_RADIUS, EARTH_MASS);
+        values.rotation = std::f64::consts::FRAC_PI_2;
+        let period = values.period;
+        let mut return_map = ReturnMap::new(&system, values, RETURN_MAP_TOLERANCE);
+        for body in system.iter_mut() {
+            body.position = body.position.perp();
+            body.velocity = body.velocity.perp();
+        }
+        assert!(return_map.check(&system, period).unwrap() < 1e-12);
+        assert!(return_map.check(&system, 2. * period).unwrap() > 1.);
+    }
+
+    #[test]
+    fn test_return_map_rows() {
+        let orbit = find_periodic_orbit("Figure 8").unwrap();
+        let mut system: Vec<Particle> = Vec::new();
+        initialize_periodic_orbit(
+            &mut system,
+            orbit,
+            &EARTH_ORBITAL_RADIUS,
+            &EARTH_MASS,
+            &EARTH_RADIUS,
+        );
+        let values = periodic_orbit_return_map_values(orbit, EARTH_ORBITAL_RADIUS, EARTH_MASS);
+        let period = values.period;
+        let mut return_map = ReturnMap::new(&system, values, RETURN_MAP_TOLERANCE);
+        let distance = return_map.check(&system, period).unwrap();
+        let mut wtr = Some(Writer::from_writer(Vec::new()));
+        add_return_map_topline(wtr.as_mut().unwrap()).unwrap();
+        add_return(&mut wtr, &return_map, period, distance);
+        let text = String::from_utf8(wtr.unwrap().into_inner().unwrap()).unwrap();
+        let rows: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            rows,
+            [
+                "Period,Time,Phase Space Distance",
+                &format!("1,{},0", period)
+            ]
+        );
+    }
+}
@@ -1,11 +1,12 @@
+use crate::cluster::quantile;
 use crate::constants::*;
 use crate::helpers::{Particle, velocity_to_color};
 use crate::init_helpers::ConfigValues;
 use crate::render::LockedTarget::Planet;
-use macroquad::color::{Color, RED};
+use macroquad::color::{Color, RED, YELLOW};
 use macroquad::input::{is_key_down, is_key_released};
 use macroquad::math::{DVec2, Vec2};
-use macroquad::prelude::{KeyCode, draw_circle, draw_line};
+use macroquad::prelude::{KeyCode, draw_circle, draw_circle_lines, draw_line};
 use macroquad::text::draw_text;
 use std::any::Any;
 use std::string::ToString;
@@ -42,6 +43,30 @@ impl ScreenValues {
         self.center_meters = DVec2::ZERO;
         self.mode = Mode::Free;
     }
+
+    // Sizes and centers the screen so SCREEN_FIT_QUANTILE of the bodies fit around the center of mass
+    pub fn initialize_to_fit(&mut self, screen_size_pixels: u32, system: &[Particle]) {
+        let total_mass: f64 = system.iter().map(|body| body.mass).sum();
+        let center_of_mass = if total_mass > 0. {
+            system
+                .iter()
+                .map(|body| body.position * body.mass)
+                .sum::<DVec2>()
+                / total_mass
+        } else {
+            DVec2::ZERO
+        };
+        let mut distances: Vec<f64> = system
+            .iter()
+            .map(|body| (body.position - center_of_mass).length())
+            .collect();
+        distances.sort_by(|a, b| a.total_cmp(b));
+        let fit_radius = quantile(&distances, SCREEN_FIT_QUANTILE).max(f64::MIN_POSITIVE);
+
+        self.initialize(screen_size_pixels, 2. * SCREEN_FIT_MARGIN * fit_radius);
+        self.center_meters = center_of_mass;
+    }
+
     pub fn update_free(&mut self) {
         let down = is_key_down(KeyCode::S);
         let up = is_key_down(KeyCode::W);
@@ -117,6 +142,47 @@ pub fn draw_bodies(system: &Vec<Particle>, screen_values: &ScreenValues) {
     }
 }
 
+// Rings both bodies of every ongoing close encounter and joins them with a line
+pub fn draw_encounters(
+    system: &[Particle],
+    pairs: &[(usize, usize)],
+    screen_values: &ScreenValues,
+) {
+    for &(i, j) in pairs.iter() {
+        let position_i = screen_values.physical_pos_to_screen_coords(system[i].position);
+        let position_j = screen_values.physical_pos_to_screen_coords(system[j].position);
+        draw_line(
+            position_i.x,
+            position_i.y,
+            position_j.x,
+            position_j.y,
+            ENCOUNTER_LINE_WIDTH,
+            YELLOW,
+        );
+        for (body, position) in [(i, position_i), (j, position_j)] {
+            draw_circle_lines(
+                position.x,
+                position.y,
+                system[body].generate_visible_radius() + ENCOUNTER_RING_PIXELS,
+                ENCOUNTER_LINE_WIDTH,
+                YELLOW,
+            );
+        }
+    }
+}
+
+pub fn draw_test_particles(test_particles: &[Particle], screen_values: &ScreenValues) {
+    for particle in test_particles.iter() {
+        let screen_position = screen_values.physical_pos_to_screen_coords(particle.position);
+        draw_circle(
+            screen_position.x,
+            screen_position.y,
+            TEST_PARTICLE_RADIUS_PIXELS,
+            particle.color,
+        );
+    }
+}
+
 pub fn draw_trails(
     num_important_bodies: usize,
     system: &Vec<Particle>,
@@ -188,8 +254,8 @@ pub fn render_call(
     trails: bool,
     screen_values: &mut ScreenValues,
     system: &mut Vec<Particle>,
+    test_particles: &[Particle],
     init_output: &ConfigValues,
-    important_bodies_added: usize,
     mut trail_point_counter: &mut usize,
     mut trail_values: &mut Vec<Vec<(DVec2, Color)>>,
 ) {
@@ -210,6 +276,7 @@ pub fn render_call(
         Mode::Locked(Planet(id)) => screen_values.update_locked(system, id),
     }
     // Draws main bodies
+    draw_test_particles(test_particles, screen_values);
     draw_bodies(&system, &screen_values);
     cross(&screen_values);
     if is_key_released(KeyCode::Tab) {
@@ -220,7 +287,7 @@ pub fn render_call(
     }
     if trails {
         draw_trails(
-            important_bodies_added,
+            init_output.important_bodies_added,
             &system,
             &mut trail_point_counter,
             &mut trail_values,
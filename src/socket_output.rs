@@ -0,0 +1,308 @@
+use crate::body_columns::BodyColumns;
+use crate::constants::SOCKET_DEFAULT_ADDRESS;
+use crate::helpers::{Particle, find_system_totals, get_int_from_user, get_text_from_user};
+use crate::output_sink::OutputSink;
+use crate::tidy_output::DIAGNOSTIC_COLUMNS;
+use serde_json::{Map, Value, json};
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/*
+Streams every sample to one consumer connected over a local TCP port or a Unix domain socket,
+so another process can watch a run as it happens. The simulation listens and waits for the
+consumer before starting. Each sample carries the same system totals as the CSV diagnostics
+and, unless only diagnostics were asked for, the chosen body columns.
+
+Newline delimited JSON sends one object per sample:
+  {"time_s": ..., "kinetic_energy_j": ..., ..., "massive_bodies": ...,
+   "bodies": [{"body_id": 0, "name": "...", "mass_kg": ..., "x_m": ..., ...}, ...]}
+with NaN written as null.
+
+Binary frames are a little endian u32 byte length followed by the payload. The first frame is a
+JSON header {"diagnostics": [...], "body_columns": [...]} naming the values, and every frame
+after it is a u32 body count then f64 values: time_s, the diagnostics, and for each body
+mass_kg followed by its body columns.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamEncoding {
+    Ndjson,
+    Binary,
+}
+
+pub enum SocketStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Write for SocketStream {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        match self {
+            SocketStream::Tcp(stream) => stream.write(bytes),
+            #[cfg(unix)]
+            SocketStream::Unix(stream) => stream.write(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SocketStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            SocketStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+// An address like 127.0.0.1:7878 listens on TCP, anything else is the path of a Unix socket
+pub fn accept_consumer(address: &str) -> io::Result<SocketStream> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        let listener = TcpListener::bind(address)?;
+        println!("Waiting for a consumer on {}", listener.local_addr()?);
+        return Ok(SocketStream::Tcp(listener.accept()?.0));
+    }
+    accept_unix_consumer(address)
+}
+
+#[cfg(unix)]
+fn accept_unix_consumer(path: &str) -> io::Result<SocketStream> {
+    use std::os::unix::fs::FileTypeExt;
+    // A socket left behind by an earlier run would make bind fail
+    if let Ok(metadata) = std::fs::symlink_metadata(path)
+        && metadata.file_type().is_socket()
+    {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    println!("Waiting for a consumer on {}", path);
+    Ok(SocketStream::Unix(listener.accept()?.0))
+}
+
+#[cfg(not(unix))]
+fn accept_unix_consumer(path: &str) -> io::Result<SocketStream> {
+    Err(io::Error::other(format!(
+        "{} is not a host:port, and Unix sockets aren't supported here",
+        path
+    )))
+}
+
+pub struct SocketSink {
+    stream: BufWriter<SocketStream>,
+    encoding: StreamEncoding,
+    columns: Option<BodyColumns>, // None streams the diagnostics only
+    header_sent: bool,
+}
+
+impl SocketSink {
+    pub fn new(
+        stream: SocketStream,
+        encoding: StreamEncoding,
+        columns: Option<BodyColumns>,
+    ) -> SocketSink {
+        SocketSink {
+            stream: BufWriter::new(stream),
+            encoding,
+            columns,
+            header_sent: false,
+        }
+    }
+
+    // Asks for the address, encoding and content, then waits for the consumer to connect
+    pub fn from_user(columns: &BodyColumns) -> io::Result<SocketSink> {
+        let mut address = get_text_from_user(&format!(
+            "Address to listen on, a host:port for TCP or a path for a Unix socket? Leave empty for {}",
+            SOCKET_DEFAULT_ADDRESS
+        ));
+        if address.is_empty() {
+            address = SOCKET_DEFAULT_ADDRESS.to_string();
+        }
+        let encoding = loop {
+            match get_int_from_user(
+                "What encoding? \n[0] Newline delimited JSON\n[1] Binary frames",
+            ) {
+                0 => break StreamEncoding::Ndjson,
+                1 => break StreamEncoding::Binary,
+                _ => println!("Invalid encoding"),
+            }
+        };
+        let columns = loop {
+            match get_int_from_user(
+                "What should be streamed? \n[0] Body states and diagnostics\n[1] Diagnostics only",
+            ) {
+                0 => break Some(columns.clone()),
+                1 => break None,
+                _ => println!("Invalid choice"),
+            }
+        };
+        Ok(SocketSink::new(
+            accept_consumer(&address)?,
+            encoding,
+            columns,
+        ))
+    }
+
+    fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.stream
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.stream.write_all(payload)
+    }
+
+    fn write_json(
+        &mut self,
+        system: &[Particle],
+        time: f64,
+        diagnostics: &[f64],
+    ) -> io::Result<()> {
+        let mut sample = Map::new();
+        sample.insert("time_s".to_string(), json!(time));
+        for (name, value) in DIAGNOSTIC_COLUMNS[2..].iter().zip(diagnostics) {
+            sample.insert(name.to_string(), json!(value));
+        }
+        if let Some(columns) = &self.columns {
+            let names = columns.long_names();
+            let bodies: Vec<Value> = system
+                .iter()
+                .zip(columns.values(system))
+                .enumerate()
+                .map(|(body_id, (body, values))| {
+                    let mut entry = Map::new();
+                    entry.insert("body_id".to_string(), json!(body_id));
+                    entry.insert("name".to_string(), json!(body.name));
+                    entry.insert("mass_kg".to_string(), json!(body.mass));
+                    for (name, value) in names.iter().zip(values) {
+                        entry.insert(name.to_string(), json!(value));
+                    }
+                    Value::Object(entry)
+                })
+                .collect();
+            sample.insert("bodies".to_string(), Value::Array(bodies));
+        }
+        serde_json::to_writer(&mut self.stream, &Value::Object(sample))
+            .map_err(io::Error::other)?;
+        self.stream.write_all(b"\n")
+    }
+
+    fn write_binary(
+        &mut self,
+        system: &[Particle],
+        time: f64,
+        diagnostics: &[f64],
+    ) -> io::Result<()> {
+        if !self.header_sent {
+            let body_columns = self
+                .columns
+                .as_ref()
+                .map(|columns| columns.long_names())
+                .unwrap_or_default();
+            let header = json!({
+                "diagnostics": DIAGNOSTIC_COLUMNS[2..],
+                "body_columns": body_columns,
+            });
+            self.write_frame(header.to_string().as_bytes())?;
+            self.header_sent = true;
+        }
+        let bodies = match &self.columns {
+            Some(columns) => system.iter().zip(columns.values(system)).collect(),
+            None => Vec::new(),
+        };
+        let mut payload = (bodies.len() as u32).to_le_bytes().to_vec();
+        let mut push = |value: f64| payload.extend_from_slice(&value.to_le_bytes());
+        push(time);
+        diagnostics.iter().for_each(|value| push(*value));
+        for (body, values) in bodies {
+            push(body.mass);
+            values.into_iter().for_each(&mut push);
+        }
+        self.write_frame(&payload)
+    }
+}
+
+impl OutputSink for SocketSink {
+    fn add_data(&mut self, system: &[Particle], time: f64) -> io::Result<()> {
+        let totals = find_system_totals(system);
+        let diagnostics = [
+            totals.kinetic_energy,
+            totals.potential_energy,
+            totals.total_energy(),
+            totals.momentum.x,
+            totals.momentum.y,
+            system.iter().filter(|body| body.mass > 0.).count() as f64,
+        ];
+        match self.encoding {
+            StreamEncoding::Ndjson => self.write_json(system, time, &diagnostics)?,
+            StreamEncoding::Binary => self.write_binary(system, time, &diagnostics)?,
+        }
+        // The consumer is watching live, so nothing waits in the buffer
+        self.stream.flush()
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body_columns::BodyQuantity;
+    use crate::constants::*;
+    use crate::output_sink::test_support::test_system;
+    use std::io::{BufRead, BufReader, Read};
+
+    fn connected_pair() -> (SocketStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let consumer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (SocketStream::Tcp(listener.accept().unwrap().0), consumer)
+    }
+
+    #[test]
+    fn test_ndjson_stream() {
+        let (stream, consumer) = connected_pair();
+        let columns = BodyColumns::new(&[BodyQuantity::Position, BodyQuantity::DistanceToPrimary]);
+        let mut sink: Box<dyn OutputSink> = Box::new(SocketSink::new(
+            stream,
+            StreamEncoding::Ndjson,
+            Some(columns),
+        ));
+        sink.add_data(&test_system(), 1.).unwrap();
+        sink.add_data(&test_system(), 2.).unwrap();
+        sink.finish().unwrap();
+
+        let lines: Vec<String> = BufReader::new(consumer)
+            .lines()
+            .map(|line| line.unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        let sample: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(sample["time_s"], 2.);
+        assert_eq!(sample["massive_bodies"], 2.);
+        assert_eq!(sample["bodies"][1]["name"], "earth");
+        assert_eq!(sample["bodies"][1]["x_m"], AU);
+        assert_eq!(sample["bodies"][1]["distance_to_primary_m"], AU);
+    }
+
+    #[test]
+    fn test_binary_stream() {
+        let (stream, mut consumer) = connected_pair();
+        let mut sink: Box<dyn OutputSink> =
+            Box::new(SocketSink::new(stream, StreamEncoding::Binary, None));
+        sink.add_data(&test_system(), 3.).unwrap();
+        sink.finish().unwrap();
+
+        let mut bytes = Vec::new();
+        consumer.read_to_end(&mut bytes).unwrap();
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap()) as usize;
+        let header_length = u32_at(0);
+        let header: Value = serde_json::from_slice(&bytes[4..4 + header_length]).unwrap();
+        assert_eq!(header["diagnostics"][0], "kinetic_energy_j");
+        assert_eq!(header["body_columns"], json!([]));
+
+        let frame = 4 + header_length;
+        // A body count, then time and six diagnostics
+        assert_eq!(u32_at(frame), 4 + 7 * 8);
+        assert_eq!(u32_at(frame + 4), 0);
+        let time = f64::from_le_bytes(bytes[frame + 8..frame + 16].try_into().unwrap());
+        assert_eq!(time, 3.);
+    }
+}
@@ -0,0 +1,463 @@
+use crate::constants::SECONDS_IN_DAY;
+use crate::horizon::{BodyKind, HorizonsTarget, OutputValues, PhysicalData, StateVector};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Put .bsp kernels like de440.bsp here, or list them in the SPK_KERNELS environment variable
+pub const SPK_KERNEL_DIR: &str = "ephemerides";
+
+const RECORD_BYTES: u64 = 1024;
+const J2000_JULIAN_DATE: f64 = 2451545.;
+const SOLAR_SYSTEM_BARYCENTER: i32 = 0;
+const SUN: i32 = 10;
+const J2000_FRAME: i32 = 1;
+// Mean obliquity of the ecliptic at J2000, 84381.448 arcseconds
+const J2000_OBLIQUITY: f64 = 84381.448 / 3600. * std::f64::consts::PI / 180.;
+
+/*
+One segment summary from a DAF file: which body it gives relative to which, over what span of
+TDB seconds past J2000, and the word addresses of its data. Addresses count 8 byte words from 1.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SpkSegment {
+    pub target: i32,
+    pub center: i32,
+    pub frame: i32,
+    pub data_type: i32,
+    pub start_et: f64,
+    pub end_et: f64,
+    start_address: u64,
+    end_address: u64,
+}
+
+pub struct SpkFile {
+    pub segments: Vec<SpkSegment>,
+    little_endian: bool,
+    file: Mutex<File>,
+}
+
+impl SpkFile {
+    pub fn open(path: &Path) -> io::Result<SpkFile> {
+        let mut file = File::open(path)?;
+        let mut file_record = [0u8; RECORD_BYTES as usize];
+        file.read_exact(&mut file_record)?;
+        if &file_record[..7] != b"DAF/SPK" {
+            return Err(invalid_data(format!(
+                "{} is not an SPK file",
+                path.display()
+            )));
+        }
+        let little_endian = match &file_record[88..96] {
+            b"LTL-IEEE" => true,
+            b"BIG-IEEE" => false,
+            // Files older than the format string: ND is always 2 for SPK
+            _ => i32::from_le_bytes(file_record[8..12].try_into().unwrap()) == 2,
+        };
+        let mut spk_file = SpkFile {
+            segments: Vec::new(),
+            little_endian,
+            file: Mutex::new(file),
+        };
+        let int_at = |offset: usize| spk_file.int(&file_record[offset..offset + 4]);
+        let (double_count, int_count) = (int_at(8), int_at(12));
+        if double_count != 2 || int_count != 6 {
+            return Err(invalid_data(format!(
+                "{} has {} doubles and {} integers per summary instead of 2 and 6",
+                path.display(),
+                double_count,
+                int_count
+            )));
+        }
+
+        // Summary records form a linked list, each with up to 25 five word summaries
+        let mut segments = Vec::new();
+        let mut record_number = int_at(76) as u64;
+        while record_number > 0 {
+            let record = spk_file.read_bytes((record_number - 1) * RECORD_BYTES, RECORD_BYTES)?;
+            let double = |word: usize| spk_file.double(&record[word * 8..word * 8 + 8]);
+            let summary_count = double(2) as usize;
+            for i in 0..summary_count.min(25) {
+                let start = 24 + i * 40;
+                let int = |n: usize| spk_file.int(&record[start + 16 + n * 4..start + 20 + n * 4]);
+                segments.push(SpkSegment {
+                    start_et: spk_file.double(&record[start..start + 8]),
+                    end_et: spk_file.double(&record[start + 8..start + 16]),
+                    target: int(0),
+                    center: int(1),
+                    frame: int(2),
+                    data_type: int(3),
+                    start_address: int(4) as u64,
+                    end_address: int(5) as u64,
+                });
+            }
+            record_number = double(0) as u64;
+        }
+        spk_file.segments = segments;
+        Ok(spk_file)
+    }
+
+    fn int(&self, bytes: &[u8]) -> i32 {
+        let bytes = bytes.try_into().unwrap();
+        if self.little_endian {
+            i32::from_le_bytes(bytes)
+        } else {
+            i32::from_be_bytes(bytes)
+        }
+    }
+
+    fn double(&self, bytes: &[u8]) -> f64 {
+        let bytes = bytes.try_into().unwrap();
+        if self.little_endian {
+            f64::from_le_bytes(bytes)
+        } else {
+            f64::from_be_bytes(bytes)
+        }
+    }
+
+    fn read_bytes(&self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0u8; length as usize];
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_doubles(&self, address: u64, count: u64) -> io::Result<Vec<f64>> {
+        let bytes = self.read_bytes((address - 1) * 8, count * 8)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|bytes| self.double(bytes))
+            .collect())
+    }
+
+    /*
+    Types 2 and 3 split the segment into equal intervals, each with Chebyshev coefficients for
+    x, y and z (type 2) or for the velocity as well (type 3). The segment ends with the start of
+    the first interval, the interval length, the words per record and the number of records.
+     */
+    fn evaluate(&self, segment: &SpkSegment, et: f64) -> io::Result<[f64; 6]> {
+        let directory = self.read_doubles(segment.end_address - 3, 4)?;
+        let (first_start, interval, record_size, record_count) = (
+            directory[0],
+            directory[1],
+            directory[2] as u64,
+            directory[3] as u64,
+        );
+        let index = (((et - first_start) / interval).floor().max(0.) as u64)
+            .min(record_count.saturating_sub(1));
+        let record = self.read_doubles(segment.start_address + index * record_size, record_size)?;
+        let (middle, radius) = (record[0], record[1]);
+        let s = (et - middle) / radius;
+        let components = if segment.data_type == 2 { 3 } else { 6 };
+        let degree = (record_size as usize - 2) / components;
+        let coefficients = |component: usize| &record[2 + component * degree..][..degree];
+
+        let mut state = [0.; 6];
+        for component in 0..3 {
+            let (position, derivative) = chebyshev(coefficients(component), s);
+            state[component] = position;
+            state[component + 3] = if segment.data_type == 2 {
+                derivative / radius
+            } else {
+                chebyshev(coefficients(component + 3), s).0
+            };
+        }
+        Ok(state)
+    }
+}
+
+// The value and the derivative with respect to s of a Chebyshev series at s in [-1, 1]
+fn chebyshev(coefficients: &[f64], s: f64) -> (f64, f64) {
+    let (mut t_previous, mut t) = (1., s);
+    let (mut dt_previous, mut dt) = (0., 1.);
+    let mut value = coefficients[0];
+    let mut derivative = 0.;
+    for (k, coefficient) in coefficients.iter().enumerate().skip(1) {
+        if k > 1 {
+            let t_next = 2. * s * t - t_previous;
+            let dt_next = 2. * t + 2. * s * dt - dt_previous;
+            (t_previous, t) = (t, t_next);
+            (dt_previous, dt) = (dt, dt_next);
+        }
+        value += coefficient * t;
+        derivative += coefficient * dt;
+    }
+    (value, derivative)
+}
+
+/*
+A set of kernels, searched from the last loaded to the first like SPICE does, so a satellite
+kernel listed after de440.bsp overrides it. States are chained through their centers down to
+the Solar System barycenter.
+ */
+pub struct SpkKernels {
+    pub files: Vec<SpkFile>,
+}
+
+impl SpkKernels {
+    pub fn open(paths: &[PathBuf]) -> io::Result<SpkKernels> {
+        let files = paths
+            .iter()
+            .map(|path| SpkFile::open(path))
+            .collect::<io::Result<_>>()?;
+        Ok(SpkKernels { files })
+    }
+
+    // Kernels from SPK_KERNELS, or every .bsp file in SPK_KERNEL_DIR, if there are any
+    pub fn from_env() -> Option<SpkKernels> {
+        let paths: Vec<PathBuf> = match env::var_os("SPK_KERNELS") {
+            Some(list) => env::split_paths(&list).collect(),
+            None => {
+                let mut paths: Vec<PathBuf> = fs::read_dir(SPK_KERNEL_DIR)
+                    .ok()?
+                    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                    .filter(|path| path.extension().is_some_and(|extension| extension == "bsp"))
+                    .collect();
+                paths.sort();
+                paths
+            }
+        };
+        if paths.is_empty() {
+            return None;
+        }
+        match SpkKernels::open(&paths) {
+            Ok(kernels) => {
+                println!("Using SPK kernels {:?}", paths);
+                Some(kernels)
+            }
+            Err(e) => {
+                println!("Could not read SPK kernels, ignoring them: {}", e);
+                None
+            }
+        }
+    }
+
+    fn find_segment(&self, target: i32, et: f64) -> Option<(&SpkFile, &SpkSegment)> {
+        self.files.iter().rev().find_map(|file| {
+            file.segments
+                .iter()
+                .rev()
+                .find(|segment| {
+                    segment.target == target
+                        && segment.frame == J2000_FRAME
+                        && (segment.data_type == 2 || segment.data_type == 3)
+                        && segment.start_et <= et
+                        && et <= segment.end_et
+                })
+                .map(|segment| (file, segment))
+        })
+    }
+
+    // Position (km) and velocity (km/s) relative to the Solar System barycenter, in J2000
+    pub fn barycentric_state(&self, target: i32, et: f64) -> io::Result<[f64; 6]> {
+        let mut state = [0.; 6];
+        let mut body = target;
+        while body != SOLAR_SYSTEM_BARYCENTER {
+            let (file, segment) = self.find_segment(body, et).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no SPK segment for body {} at ET {:.0}", body, et),
+                )
+            })?;
+            let relative = file.evaluate(segment, et)?;
+            for (total, component) in state.iter_mut().zip(relative) {
+                *total += component;
+            }
+            body = segment.center;
+        }
+        Ok(state)
+    }
+
+    /*
+    The state relative to the Sun's center in the ecliptic plane of J2000, which is what the
+    Horizons queries return. Planets whose body center isn't in the kernels, like Jupiter with
+    only de440.bsp, fall back to their system barycenter.
+     */
+    pub fn heliocentric_state(&self, target: i32, julian_date: f64) -> io::Result<StateVector> {
+        let et = (julian_date - J2000_JULIAN_DATE) * SECONDS_IN_DAY;
+        let body_state = match self.barycentric_state(target, et) {
+            Err(_) if (199..=999).contains(&target) && target % 100 == 99 => {
+                self.barycentric_state(target / 100, et)?
+            }
+            result => result?,
+        };
+        let sun_state = self.barycentric_state(SUN, et)?;
+        let relative: Vec<f64> = body_state
+            .iter()
+            .zip(sun_state)
+            .map(|(body, sun)| body - sun)
+            .collect();
+        let (x, y) = equatorial_to_ecliptic(relative[0], relative[1], relative[2]);
+        let (vx, vy) = equatorial_to_ecliptic(relative[3], relative[4], relative[5]);
+        Ok(StateVector {
+            julian_date,
+            x,
+            y,
+            vx,
+            vy,
+        })
+    }
+}
+
+// The x and y of a J2000 equatorial vector in the J2000 ecliptic frame
+fn equatorial_to_ecliptic(x: f64, y: f64, z: f64) -> (f64, f64) {
+    (x, y * J2000_OBLIQUITY.cos() + z * J2000_OBLIQUITY.sin())
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/*
+Output values for every target with a numeric command the kernels cover, and the targets left
+over for Horizons. Kernels carry no physical data, so masses and radii come from the tables.
+ */
+pub fn spk_ephemerides(
+    kernels: &SpkKernels,
+    targets: &[HorizonsTarget],
+    julian_dates: &[f64],
+) -> (Vec<(OutputValues, Vec<StateVector>)>, Vec<HorizonsTarget>) {
+    let mut ephemerides = Vec::new();
+    let mut remaining = Vec::new();
+    for target in targets.iter() {
+        let states: Option<Vec<StateVector>> = target.command.parse::<i32>().ok().and_then(|id| {
+            julian_dates
+                .iter()
+                .map(|julian_date| kernels.heliocentric_state(id, *julian_date).ok())
+                .collect()
+        });
+        match states {
+            Some(states) if !states.is_empty() => {
+                let last = states[states.len() - 1];
+                let values = OutputValues {
+                    name: target.name.clone(),
+                    kind: if target.is_major_body() {
+                        BodyKind::MajorBody
+                    } else if target.command.starts_with('-') {
+                        BodyKind::Spacecraft
+                    } else {
+                        BodyKind::SmallBody
+                    },
+                    x: last.x,
+                    y: last.y,
+                    vx: last.vx,
+                    vy: last.vy,
+                    physical_data: PhysicalData::default(),
+                };
+                ephemerides.push((values, states));
+            }
+            _ => remaining.push(target.clone()),
+        }
+    }
+    (ephemerides, remaining)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (target, center, type, record) where each record is the whole segment of one interval
+    fn write_test_kernel(path: &Path, segments: &[(i32, i32, i32, Vec<f64>)], radius: f64) {
+        let mut file_record = vec![0u8; RECORD_BYTES as usize];
+        file_record[..8].copy_from_slice(b"DAF/SPK ");
+        file_record[8..12].copy_from_slice(&2i32.to_le_bytes());
+        file_record[12..16].copy_from_slice(&6i32.to_le_bytes());
+        file_record[76..80].copy_from_slice(&2i32.to_le_bytes());
+        file_record[88..96].copy_from_slice(b"LTL-IEEE");
+
+        let mut summary_record = vec![0u8; RECORD_BYTES as usize];
+        summary_record[16..24].copy_from_slice(&(segments.len() as f64).to_le_bytes());
+        let mut data: Vec<f64> = Vec::new();
+        let first_address = 3 * RECORD_BYTES / 8 + 1;
+        for (i, (target, center, data_type, record)) in segments.iter().enumerate() {
+            let start_address = first_address + data.len() as u64;
+            data.extend(record);
+            data.extend([-radius, 2. * radius, record.len() as f64, 1.]);
+            let end_address = first_address + data.len() as u64 - 1;
+            let start = 24 + i * 40;
+            summary_record[start..start + 8].copy_from_slice(&(-radius).to_le_bytes());
+            summary_record[start + 8..start + 16].copy_from_slice(&radius.to_le_bytes());
+            let ints = [
+                *target,
+                *center,
+                J2000_FRAME,
+                *data_type,
+                start_address as i32,
+                end_address as i32,
+            ];
+            for (n, int) in ints.iter().enumerate() {
+                let offset = start + 16 + n * 4;
+                summary_record[offset..offset + 4].copy_from_slice(&int.to_le_bytes());
+            }
+        }
+
+        let mut bytes = file_record;
+        bytes.extend(summary_record);
+        bytes.extend(vec![b' '; RECORD_BYTES as usize]);
+        for word in data {
+            bytes.extend(word.to_le_bytes());
+        }
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_chebyshev() {
+        // 1 + 2 s + 3 (2 s^2 - 1)
+        let (value, derivative) = chebyshev(&[1., 2., 3.], 0.5);
+        assert!((value - (1. + 1. + 3. * -0.5)).abs() < 1e-12);
+        assert!((derivative - (2. + 12. * 0.5)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_spk_chains_segments() {
+        let radius = 10. * SECONDS_IN_DAY;
+        let path = env::temp_dir().join(format!("nbodyproblem_test_{}.bsp", std::process::id()));
+        let segments = vec![
+            // The Sun sits still at (1, 2, 3) km from the barycenter
+            (SUN, 0, 2, vec![0., radius, 1., 0., 2., 0., 3., 0.]),
+            // The Earth-Moon barycenter moves along x, with its velocity given explicitly
+            (
+                3,
+                0,
+                3,
+                vec![
+                    0., radius, 100., 10., 0., 0., 0., 0., 7., 0., 0., 0., 0., 0.,
+                ],
+            ),
+            // The Earth moves along y and z relative to the Earth-Moon barycenter
+            (399, 3, 2, vec![0., radius, 0., 0., 5., 4., 0., 4.]),
+        ];
+        write_test_kernel(&path, &segments, radius);
+        let kernels = SpkKernels::open(std::slice::from_ref(&path)).unwrap();
+        assert_eq!(kernels.files[0].segments.len(), 3);
+
+        let julian_date = J2000_JULIAN_DATE + 5.;
+        let s = 0.5;
+        let state = kernels.heliocentric_state(399, julian_date).unwrap();
+        let expected = equatorial_to_ecliptic(100. + 10. * s - 1., 5. + 4. * s - 2., 4. * s - 3.);
+        assert!((state.x - expected.0).abs() < 1e-9);
+        assert!((state.y - expected.1).abs() < 1e-9);
+        let expected_velocity = equatorial_to_ecliptic(7., 4. / radius, 4. / radius);
+        assert!((state.vx - expected_velocity.0).abs() < 1e-12);
+        assert!((state.vy - expected_velocity.1).abs() < 1e-12);
+
+        // Mars isn't in the kernel, and outside the segments nothing is covered
+        assert!(kernels.heliocentric_state(499, julian_date).is_err());
+        assert!(kernels.barycentric_state(399, 2. * radius).is_err());
+        let (ephemerides, remaining) = spk_ephemerides(
+            &kernels,
+            &[
+                HorizonsTarget::major_body("earth"),
+                HorizonsTarget::major_body("mars"),
+            ],
+            &[julian_date],
+        );
+        assert_eq!(ephemerides.len(), 1);
+        assert_eq!(ephemerides[0].0.name, "earth");
+        assert_eq!(remaining[0].name, "mars");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use crate::constants::*;
+use crate::helpers::Particle;
+use crate::horizon::{epoch_from_julian_date, parse_saved_horizons_text};
+use crate::horizons_table::HORIZONS_COLORS;
+use crate::init_helpers::horizons_particle;
+use chrono::NaiveDateTime;
+use macroquad::color::LIGHTGRAY;
+use macroquad::math::DVec2;
+use std::f64::consts::TAU;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub struct ImportedSystem {
+    pub bodies: Vec<Particle>,
+    pub epoch: Option<NaiveDateTime>, // Only known for Horizons exports
+}
+
+/*
+Reads initial conditions from a file, chosen by its extension:
+.csv and .json are generic state vector files, anything else is read as text saved from
+Horizons. A directory imports every file in it, in name order.
+ */
+pub fn import_system(path: &Path) -> io::Result<ImportedSystem> {
+    if path.is_dir() {
+        let mut paths: Vec<_> = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+        paths.sort();
+        let mut imported = ImportedSystem {
+            bodies: Vec::new(),
+            epoch: None,
+        };
+        for file_path in paths.iter().filter(|file_path| file_path.is_file()) {
+            let file_system = import_system(file_path)?;
+            imported.bodies.extend(file_system.bodies);
+            imported.epoch = imported.epoch.or(file_system.epoch);
+        }
+        return Ok(imported);
+    }
+
+    let text = fs::read_to_string(path)?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match extension.as_str() {
+        "csv" => Ok(ImportedSystem {
+            bodies: import_csv(&text)?,
+            epoch: None,
+        }),
+        "json" => Ok(ImportedSystem {
+            bodies: import_json(&text)?,
+            epoch: None,
+        }),
+        _ => import_horizons_text(&text),
+    }
+}
+
+// Units a column or field can be declared in, as multiples of the SI unit
+fn unit_scale(unit: &str) -> io::Result<f64> {
+    let scale = match unit.trim().to_lowercase().as_str() {
+        "" | "m" | "kg" | "m/s" => 1.,
+        "km" => 1e3,
+        "au" => AU,
+        "km/s" => 1e3,
+        "km/d" | "km/day" => 1e3 / SECONDS_IN_DAY,
+        "au/d" | "au/day" => AU / SECONDS_IN_DAY,
+        "g" => 1e-3,
+        "msun" | "solar masses" => STAR_MASS,
+        "mearth" | "earth masses" => EARTH_MASS,
+        "mjup" | "jupiter masses" => JUPITER_MASS,
+        other => return Err(io::Error::other(format!("unknown unit '{}'", other))),
+    };
+    Ok(scale)
+}
+
+// "vx (km/s)" or "vx [km/s]" -> ("vx", "km/s"), and "vx" -> ("vx", "")
+fn split_unit(header: &str) -> (String, &str) {
+    let header = header.trim();
+    let unit_start = header.find(['(', '[']);
+    match unit_start {
+        Some(start) => (
+            header[..start].trim().to_lowercase(),
+            header[start + 1..].trim_end_matches([')', ']']),
+        ),
+        None => (header.to_lowercase(), ""),
+    }
+}
+
+/*
+A CSV with a header row naming the columns name, mass, radius, x, y, z, vx, vy and vz, each
+optionally followed by its unit like "x (au)" or "vx [km/s]". Columns without a unit are SI.
+mass, x, y, vx and vy are required. The simulation is 2D, so z and vz are ignored.
+ */
+pub fn import_csv(text: &str) -> io::Result<Vec<Particle>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(text.as_bytes());
+    let mut columns: Vec<(String, f64)> = Vec::new();
+    for header in reader.headers().map_err(io::Error::other)?.iter() {
+        let (name, unit) = split_unit(header);
+        let scale = if name == "name" {
+            1.
+        } else {
+            unit_scale(unit)?
+        };
+        columns.push((name, scale));
+    }
+
+    let mut bodies = Vec::new();
+    for (row_number, record) in reader.records().enumerate() {
+        let record = record.map_err(io::Error::other)?;
+        let field = |wanted: &str| {
+            columns
+                .iter()
+                .position(|(name, _)| name == wanted)
+                .and_then(|index| record.get(index).map(|text| (text, columns[index].1)))
+        };
+        let number = |wanted: &str| -> io::Result<Option<f64>> {
+            match field(wanted) {
+                Some((text, scale)) if !text.is_empty() => {
+                    let value: f64 = text.parse().map_err(|_| {
+                        io::Error::other(format!(
+                            "row {}: could not read {} '{}'",
+                            row_number + 1,
+                            wanted,
+                            text
+                        ))
+                    })?;
+                    Ok(Some(value * scale))
+                }
+                _ => Ok(None),
+            }
+        };
+        let name = field("name")
+            .map(|(text, _)| text.to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| format!("Body {}", row_number + 1));
+        bodies.push(imported_particle(
+            name,
+            number("mass")?,
+            number("radius")?,
+            [number("x")?, number("y")?, number("vx")?, number("vy")?],
+        )?);
+    }
+    Ok(bodies)
+}
+
+/*
+Either a list of bodies with SI values, or an object with the list under "bodies" and the
+units of the values under "units", e.g.
+{"units": {"length": "au", "velocity": "km/s", "mass": "msun", "radius": "km"},
+ "bodies": [{"name": "Sun", "mass": 1, "radius": 695700, "x": 0, "y": 0, "vx": 0, "vy": 0}]}
+The radius uses the length unit unless it has its own.
+ */
+pub fn import_json(text: &str) -> io::Result<Vec<Particle>> {
+    let parsed: serde_json::Value = serde_json::from_str(text).map_err(io::Error::other)?;
+    let units = &parsed["units"];
+    let unit = |quantity: &str| unit_scale(units[quantity].as_str().unwrap_or_default());
+    let length_scale = unit("length")?;
+    let velocity_scale = unit("velocity")?;
+    let mass_scale = unit("mass")?;
+    let radius_scale = match units["radius"].as_str() {
+        Some(radius_unit) => unit_scale(radius_unit)?,
+        None => length_scale,
+    };
+
+    let bodies = parsed
+        .as_array()
+        .or_else(|| parsed["bodies"].as_array())
+        .ok_or_else(|| io::Error::other("expected a list of bodies"))?;
+    bodies
+        .iter()
+        .enumerate()
+        .map(|(i, body)| {
+            let number = |key: &str, scale: f64| body[key].as_f64().map(|value| value * scale);
+            let name = body["name"]
+                .as_str()
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| format!("Body {}", i + 1));
+            imported_particle(
+                name,
+                number("mass", mass_scale),
+                number("radius", radius_scale),
+                [
+                    number("x", length_scale),
+                    number("y", length_scale),
+                    number("vx", velocity_scale),
+                    number("vy", velocity_scale),
+                ],
+            )
+        })
+        .collect()
+}
+
+// One or more vector tables saved from Horizons, one after another in the same text
+pub fn import_horizons_text(text: &str) -> io::Result<ImportedSystem> {
+    let mut imported = ImportedSystem {
+        bodies: Vec::new(),
+        epoch: None,
+    };
+    let mut rest = text;
+    while let Some(eoe) = rest.find("$$EOE") {
+        let (values, julian_date) =
+            parse_saved_horizons_text(&rest[..eoe + 5]).map_err(io::Error::other)?;
+        imported.bodies.push(horizons_particle(&values));
+        imported.epoch = imported.epoch.or(epoch_from_julian_date(julian_date));
+        rest = &rest[eoe + 5..];
+    }
+    if imported.bodies.is_empty() {
+        return Err(io::Error::other("no $$SOE/$$EOE vector table found"));
+    }
+    Ok(imported)
+}
+
+fn imported_particle(
+    name: String,
+    mass: Option<f64>,
+    radius: Option<f64>,
+    state: [Option<f64>; 4],
+) -> io::Result<Particle> {
+    let mass = mass.ok_or_else(|| io::Error::other(format!("{} has no mass", name)))?;
+    let [Some(x), Some(y), Some(vx), Some(vy)] = state else {
+        return Err(io::Error::other(format!("{} needs x, y, vx and vy", name)));
+    };
+    Ok(Particle {
+        mass,
+        radius: radius.unwrap_or(DEFAULT_SMALL_BODY_RADIUS),
+        position: DVec2::new(x, y),
+        velocity: DVec2::new(vx, vy),
+        color: HORIZONS_COLORS
+            .get(name.to_lowercase().as_str())
+            .copied()
+            .unwrap_or(LIGHTGRAY),
+        name,
+    })
+}
+
+// Shortest two body orbital period between any pair, so moons set the time step rather than
+// the planets they orbit
+pub fn shortest_orbital_period(system: &[Particle]) -> f64 {
+    let mut shortest = f64::INFINITY;
+    for (i, body) in system.iter().enumerate() {
+        for other in system[i + 1..].iter() {
+            let distance = (body.position - other.position).length();
+            let period = TAU * (distance.powi(3) / (G * (body.mass + other.mass))).sqrt();
+            if period > 0. {
+                shortest = shortest.min(period);
+            }
+        }
+    }
+    shortest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_csv_with_units() {
+        let text = "name, mass (msun), radius [km], x (au), y (au), z (au), vx (km/s), vy (km/s), vz\n\
+            Sun, 1, 695700, 0, 0, 0, 0, 0, 0\n\
+            , 3e-6, 6371, 1, 0, 0.1, 0, 29.78, 1\n";
+        let bodies = import_csv(text).unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0].name, "Sun");
+        assert_eq!(bodies[0].radius, 695700e3);
+        assert_eq!(bodies[1].name, "Body 2");
+        assert_eq!(bodies[1].mass, 3e-6 * STAR_MASS);
+        assert_eq!(bodies[1].position, DVec2::new(AU, 0.));
+        assert_eq!(bodies[1].velocity, DVec2::new(0., 29780.));
+
+        assert!(import_csv("name, mass (stone), x, y, vx, vy\nA, 1, 0, 0, 0, 0").is_err());
+        assert!(import_csv("name, mass, x, y, vx\nA, 1, 0, 0, 0").is_err());
+    }
+
+    #[test]
+    fn test_import_json() {
+        let text = r#"{"units": {"length": "au", "velocity": "au/d", "mass": "mearth", "radius": "km"},
+            "bodies": [{"name": "Earth", "mass": 1, "radius": 6371, "x": 1, "y": 0, "vx": 0, "vy": 0.0172}]}"#;
+        let bodies = import_json(text).unwrap();
+        assert_eq!(bodies[0].mass, EARTH_MASS);
+        assert_eq!(bodies[0].radius, 6371e3);
+        assert_eq!(bodies[0].position.x, AU);
+        assert!((bodies[0].velocity.y - 0.0172 * AU / SECONDS_IN_DAY).abs() < 1e-9);
+
+        let si = r#"[{"mass": 5, "x": 1, "y": 2, "vx": 3, "vy": 4}]"#;
+        let bodies = import_json(si).unwrap();
+        assert_eq!(bodies[0].name, "Body 1");
+        assert_eq!(bodies[0].velocity, DVec2::new(3., 4.));
+    }
+
+    #[test]
+    fn test_import_horizons_text() {
+        let export = |target: &str, gm: &str, x: &str| {
+            format!(
+                " Target body name: {}\n GM, km^3/s^2          = {}\n\
+                $$SOE\n2453736.500000000 = A.D. 2006-Jan-01 00:00:00.0000 TDB\n\
+                 X = {} Y = 0.0E+00 Z = 0.0E+00\n VX= 0.0E+00 VY= 1.0E+01 VZ= 0.0E+00\n$$EOE\n",
+                target, gm, x
+            )
+        };
+        let text = export(
+            "Mars (499)                {source: mar097}",
+            "42828.375214",
+            "2.0E+08",
+        ) + &export("Voyager 1 (spacecraft) (-31)", "n.a.", "2.0E+10");
+        let imported = import_horizons_text(&text).unwrap();
+        assert_eq!(imported.bodies.len(), 2);
+        assert_eq!(imported.bodies[0].name, "mars");
+        assert_eq!(imported.bodies[0].position.x, 2.0E+11);
+        assert!((imported.bodies[0].mass / 6.417e23 - 1.).abs() < 1e-3);
+        assert_eq!(imported.bodies[1].name, "Voyager 1 (spacecraft) (-31)");
+        assert_eq!(imported.bodies[1].mass, DEFAULT_SPACECRAFT_MASS);
+        assert_eq!(imported.epoch, epoch_from_julian_date(2453736.5));
+        assert!(import_horizons_text("No matches found.").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::body_columns::BodyColumns;
+use crate::constants::SECONDS_IN_DAY;
+use crate::helpers::{Particle, find_system_totals};
+use crate::horizon::julian_date;
+use crate::output_sink::{OutputSink, OutputStream};
+use chrono::NaiveDateTime;
+use csv::Writer;
+use std::io;
+
+/*
+Long format output for loading straight into a data frame. Each sample adds one row per body to
+{prefix}_bodies.csv and one row of system totals to {prefix}_diagnostics.csv. Both files have a
+single header row and SI units, which the column names spell out.
+
+{prefix}_bodies.csv
+  time_s        Seconds since the start of the run
+  julian_date   TDB Julian date of the sample, empty unless the scenario has an epoch
+  body_id       Index of the body in the initial system, which collisions don't change
+  name          Body name, like "earth" from Horizons or "Disk Star 3" from a generator
+  mass_kg       Zero once a body has merged into another
+  radius_m
+followed by the chosen body columns, by default
+  x_m, y_m
+  vx_m_per_s, vy_m_per_s
+
+{prefix}_diagnostics.csv
+  time_s, julian_date
+  kinetic_energy_j, potential_energy_j, total_energy_j
+  momentum_x_kg_m_per_s, momentum_y_kg_m_per_s
+  massive_bodies  Bodies with nonzero mass, which drops as bodies merge
+ */
+pub const BODY_ID_COLUMNS: [&str; 6] = [
+    "time_s",
+    "julian_date",
+    "body_id",
+    "name",
+    "mass_kg",
+    "radius_m",
+];
+
+pub const DIAGNOSTIC_COLUMNS: [&str; 8] = [
+    "time_s",
+    "julian_date",
+    "kinetic_energy_j",
+    "potential_energy_j",
+    "total_energy_j",
+    "momentum_x_kg_m_per_s",
+    "momentum_y_kg_m_per_s",
+    "massive_bodies",
+];
+
+pub struct TidyWriter {
+    bodies: Writer<OutputStream>,
+    diagnostics: Writer<OutputStream>,
+    columns: BodyColumns,
+    epoch_julian_date: Option<f64>,
+}
+
+impl TidyWriter {
+    pub fn new(
+        bodies: OutputStream,
+        diagnostics: OutputStream,
+        columns: &BodyColumns,
+        epoch: Option<NaiveDateTime>,
+    ) -> io::Result<TidyWriter> {
+        let mut bodies = Writer::from_writer(bodies);
+        let mut diagnostics = Writer::from_writer(diagnostics);
+        bodies.write_record(BODY_ID_COLUMNS.iter().chain(&columns.long_names()))?;
+        diagnostics.write_record(DIAGNOSTIC_COLUMNS)?;
+        Ok(TidyWriter {
+            bodies,
+            diagnostics,
+            columns: columns.clone(),
+            epoch_julian_date: epoch.map(|epoch| julian_date(&epoch)),
+        })
+    }
+}
+
+impl OutputSink for TidyWriter {
+    fn add_data(&mut self, system: &[Particle], time: f64) -> io::Result<()> {
+        let time_text = time.to_string();
+        let julian_date_text = self
+            .epoch_julian_date
+            .map(|epoch| (epoch + time / SECONDS_IN_DAY).to_string())
+            .unwrap_or_default();
+
+        for (body_id, (body, values)) in system.iter().zip(self.columns.values(system)).enumerate()
+        {
+            let mut record = vec![
+                time_text.clone(),
+                julian_date_text.clone(),
+                body_id.to_string(),
+                body.name.clone(),
+                body.mass.to_string(),
+                body.radius.to_string(),
+            ];
+            record.extend(values.iter().map(|value| value.to_string()));
+            self.bodies.write_record(record)?;
+        }
+
+        let totals = find_system_totals(system);
+        self.diagnostics.write_record([
+            time_text,
+            julian_date_text,
+            totals.kinetic_energy.to_string(),
+            totals.potential_energy.to_string(),
+            totals.total_energy().to_string(),
+            totals.momentum.x.to_string(),
+            totals.momentum.y.to_string(),
+            system
+                .iter()
+                .filter(|body| body.mass > 0.)
+                .count()
+                .to_string(),
+        ])?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        for wtr in [self.bodies, self.diagnostics] {
+            wtr.into_inner()
+                .map_err(|error| error.into_error())?
+                .close()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body_columns::BodyQuantity;
+    use crate::constants::*;
+    use crate::output_sink::MemoryBuffer;
+    use crate::output_sink::test_support::test_system;
+
+    #[test]
+    fn test_tidy_writer() {
+        let system = test_system();
+        let (bodies_buffer, diagnostics_buffer) =
+            (MemoryBuffer::default(), MemoryBuffer::default());
+        let epoch = crate::horizon::parse_epoch("JD2451545").unwrap();
+        let mut writer: Box<dyn OutputSink> = Box::new(
+            TidyWriter::new(
+                OutputStream::Memory(bodies_buffer.clone()),
+                OutputStream::Memory(diagnostics_buffer.clone()),
+                &BodyColumns::new(&[BodyQuantity::Position, BodyQuantity::Velocity]),
+                Some(epoch),
+            )
+            .unwrap(),
+        );
+        writer.add_data(&system, 0.).unwrap();
+        writer.add_data(&system, SECONDS_IN_DAY).unwrap();
+        writer.finish().unwrap();
+
+        let contents = bodies_buffer.contents();
+        let mut bodies = csv::Reader::from_reader(contents.as_slice());
+        assert_eq!(
+            bodies.headers().unwrap(),
+            [
+                "time_s",
+                "julian_date",
+                "body_id",
+                "name",
+                "mass_kg",
+                "radius_m",
+                "x_m",
+                "y_m",
+                "vx_m_per_s",
+                "vy_m_per_s"
+            ]
+            .as_slice()
+        );
+        let rows: Vec<csv::StringRecord> = bodies.records().map(|row| row.unwrap()).collect();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(&rows[3][1], "2451546");
+        assert_eq!(&rows[3][2], "1");
+        assert_eq!(&rows[3][3], "earth");
+        assert_eq!(rows[3][6].parse::<f64>().unwrap(), AU);
+
+        let contents = diagnostics_buffer.contents();
+        let mut diagnostics = csv::Reader::from_reader(contents.as_slice());
+        let rows: Vec<csv::StringRecord> = diagnostics.records().map(|row| row.unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        let potential_energy: f64 = rows[0][3].parse().unwrap();
+        assert!((potential_energy / (-G * STAR_MASS * EARTH_MASS / AU) - 1.).abs() < 1e-12);
+        assert_eq!(&rows[0][7], "2");
+    }
+}
@@ -0,0 +1,209 @@
+use crate::constants::*;
+use crate::helpers::{Particle, get_int_from_user, leapfrog_step};
+use crate::horizon::{StateVector, describe_epoch, get_horizons_ephemerides};
+use crate::init_helpers::{choose_epoch, horizons_particle};
+use csv::Writer;
+use macroquad::math::DVec2;
+use std::fs::File;
+use std::io;
+
+// Position error (m) and velocity error (m/s) of every body at one Horizons sample
+pub struct ValidationSample {
+    pub julian_date: f64,
+    pub time: f64, // Seconds since the first sample
+    pub errors: Vec<(f64, f64)>,
+}
+
+/*
+Starts the Solar System from Horizons' first state, integrates it with the Solar System time
+step divided into the chosen ticks per frame, and compares every body against Horizons at each
+later sample. Run with "validate" as the first argument.
+ */
+pub fn run_validation() {
+    let start = choose_epoch();
+    let days = get_int_from_user("How many days should the validation cover? ").max(1);
+    let step_days = get_int_from_user("How many days between Horizons samples? ").clamp(1, days);
+    let ticks_per_frame = get_int_from_user(&format!(
+        "Ticks per frame? The Solar System scenario uses {}",
+        TICKS_PER_FRAME_SOLAR_SYSTEM
+    ))
+    .max(1) as usize;
+    let dt = SOLAR_SYS_SECONDS_PER_FRAME / ticks_per_frame as f64;
+
+    let stop = start + chrono::Duration::days(days as i64);
+    let ephemerides = get_horizons_ephemerides(&start, &stop, step_days);
+    if ephemerides.is_empty() {
+        println!("No Horizons data to validate against");
+        return;
+    }
+
+    let mut system: Vec<Particle> = Vec::new();
+    let mut reference: Vec<Vec<StateVector>> = Vec::new();
+    for (mut value, states) in ephemerides {
+        value.x = states[0].x;
+        value.y = states[0].y;
+        value.vx = states[0].vx;
+        value.vy = states[0].vy;
+        system.push(horizons_particle(&value));
+        reference.push(states);
+    }
+
+    println!(
+        "Validating {} bodies from {} with dt = {:.1} seconds",
+        system.len(),
+        describe_epoch(&start),
+        dt
+    );
+    let samples = integrate_and_compare(&mut system, &reference, dt);
+
+    let file_name = format!(
+        "target/horizons_validation_accuracy_{}.csv",
+        ticks_per_frame
+    );
+    let mut wtr = Writer::from_writer(File::create(&file_name).unwrap());
+    add_validation_topline(&system, &mut wtr).unwrap();
+    for sample in samples.iter() {
+        add_validation_data(sample, &mut wtr).unwrap();
+    }
+
+    if let Some(last) = samples.last() {
+        println!("Errors after {:.1} days:", last.time / SECONDS_IN_DAY);
+        for (body, (position_error, velocity_error)) in system.iter().zip(last.errors.iter()) {
+            println!(
+                "{:>10}: position {:.3e} m, velocity {:.3e} m/s",
+                body.name, position_error, velocity_error
+            );
+        }
+    }
+    println!("Wrote {}", file_name);
+}
+
+/*
+Steps the system up to every sample time in the reference and measures each body's error.
+Horizons' vectors are relative to the Sun's center, so if there is a body named "sun" the
+simulated states are measured from it. Only the x and y components are compared.
+ */
+pub fn integrate_and_compare(
+    system: &mut [Particle],
+    reference: &[Vec<StateVector>],
+    dt: f64,
+) -> Vec<ValidationSample> {
+    let sample_count = reference
+        .iter()
+        .map(|states| states.len())
+        .min()
+        .unwrap_or(0);
+    if sample_count == 0 {
+        return Vec::new();
+    }
+    let sun_index = system.iter().position(|body| body.name == "sun");
+    let first_julian_date = reference[0][0].julian_date;
+    let mut time = 0.;
+    let mut samples = Vec::with_capacity(sample_count);
+
+    for k in 0..sample_count {
+        let julian_date = reference[0][k].julian_date;
+        let sample_time = (julian_date - first_julian_date) * SECONDS_IN_DAY;
+        while sample_time - time > 0. {
+            let step = dt.min(sample_time - time);
+            leapfrog_step(system, step);
+            time += step;
+        }
+
+        let (origin_position, origin_velocity) = match sun_index {
+            Some(i) => (system[i].position, system[i].velocity),
+            None => (DVec2::ZERO, DVec2::ZERO),
+        };
+        let errors = system
+            .iter()
+            .zip(reference.iter())
+            .map(|(body, states)| {
+                let state = states[k];
+                let position = DVec2::new(state.x, state.y) * 1000.;
+                let velocity = DVec2::new(state.vx, state.vy) * 1000.;
+                (
+                    (body.position - origin_position - position).length(),
+                    (body.velocity - origin_velocity - velocity).length(),
+                )
+            })
+            .collect();
+        samples.push(ValidationSample {
+            julian_date,
+            time,
+            errors,
+        });
+    }
+    samples
+}
+
+pub fn add_validation_topline(system: &[Particle], wtr: &mut Writer<File>) -> io::Result<()> {
+    let mut newline = vec!["Julian Date".to_string(), "Time".to_string()];
+    for body in system.iter() {
+        newline.push(format!("{} Position Error (m)", body.name));
+        newline.push(format!("{} Velocity Error (m/s)", body.name));
+    }
+    wtr.write_record(newline)?;
+    wtr.flush()?;
+    Ok(())
+}
+
+pub fn add_validation_data(sample: &ValidationSample, wtr: &mut Writer<File>) -> io::Result<()> {
+    let mut newline = vec![sample.julian_date.to_string(), sample.time.to_string()];
+    for (position_error, velocity_error) in sample.errors.iter() {
+        newline.push(position_error.to_string());
+        newline.push(velocity_error.to_string());
+    }
+    wtr.write_record(newline)?;
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macroquad::color::WHITE;
+
+    #[test]
+    fn test_circular_orbit_validates() {
+        // Two body circular orbit, which has an exact solution relative to the Sun
+        let speed = (G * (STAR_MASS + EARTH_MASS) / AU).sqrt();
+        let angular_speed = speed / AU;
+        let body = |name: &str, mass: f64| Particle {
+            mass,
+            radius: 1.,
+            position: DVec2::ZERO,
+            velocity: DVec2::ZERO,
+            color: WHITE,
+            name: name.to_string(),
+        };
+        let mut system = vec![body("sun", STAR_MASS), body("earth", EARTH_MASS)];
+        system[1].position = DVec2::new(AU, 0.);
+        system[1].velocity = DVec2::new(0., speed);
+
+        let reference: Vec<Vec<StateVector>> = (0..2)
+            .map(|i| {
+                (0..=30)
+                    .map(|day| {
+                        let angle = angular_speed * day as f64 * SECONDS_IN_DAY;
+                        let scale = if i == 0 { 0. } else { 1e-3 };
+                        StateVector {
+                            julian_date: 2451545. + day as f64,
+                            x: AU * angle.cos() * scale,
+                            y: AU * angle.sin() * scale,
+                            vx: -speed * angle.sin() * scale,
+                            vy: speed * angle.cos() * scale,
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let samples = integrate_and_compare(&mut system, &reference, 3600.);
+        assert_eq!(samples.len(), 31);
+        let last = samples.last().unwrap();
+        assert_eq!(last.time, 30. * SECONDS_IN_DAY);
+        assert!(last.errors[0].0 == 0.);
+        assert!(last.errors[1].0 < 1e-6 * AU, "{}", last.errors[1].0);
+        assert!(last.errors[1].1 < 1e-6 * speed, "{}", last.errors[1].1);
+    }
+}